@@ -0,0 +1,319 @@
+use crate::constants::{
+    ADDRESS_BOOK_EXPIRY_SECS, ADDR_RECENT_SECS, MAX_ADDRESSES_PER_SOURCE, MAX_ADDRESS_BOOK_SIZE,
+};
+use crate::db::Database;
+use crate::discover_warn;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OnceCell};
+
+/// What the node knows about a peer address, timestamps are in seconds
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub address: String,
+    /// Only known once the peer registered with us, or we connected to it
+    pub node_id: Option<String>,
+    /// Last time the address was announced by a peer, or we connected to it
+    pub last_seen: i64,
+    /// Last time a connection to the address succeeded
+    pub last_success: Option<i64>,
+    /// Failed connection attempts since the last success
+    pub failures: u32,
+}
+
+impl PeerRecord {
+    pub fn new(address: &str, now: i64) -> Self {
+        PeerRecord {
+            address: address.to_string(),
+            node_id: None,
+            last_seen: now,
+            last_success: None,
+            failures: 0,
+        }
+    }
+
+    /// Addresses nobody mentioned, and we couldn't connect to,
+    /// for `ADDRESS_BOOK_EXPIRY_SECS` are forgotten
+    pub fn is_expired(&self, now: i64) -> bool {
+        let last_heard = self.last_seen.max(self.last_success.unwrap_or(0));
+        now - last_heard > ADDRESS_BOOK_EXPIRY_SECS
+    }
+}
+
+/// Orders the records from the best peer to connect to: the ones that didn't fail lately,
+/// then the ones we connected to most recently, then the ones seen most recently
+pub fn rank_peers(records: &mut [PeerRecord]) {
+    records.sort_by_key(|record| {
+        (
+            record.failures,
+            Reverse(record.last_success),
+            Reverse(record.last_seen),
+        )
+    });
+}
+
+/// Network an address belongs to, a /16 for IPv4 and a /32 for IPv6, like Bitcoin does.
+/// Peers of the same network may be run by the same operator, so outbound connections
+/// are spread over as many networks as possible
+pub fn network_group(address: &str) -> String {
+    match address.parse::<SocketAddr>().map(|address| address.ip()) {
+        Ok(IpAddr::V4(ip)) => {
+            let octets = ip.octets();
+            format!("{}.{}", octets[0], octets[1])
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}", segments[0], segments[1])
+        }
+        // Host names are compared as a whole
+        Err(_) => address
+            .rsplit_once(':')
+            .map_or(address, |(host, _)| host)
+            .to_string(),
+    }
+}
+
+/// Picks `count` peers of the ranked records, skipping the excluded addresses.
+/// Peers of networks we have no connection with come first, then the best ranked of the rest
+pub fn select_diverse(
+    records: &[PeerRecord],
+    count: usize,
+    exclude: &HashSet<String>,
+) -> Vec<String> {
+    let mut groups: HashSet<String> = exclude
+        .iter()
+        .map(|address| network_group(address))
+        .collect();
+    let candidates: Vec<&PeerRecord> = records
+        .iter()
+        .filter(|record| !exclude.contains(&record.address))
+        .collect();
+
+    let mut selected: Vec<String> = vec![];
+    for record in &candidates {
+        if selected.len() < count && groups.insert(network_group(&record.address)) {
+            selected.push(record.address.clone());
+        }
+    }
+    for record in candidates {
+        if selected.len() < count && !selected.contains(&record.address) {
+            selected.push(record.address.clone());
+        }
+    }
+    selected
+}
+
+/// Every peer address the node heard of, stored in the `peers` tree of the database,
+/// so a node can find the network again after a restart, even if its bootstrap node is down.
+/// The records are read from the database once, and kept in memory, every change is
+/// written through to the database
+#[derive(Clone)]
+pub struct AddressBook {
+    database: Arc<Mutex<Database>>,
+    /// Our own address, peers announce it too but it's never stored
+    tcp_address: String,
+    book: Arc<OnceCell<Mutex<Book>>>,
+}
+
+#[derive(Default)]
+struct Book {
+    records: HashMap<String, PeerRecord>,
+    /// Peer each address learned through an `addr` came from, until it's verified,
+    /// so a single peer can't fill the book with addresses nobody else knows about
+    sources: HashMap<String, String>,
+    /// Number of addresses in `sources` coming from each peer
+    learned_from: HashMap<String, usize>,
+}
+
+impl Book {
+    fn add_source(&mut self, address: &str, source: &str) {
+        self.sources.insert(address.to_string(), source.to_string());
+        *self.learned_from.entry(source.to_string()).or_default() += 1;
+    }
+
+    /// The address no longer counts against the peer that told us about it
+    fn remove_source(&mut self, address: &str) {
+        let source = match self.sources.remove(address) {
+            Some(source) => source,
+            None => return,
+        };
+        if let Some(count) = self.learned_from.get_mut(&source) {
+            *count -= 1;
+            if *count == 0 {
+                self.learned_from.remove(&source);
+            }
+        }
+    }
+}
+
+impl AddressBook {
+    pub fn new(database: Arc<Mutex<Database>>, tcp_address: String) -> Self {
+        Self {
+            database,
+            tcp_address,
+            book: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Records an address another node told us about, nothing proves it's reachable.
+    /// It's only added when it's new, without refreshing the addresses we already know,
+    /// and a source adds at most `MAX_ADDRESSES_PER_SOURCE` of them. Once the book holds
+    /// `MAX_ADDRESS_BOOK_SIZE` addresses, the new ones are ignored
+    pub async fn learned(&self, address: &str, source: &str) {
+        if address == self.tcp_address {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp();
+        let mut book = self.book().await.lock().await;
+        if book.records.contains_key(address)
+            || book.records.len() >= MAX_ADDRESS_BOOK_SIZE
+            || book.learned_from.get(source).copied().unwrap_or(0) >= MAX_ADDRESSES_PER_SOURCE
+        {
+            return;
+        }
+        let record = PeerRecord::new(address, now);
+        if let Err(err) = self.database.lock().await.store_peer(&record) {
+            discover_warn!("Failed to store {} in the address book: {}", address, err);
+            return;
+        }
+        book.records.insert(address.to_string(), record);
+        book.add_source(address, source);
+    }
+
+    /// Records a successful connection to the peer, its failures start over
+    pub async fn connected(&self, address: &str, node_id: &str) {
+        self.update(address, |record, now| {
+            record.node_id = Some(node_id.to_string());
+            record.last_seen = now;
+            record.last_success = Some(now);
+            record.failures = 0;
+        })
+        .await;
+    }
+
+    /// Records a failed connection attempt to the peer
+    pub async fn failed(&self, address: &str) {
+        let mut book = self.book().await.lock().await;
+        let record = match book.records.get_mut(address) {
+            Some(record) => record,
+            None => return,
+        };
+        record.failures = record.failures.saturating_add(1);
+        if let Err(err) = self.database.lock().await.store_peer(record) {
+            discover_warn!("Failed to store {} in the address book: {}", address, err);
+        }
+    }
+
+    pub async fn forget(&self, address: &str) {
+        let mut book = self.book().await.lock().await;
+        self.remove(&mut book, address).await;
+    }
+
+    /// Returns up to `count` addresses to open a connection to, other than the excluded ones.
+    /// Recently successful peers are preferred, spread over as many networks as possible.
+    pub async fn select_outbound(&self, count: usize, exclude: &HashSet<String>) -> Vec<String> {
+        let mut records = self.records().await;
+        rank_peers(&mut records);
+        select_diverse(&records, count, exclude)
+    }
+
+    /// Returns up to `count` addresses seen in the last `ADDR_RECENT_SECS`, picked at random,
+    /// to be shared with a peer
+    pub async fn sample_recent(&self, count: usize) -> Vec<String> {
+        let now = chrono::Utc::now().timestamp();
+        let mut addresses: Vec<String> = self
+            .records()
+            .await
+            .into_iter()
+            .filter(|record| now - record.last_seen <= ADDR_RECENT_SECS)
+            .map(|record| record.address)
+            .collect();
+        addresses.shuffle(&mut rand::rng());
+        addresses.truncate(count);
+        addresses
+    }
+
+    /// Number of addresses in the book
+    #[allow(dead_code)]
+    pub async fn len(&self) -> usize {
+        self.book().await.lock().await.records.len()
+    }
+
+    /// Records of the book, read from the database the first time the book is used
+    async fn book(&self) -> &Mutex<Book> {
+        self.book
+            .get_or_init(|| async {
+                let records = match self.database.lock().await.get_peers() {
+                    Ok(records) => records,
+                    Err(err) => {
+                        discover_warn!("Failed to read the address book: {}", err);
+                        vec![]
+                    }
+                };
+                Mutex::new(Book {
+                    records: records
+                        .into_iter()
+                        .map(|record| (record.address.clone(), record))
+                        .collect(),
+                    ..Book::default()
+                })
+            })
+            .await
+    }
+
+    /// Returns the records of the book, expired addresses are removed from it
+    async fn records(&self) -> Vec<PeerRecord> {
+        let now = chrono::Utc::now().timestamp();
+        let mut book = self.book().await.lock().await;
+        let expired: Vec<String> = book
+            .records
+            .values()
+            .filter(|record| record.is_expired(now))
+            .map(|record| record.address.clone())
+            .collect();
+        for address in expired {
+            self.remove(&mut book, &address).await;
+        }
+        book.records.values().cloned().collect()
+    }
+
+    async fn remove(&self, book: &mut Book, address: &str) {
+        book.records.remove(address);
+        book.remove_source(address);
+        if let Err(err) = self.database.lock().await.remove_peer(address) {
+            discover_warn!(
+                "Failed to remove {} from the address book: {}",
+                address,
+                err
+            );
+        }
+    }
+
+    /// Applies a change verified by a connection to the record of the address.
+    /// The address no longer counts against the peer that told us about it, and when
+    /// the book is full, the worst ranked address makes room for a new one
+    async fn update(&self, address: &str, change: impl FnOnce(&mut PeerRecord, i64)) {
+        let now = chrono::Utc::now().timestamp();
+        let mut book = self.book().await.lock().await;
+        if !book.records.contains_key(address) && book.records.len() >= MAX_ADDRESS_BOOK_SIZE {
+            let mut records: Vec<PeerRecord> = book.records.values().cloned().collect();
+            rank_peers(&mut records);
+            if let Some(worst) = records.last() {
+                self.remove(&mut book, &worst.address).await;
+            }
+        }
+        book.remove_source(address);
+        let record = book
+            .records
+            .entry(address.to_string())
+            .or_insert_with(|| PeerRecord::new(address, now));
+        change(record, now);
+        if let Err(err) = self.database.lock().await.store_peer(record) {
+            discover_warn!("Failed to store {} in the address book: {}", address, err);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Number of decimal places an amount can have
+pub const DECIMALS: u32 = 8;
+/// How many base units make a single coin, same as Bitcoin's satoshis
+pub const BASE_UNITS_PER_COIN: u64 = 10u64.pow(DECIMALS);
+
+/// Fixed-point amount of coins, stored as an integer number of base units.
+/// Using integers instead of floats keeps balances exact, and makes the
+/// signed and hashed transaction data independent of how floats are printed.
+/// In JSON, amounts are represented by their number of base units.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_base_units(base_units: u64) -> Self {
+        Amount(base_units)
+    }
+
+    pub const fn from_coins(coins: u64) -> Self {
+        Amount(coins * BASE_UNITS_PER_COIN)
+    }
+
+    pub const fn base_units(&self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn saturating_add(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_sub(other.0))
+    }
+}
+
+/// Displays the amount in coins, with all the decimal places (e.g. 1.50000000)
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:0width$}",
+            self.0 / BASE_UNITS_PER_COIN,
+            self.0 % BASE_UNITS_PER_COIN,
+            width = DECIMALS as usize
+        )
+    }
+}
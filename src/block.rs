@@ -1,6 +1,9 @@
+use crate::merkle::{merkle_branch, merkle_root, MerkleProof};
+use crate::pow::{compact_to_target, hash_meets_target};
 use crate::transaction::Transaction;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 // Import the necessary traits and types
 // We will use hex encoding
 
@@ -9,64 +12,189 @@ pub struct Block {
     pub index: u64,
     pub timestamp: u64,
     pub transactions: Vec<Transaction>, // Store the transactions
+    /// Root of the merkle tree of the transaction hashes, it's part of the header hash,
+    /// so a transaction can be proven to be in the block with just a merkle branch
+    pub merkle_root: String,
     pub previous_hash: String,
+    /// Compact encoding of the PoW target the hash must meet, same as Bitcoin's nBits
+    pub bits: u32,
     pub hash: String,
     pub nonce: u64, // New field for PoW
 }
 
+/// Fields of a block covered by its hash, without the transactions.
+/// Headers are downloaded first during sync, so the work of a peer's chain can be checked
+/// before its blocks are requested.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: u64,
+    pub merkle_root: String,
+    pub previous_hash: String,
+    pub bits: u32,
+    pub hash: String,
+    pub nonce: u64,
+}
+
+/// Header fields the difficulty retargeting and the chain work are computed from,
+/// so they work the same on blocks and on headers
+pub trait ChainEntry {
+    fn index(&self) -> u64;
+    fn timestamp(&self) -> u64;
+    fn bits(&self) -> u32;
+}
+
+impl ChainEntry for Block {
+    fn index(&self) -> u64 {
+        self.index
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn bits(&self) -> u32 {
+        self.bits
+    }
+}
+
+impl ChainEntry for BlockHeader {
+    fn index(&self) -> u64 {
+        self.index
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn bits(&self) -> u32 {
+        self.bits
+    }
+}
+
+impl BlockHeader {
+    pub fn calculate_hash(&self) -> String {
+        hash_header(
+            self.index,
+            self.timestamp,
+            &self.merkle_root,
+            &self.previous_hash,
+            self.bits,
+            self.nonce,
+        )
+    }
+
+    /// Checks the hash against the target encoded in the header
+    pub fn is_valid(&self) -> bool {
+        match compact_to_target(self.bits) {
+            Some(target) => hash_meets_target(&self.hash, &target),
+            None => false,
+        }
+    }
+}
+
 impl Block {
     pub fn new(
         index: u64,
         timestamp: u64,
         transactions: Vec<Transaction>,
         previous_hash: String,
+        bits: u32,
     ) -> Self {
         let mut block = Block {
             index,
             timestamp,
             transactions,
+            merkle_root: String::new(), // Initially empty
             previous_hash,
+            bits,
             hash: String::new(), // Initially empty
             nonce: 0,            // Initially zero
         };
 
+        block.merkle_root = block.calculate_merkle_root();
         block.hash = block.calculate_hash(); // Calculate hash after creating the block
         block
     }
 
-    pub fn calculate_hash(&self) -> String {
-        let transactions_data: String = self
-            .transactions
-            .iter()
-            .map(|tx| tx.hash()) // Get hash of each transaction
-            .collect::<Vec<_>>()
-            .join(""); // Concatenate all transaction hashes
+    /// Appends a transaction, updating the merkle root and the hash
+    pub fn add_transaction(&mut self, transaction: Transaction) {
+        self.transactions.push(transaction);
+        self.merkle_root = self.calculate_merkle_root();
+        self.hash = self.calculate_hash();
+    }
 
-        let input = format!(
-            "{}{}{}{}{}",
-            self.index, self.timestamp, transactions_data, self.previous_hash, self.nonce
-        );
+    fn transaction_hashes(&self) -> Vec<String> {
+        self.transactions.iter().map(|tx| tx.hash()).collect()
+    }
+
+    pub fn calculate_merkle_root(&self) -> String {
+        merkle_root(&self.transaction_hashes())
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(input);
-        let result = hasher.finalize();
+    /// Checks that no transaction appears twice. The last node of an odd level is paired
+    /// with itself, so repeating the last transactions gives the same merkle root and the
+    /// same block hash (CVE-2012-2459), a block relying on it must be rejected before its
+    /// hash is remembered, or the valid block with that hash would be ignored
+    pub fn has_unique_transactions(&self) -> bool {
+        let hashes = self.transaction_hashes();
+        let unique: HashSet<&String> = hashes.iter().collect();
+        unique.len() == hashes.len()
+    }
+
+    /// Builds the merkle proof for a transaction of this block, if present
+    pub fn merkle_proof(&self, transaction_hash: &str) -> Option<MerkleProof> {
+        let hashes = self.transaction_hashes();
+        let index = hashes.iter().position(|hash| hash == transaction_hash)?;
 
-        hex::encode(result)
+        Some(MerkleProof {
+            transaction_hash: transaction_hash.to_string(),
+            block_hash: self.hash.clone(),
+            merkle_root: self.merkle_root.clone(),
+            index,
+            branch: merkle_branch(&hashes, index)?,
+        })
+    }
+
+    /// The header hash commits to the transactions through the merkle root
+    pub fn calculate_hash(&self) -> String {
+        hash_header(
+            self.index,
+            self.timestamp,
+            &self.merkle_root,
+            &self.previous_hash,
+            self.bits,
+            self.nonce,
+        )
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root.clone(),
+            previous_hash: self.previous_hash.clone(),
+            bits: self.bits,
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+        }
     }
 
     // Mine the block (PoW)
     #[allow(dead_code)]
-    pub fn mine(&mut self, difficulty: usize) {
-        let target = "0".repeat(difficulty); // Target hash difficulty (e.g., "0000...")
-
-        while self.hash[..difficulty] != target {
+    pub fn mine(&mut self) {
+        while !self.is_valid() {
             self.nonce += 1;
             self.hash = self.calculate_hash();
         }
     }
 
-    pub fn is_valid(&self, difficulty: usize) -> bool {
-        self.hash.starts_with(&"0".repeat(difficulty))
+    /// Checks the hash against the target encoded in the header
+    pub fn is_valid(&self) -> bool {
+        match compact_to_target(self.bits) {
+            Some(target) => hash_meets_target(&self.hash, &target),
+            None => false,
+        }
     }
 
     pub fn mine_step(&mut self) {
@@ -74,3 +202,23 @@ impl Block {
         self.hash = self.calculate_hash();
     }
 }
+
+fn hash_header(
+    index: u64,
+    timestamp: u64,
+    merkle_root: &str,
+    previous_hash: &str,
+    bits: u32,
+    nonce: u64,
+) -> String {
+    let input = format!(
+        "{}{}{}{}{}{}",
+        index, timestamp, merkle_root, previous_hash, bits, nonce
+    );
+
+    let mut hasher = Sha256::new();
+    hasher.update(input);
+    let result = hasher.finalize();
+
+    hex::encode(result)
+}
@@ -0,0 +1,121 @@
+use crate::block::Block;
+use crate::constants::{MAX_ORPHAN_BLOCKS, MAX_SIDE_BLOCKS};
+use std::collections::{HashMap, HashSet};
+
+/// Blocks received from the network that aren't part of the main chain.
+/// Side blocks descend from a block we know, so their branch may become heavier than the
+/// main chain. Orphans are blocks whose parent hasn't arrived yet, they are kept by the hash
+/// of the missing parent, and connected to the tree once it arrives.
+#[derive(Clone, Default)]
+pub struct BlockTree {
+    side_blocks: HashMap<String, SideBlock>,
+    orphans: HashMap<String, Vec<Block>>,
+}
+
+/// A side block, with the work of the branch from genesis up to it
+#[derive(Clone)]
+struct SideBlock {
+    block: Block,
+    chain_work: u128,
+}
+
+impl BlockTree {
+    /// Checks whether the block is stored as a side block or as an orphan
+    pub fn contains(&self, hash: &str) -> bool {
+        self.side_blocks.contains_key(hash)
+            || self
+                .orphans
+                .values()
+                .any(|blocks| blocks.iter().any(|block| block.hash == hash))
+    }
+
+    pub fn get_side_block(&self, hash: &str) -> Option<&Block> {
+        self.side_blocks
+            .get(hash)
+            .map(|side_block| &side_block.block)
+    }
+
+    /// Stores a block of a side branch, `chain_work` being the work of its branch up to it.
+    /// When the tree is full, the branch tip with the least work is evicted, the tips are
+    /// evicted first so the blocks left still connect to the chain. Returns false when the
+    /// block has less work than every tip, and isn't stored
+    pub fn insert_side_block(&mut self, block: Block, chain_work: u128) -> bool {
+        if self.side_blocks.len() >= MAX_SIDE_BLOCKS {
+            let parents: HashSet<&String> = self
+                .side_blocks
+                .values()
+                .map(|side_block| &side_block.block.previous_hash)
+                .chain([&block.previous_hash])
+                .collect();
+            let evicted = self
+                .side_blocks
+                .iter()
+                .filter(|(hash, _)| !parents.contains(hash))
+                .min_by_key(|(_, side_block)| side_block.chain_work)
+                .filter(|(_, side_block)| side_block.chain_work < chain_work)
+                .map(|(hash, _)| hash.clone());
+            match evicted {
+                Some(hash) => {
+                    self.side_blocks.remove(&hash);
+                }
+                None => return false,
+            }
+        }
+        self.side_blocks
+            .insert(block.hash.clone(), SideBlock { block, chain_work });
+        true
+    }
+
+    pub fn remove_side_block(&mut self, hash: &str) -> Option<Block> {
+        self.side_blocks
+            .remove(hash)
+            .map(|side_block| side_block.block)
+    }
+
+    /// Stores a block whose parent is unknown.
+    /// When the tree is full, the orphans of another missing parent are evicted,
+    /// so a peer flooding us with orphans can't make us hold more than the limit.
+    pub fn add_orphan(&mut self, block: Block) {
+        if self.orphan_count() >= MAX_ORPHAN_BLOCKS {
+            let evicted = self
+                .orphans
+                .keys()
+                .find(|parent_hash| **parent_hash != block.previous_hash)
+                .cloned();
+            match evicted {
+                Some(parent_hash) => {
+                    self.orphans.remove(&parent_hash);
+                }
+                None => return,
+            }
+        }
+        self.orphans
+            .entry(block.previous_hash.clone())
+            .or_default()
+            .push(block);
+    }
+
+    /// Removes and returns the orphans waiting for this parent
+    pub fn take_orphans(&mut self, parent_hash: &str) -> Vec<Block> {
+        self.orphans.remove(parent_hash).unwrap_or_default()
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.values().map(|blocks| blocks.len()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn side_block_count(&self) -> usize {
+        self.side_blocks.len()
+    }
+
+    /// Drops the blocks below `min_index`, branches forking that deep won't be reorganized to
+    pub fn prune(&mut self, min_index: u64) {
+        self.side_blocks
+            .retain(|_, side_block| side_block.block.index >= min_index);
+        for blocks in self.orphans.values_mut() {
+            blocks.retain(|block| block.index >= min_index);
+        }
+        self.orphans.retain(|_, blocks| !blocks.is_empty());
+    }
+}
@@ -1,13 +1,34 @@
-use crate::block::Block;
-use crate::constants::{MAX_SUPPLY, REWARD};
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader, ChainEntry};
+use crate::block_tree::BlockTree;
+use crate::constants::{
+    DIFFICULTY_ADJUSTMENT_WINDOW, HALVING_INTERVAL, INITIAL_BITS, MAX_FUTURE_BLOCK_TIME,
+    MAX_SIDE_BRANCH_DEPTH, MAX_SUPPLY, MEDIAN_TIME_SPAN, POW_LIMIT_BITS, REWARD, TARGET_BLOCK_TIME,
+};
+use crate::ledger::{Accounts, Ledger};
+use crate::merkle::merkle_root;
+use crate::pow::{block_work, compact_to_target, scale_target, target_to_compact};
 use crate::transaction::Transaction;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Blockchain {
     pub chain: Vec<Block>,
-    pub difficulty: usize,
-    pub total_supply: u64,
+    /// Compact PoW target of the blocks mined before the first retarget
+    pub initial_bits: u32,
+    /// Expected time between blocks in seconds
+    pub target_block_time: u64,
+    /// Number of blocks between difficulty adjustments
+    pub adjustment_window: u64,
+    /// Number of blocks between halvings of the block reward
+    pub halving_interval: u64,
+    pub total_supply: Amount,
+    /// Balances and next expected nonces, according to the transactions in the chain
+    pub ledger: Ledger,
+    /// Side branches and orphans received from the network, they are only kept in memory
+    #[serde(skip)]
+    pub block_tree: BlockTree,
 }
 
 impl Blockchain {
@@ -15,43 +36,460 @@ impl Blockchain {
         let genesis_block = create_genesis_block();
         Blockchain {
             chain: vec![genesis_block],
-            difficulty: 6, // Set the PoW difficulty (e.g., 4 leading zeros)
-            total_supply: 0,
+            initial_bits: INITIAL_BITS, // Set the PoW target (e.g., ~6 leading hex zeros)
+            target_block_time: TARGET_BLOCK_TIME,
+            adjustment_window: DIFFICULTY_ADJUSTMENT_WINDOW,
+            halving_interval: HALVING_INTERVAL,
+            total_supply: Amount::ZERO,
+            ledger: Ledger::default(),
+            block_tree: BlockTree::default(),
         }
     }
 
-    pub fn is_valid_chain(chain: &[Block]) -> bool {
+    /// Sets the retargeting parameters, usually taken from the node configuration
+    pub fn set_difficulty_adjustment(&mut self, target_block_time: u64, adjustment_window: u64) {
+        self.target_block_time = target_block_time;
+        // A window of 0 blocks would retarget on a division by zero
+        self.adjustment_window = adjustment_window.max(1);
+    }
+
+    /// Sets the number of blocks between halvings, usually taken from the node configuration
+    pub fn set_halving_interval(&mut self, halving_interval: u64) {
+        self.halving_interval = halving_interval.max(1);
+    }
+
+    /// Rebuilds the blockchain from the blocks persisted in the database.
+    /// The database may also hold blocks from chains that were replaced during sync,
+    /// so the stored blocks are walked as a tree from genesis, every block being validated
+    /// once against the state of its own branch, and the valid branch with the most work
+    /// is restored, same as the fork choice.
+    /// The blocks are validated with the consensus parameters of this blockchain.
+    /// Returns false, leaving the blockchain untouched, if no valid chain could be rebuilt.
+    pub fn restore_from_blocks(&mut self, blocks: Vec<Block>) -> bool {
+        let genesis = create_genesis_block();
+        let mut has_genesis = false;
+        let mut children: HashMap<String, Vec<Block>> = HashMap::new();
+        for block in blocks {
+            if block == genesis {
+                has_genesis = true;
+            } else {
+                children
+                    .entry(block.previous_hash.clone())
+                    .or_default()
+                    .push(block);
+            }
+        }
+        // Chains from other networks, or empty databases, can't be restored
+        if !has_genesis {
+            return false;
+        }
+        let mut parents: HashMap<String, Block> = HashMap::new();
+
+        // Depth-first, `chain` holds the branch of the block being validated,
+        // each pending block comes with the state after its parent
+        let mut chain = vec![genesis.clone()];
+        let mut best: Option<(u128, String)> = None;
+        let mut pending: Vec<(usize, Block, Ledger, Amount, u128)> = children
+            .remove(&genesis.hash)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|block| (1, block, Ledger::default(), Amount::ZERO, 0))
+            .collect();
+        while let Some((depth, block, mut ledger, supply, work)) = pending.pop() {
+            chain.truncate(depth);
+            let supply = match self.connect_block(&chain, &block, &mut ledger, supply) {
+                Some(supply) => supply,
+                None => continue,
+            };
+            let work = work.saturating_add(block_work(block.bits));
+            if best.as_ref().is_none_or(|(best_work, _)| work > *best_work) {
+                best = Some((work, block.hash.clone()));
+            }
+
+            let mut next = children.remove(&block.hash).unwrap_or_default();
+            if let Some(last) = next.pop() {
+                for child in next {
+                    pending.push((depth + 1, child, ledger.clone(), supply, work));
+                }
+                pending.push((depth + 1, last, ledger, supply, work));
+            }
+            parents.insert(block.hash.clone(), block.clone());
+            chain.push(block);
+        }
+
+        // Without any valid block after it, only genesis is restored
+        let mut hash = best.map(|(_, hash)| hash).unwrap_or_default();
+        let mut chain = vec![];
+        while let Some(block) = parents.remove(&hash) {
+            hash = block.previous_hash.clone();
+            chain.push(block);
+        }
+        chain.push(genesis);
+        chain.reverse();
+
+        self.total_supply = self.compute_total_supply(&chain);
+        self.ledger = Ledger::from_chain(&chain);
+        self.chain = chain;
+        true
+    }
+
+    #[allow(dead_code)]
+    pub fn is_valid_chain(&self, chain: &[Block]) -> bool {
+        // Chains from other networks, or empty ones, can't replace ours
+        if chain.first() != Some(&create_genesis_block()) {
+            return false;
+        }
+
+        let mut ledger = Ledger::default();
+        let mut supply = Amount::ZERO;
         for i in 1..chain.len() {
-            if chain[i].previous_hash != chain[i - 1].hash
-                || chain[i].hash != chain[i].calculate_hash()
+            supply = match self.connect_block(&chain[..i], &chain[i], &mut ledger, supply) {
+                Some(supply) => supply,
+                None => return false,
+            };
+        }
+        true
+    }
+
+    /// Validates a block following `chain`, applying it to the ledger of the chain.
+    /// Returns the supply after the block, or None if the block is invalid
+    fn connect_block<A: Accounts>(
+        &self,
+        chain: &[Block],
+        block: &Block,
+        ledger: &mut A,
+        supply: Amount,
+    ) -> Option<Amount> {
+        let parent = chain.last()?;
+        // The ledger only checks nonces and balances, the signatures are checked here
+        if block.previous_hash != parent.hash
+            || block.index != parent.index + 1
+            || !Self::is_valid_timestamp(chain, block.timestamp)
+            || !Self::is_well_formed_block(block)
+            || block.bits != self.next_bits(chain)
+            || !ledger.apply_block(block)
+        {
+            return None;
+        }
+        self.apply_coinbase(supply, block)
+    }
+
+    /// Sum of the work of the blocks after genesis, the fork choice picks the chain with the most
+    /// accumulated work instead of the longest one, since blocks mined with an easier target
+    /// are cheaper to produce
+    pub fn chain_work<B: ChainEntry>(chain: &[B]) -> u128 {
+        chain.iter().skip(1).fold(0, |work, block| {
+            work.saturating_add(block_work(block.bits()))
+        })
+    }
+
+    pub fn total_work(&self) -> u128 {
+        Self::chain_work(&self.chain)
+    }
+
+    /// Fork choice, a chain only replaces ours when it's valid and has strictly more work.
+    /// On a tie we keep the chain we already have, same as Bitcoin keeps the first seen tip
+    pub fn is_heavier_valid_chain(&self, chain: &[Block]) -> bool {
+        Self::chain_work(chain) > self.total_work() && self.is_valid_branch(chain)
+    }
+
+    /// Validates the blocks of `chain` after its common ancestor with ours, the blocks up to
+    /// it are part of our chain already. They are applied on top of our ledger and supply
+    /// rolled back to the fork point, so the cost depends on the branch, not on the chain
+    fn is_valid_branch(&self, chain: &[Block]) -> bool {
+        // Chains from other networks, or empty ones, don't share our genesis block
+        let fork_index = self.fork_index(chain);
+        if fork_index == 0 || chain.first() != self.chain.first() {
+            return false;
+        }
+
+        let mut ledger = self.ledger.view();
+        let mut supply = self.total_supply;
+        for block in self.chain[fork_index..].iter().rev() {
+            ledger.revert_block(block);
+            supply = supply.saturating_sub(Self::paid_subsidy(block));
+        }
+        for i in fork_index..chain.len() {
+            supply = match self.connect_block(&chain[..i], &chain[i], &mut ledger, supply) {
+                Some(supply) => supply,
+                None => return false,
+            };
+        }
+        true
+    }
+
+    /// Number of blocks `chain` shares with ours, the block before them is the fork point
+    fn fork_index(&self, chain: &[Block]) -> usize {
+        self.chain
+            .iter()
+            .zip(chain.iter())
+            .take_while(|(block, other)| block.hash == other.hash)
+            .count()
+    }
+
+    /// Checks whether a block with this hash is already part of the chain
+    pub fn contains_block(&self, hash: &str) -> bool {
+        self.chain.iter().rev().any(|block| block.hash == hash)
+    }
+
+    /// Checks whether the block is in the chain, or kept in the block tree
+    pub fn knows_block(&self, hash: &str) -> bool {
+        self.contains_block(hash) || self.block_tree.contains(hash)
+    }
+
+    /// Looks up a block of the chain or of a side branch
+    pub fn get_block(&self, hash: &str) -> Option<&Block> {
+        self.chain
+            .iter()
+            .rev()
+            .find(|block| block.hash == hash)
+            .or_else(|| self.block_tree.get_side_block(hash))
+    }
+
+    /// Hashes describing our chain to a peer, so it can find the last block we have in common.
+    /// Same as Bitcoin's block locator, the 10 latest blocks are listed one by one,
+    /// then the step doubles every time, always ending with the genesis block
+    pub fn block_locator(&self) -> Vec<String> {
+        let mut locator = vec![];
+        let mut index = self.chain.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.chain[index].hash.clone());
+            if index == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Position in our chain of the first locator hash we have,
+    /// the genesis block when none of them is part of our chain
+    fn locate(&self, locator: &[String]) -> usize {
+        locator
+            .iter()
+            .find_map(|hash| self.chain.iter().rposition(|block| &block.hash == hash))
+            .unwrap_or(0)
+    }
+
+    /// Headers of the blocks following the fork point with the peer's locator, up to `max`
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Vec<BlockHeader> {
+        self.chain
+            .iter()
+            .skip(self.locate(locator) + 1)
+            .take(max)
+            .map(Block::header)
+            .collect()
+    }
+
+    /// Validates headers received from a peer, which must follow a block of our chain,
+    /// checking the links between them, their hashes, and their PoW against the target
+    /// the retargeting gives. Returns the work of the chain they would form, so the fork
+    /// choice can be made before downloading the blocks.
+    pub fn validate_headers(&self, headers: &[BlockHeader]) -> Option<u128> {
+        let first = headers.first()?;
+        let fork_index = self
+            .chain
+            .iter()
+            .rposition(|block| block.hash == first.previous_hash)?;
+
+        let mut chain: Vec<BlockHeader> = self.chain[..=fork_index]
+            .iter()
+            .map(Block::header)
+            .collect();
+        for header in headers {
+            let parent = chain.last()?;
+            if header.previous_hash != parent.hash
+                || header.index != parent.index + 1
+                || !Self::is_valid_timestamp(&chain, header.timestamp)
+                || header.hash != header.calculate_hash()
+                || header.bits != self.next_bits(&chain)
+                || !header.is_valid()
             {
-                return false;
+                return None;
             }
+            chain.push(header.clone());
         }
-        true
+        Some(Self::chain_work(&chain))
+    }
+
+    /// Coins minted by the coinbase of the block at `index`, when `supply` coins already exist.
+    /// The reward is halved every `halving_interval` blocks, same as Bitcoin, so the supply
+    /// approaches `MAX_SUPPLY` asymptotically. The last reward is cut short anyway,
+    /// so a shorter interval or rounding can never take the supply past the maximum
+    pub fn block_subsidy(&self, index: u64, supply: Amount) -> Amount {
+        let halvings = index / self.halving_interval;
+        let reward = Amount::from_coins(REWARD)
+            .base_units()
+            .checked_shr(halvings.min(u32::MAX as u64) as u32)
+            .unwrap_or(0);
+        let remaining = Amount::from_coins(MAX_SUPPLY).saturating_sub(supply);
+        Amount::from_base_units(reward).min(remaining)
+    }
+
+    /// Checks that the block has exactly one coinbase transaction, paying the block subsidy
+    /// plus the fees of the other transactions, nothing more and nothing less.
+    /// Returns the supply after the block, or None if the coinbase is invalid
+    fn apply_coinbase(&self, supply: Amount, block: &Block) -> Option<Amount> {
+        let mut coinbases = block.transactions.iter().filter(|tx| tx.is_coinbase());
+        let coinbase = coinbases.next()?;
+        if coinbases.next().is_some() || coinbase.fee != Amount::ZERO {
+            return None;
+        }
+
+        let fees = block
+            .transactions
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .try_fold(Amount::ZERO, |fees, tx| fees.checked_add(tx.fee))?;
+        let subsidy = self.block_subsidy(block.index, supply);
+        if coinbase.amount != subsidy.checked_add(fees)? {
+            return None;
+        }
+        supply.checked_add(subsidy)
+    }
+
+    /// Subsidy a block of the chain added to the supply, its coinbase pays it plus the fees
+    fn paid_subsidy(block: &Block) -> Amount {
+        let fees = block
+            .transactions
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .fold(Amount::ZERO, |fees, tx| fees.saturating_add(tx.fee));
+        block
+            .transactions
+            .iter()
+            .find(|tx| tx.is_coinbase())
+            .map_or(Amount::ZERO, |coinbase| {
+                coinbase.amount.saturating_sub(fees)
+            })
+    }
+
+    fn compute_total_supply(&self, chain: &[Block]) -> Amount {
+        chain.iter().skip(1).fold(Amount::ZERO, |supply, block| {
+            self.apply_coinbase(supply, block).unwrap_or(supply)
+        })
+    }
+
+    /// Returns the balance of this address, according to the transactions in the chain
+    pub fn get_balance(&self, address: &str) -> Amount {
+        self.ledger.balance(address)
+    }
+
+    /// Returns the nonce the next transaction of this address must use
+    pub fn get_next_nonce(&self, address: &str) -> u64 {
+        self.ledger.next_nonce(address)
+    }
+
+    /// Returns the index of the next block, transactions valid until a lower height have expired
+    pub fn next_height(&self) -> u64 {
+        self.get_last_block().index + 1
     }
 
     // By default, the miners reward would be the coins still available under supply
-    // plus all block's transactions fees
-    pub fn get_miner_transaction(&self, miner_address: String, fees: f64) -> Option<Transaction> {
-        if self.total_supply <= MAX_SUPPLY {
-            let new_timestamp = chrono::Utc::now().timestamp() as u64;
-            return Some(Transaction::new(
-                "COINBASE".to_string(), // Sender is "COINBASE"
-                miner_address.clone(),  // Miner receives the reward
-                REWARD as f64 + fees,   // Reward amount
-                0.0,                    // No fee for coinbase transactions
-                new_timestamp as i64,
-            ));
+    // plus all block's transactions fees. Once the supply is exhausted, the coinbase
+    // is still required, paying the fees only
+    pub fn get_miner_transaction(&self, miner_address: String, fees: Amount) -> Transaction {
+        let new_timestamp = chrono::Utc::now().timestamp() as u64;
+        let index = self.get_last_block().index + 1;
+        let reward = self
+            .block_subsidy(index, self.total_supply)
+            .saturating_add(fees);
+        Transaction::new(
+            "COINBASE".to_string(), // Sender is "COINBASE"
+            miner_address.clone(),  // Miner receives the reward
+            reward,                 // Reward amount
+            Amount::ZERO,           // No fee for coinbase transactions
+            index,                  // Coinbase nonce is the new block index
+            new_timestamp as i64,
+        )
+    }
+
+    /// Returns the compact PoW target of the block following `chain`.
+    /// Every `adjustment_window` blocks the target is scaled by how long the previous window
+    /// actually took compared to the target block time, same as Bitcoin's retargeting,
+    /// so every node gets the same value from the same chain. In between, blocks keep
+    /// the target of their parent.
+    pub fn next_bits<B: ChainEntry>(&self, chain: &[B]) -> u32 {
+        let height = chain.len() as u64;
+        let last = match chain.last() {
+            // The genesis block is hardcoded, so the first target comes from the configuration
+            Some(last) if last.index() > 0 => last,
+            _ => return self.initial_bits,
+        };
+        if !height.is_multiple_of(self.adjustment_window) {
+            return last.bits();
         }
 
-        None
+        // The genesis timestamp is a placeholder, so it's never used as start of a window
+        let first = &chain[height.saturating_sub(self.adjustment_window).max(1) as usize];
+        let intervals = last.index().saturating_sub(first.index());
+        let (target, limit) = match (
+            compact_to_target(last.bits()),
+            compact_to_target(POW_LIMIT_BITS),
+        ) {
+            (Some(target), Some(limit)) if intervals > 0 => (target, limit),
+            _ => return last.bits(),
+        };
+
+        // Limiting the adjustment to a factor of 4, so a few odd timestamps can't swing it
+        let expected_time = intervals * self.target_block_time;
+        let actual_time = last.timestamp().saturating_sub(first.timestamp());
+        let (numerator, denominator) = if actual_time.saturating_mul(4) < expected_time {
+            (1, 4)
+        } else if actual_time > expected_time.saturating_mul(4) {
+            (4, 1)
+        } else {
+            (actual_time, expected_time)
+        };
+        target_to_compact(&scale_target(&target, numerator, denominator, &limit))
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks of the chain,
+    /// same as Bitcoin's median time past
+    pub fn median_time_past<B: ChainEntry>(chain: &[B]) -> u64 {
+        let mut timestamps: Vec<u64> = chain
+            .iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|block| block.timestamp())
+            .collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Checks the timestamp of the block following `chain`, it must be later than the median
+    /// time past, and at most `MAX_FUTURE_BLOCK_TIME` seconds ahead of our clock.
+    /// Retargeting is computed from these timestamps, so miners can't fake a slow window
+    /// to lower the difficulty
+    fn is_valid_timestamp<B: ChainEntry>(chain: &[B], timestamp: u64) -> bool {
+        timestamp > Self::median_time_past(chain) && !Self::is_too_new(timestamp)
+    }
+
+    /// Checks whether the timestamp is more than `MAX_FUTURE_BLOCK_TIME` seconds ahead of our
+    /// clock. Such a block may become valid later, or our clock may be behind, so unlike
+    /// other invalid blocks it's dropped without penalizing the peer that sent it
+    pub fn is_too_new(timestamp: u64) -> bool {
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME)
+    }
+
+    /// Returns the compact PoW target the next block must meet
+    pub fn next_block_bits(&self) -> u32 {
+        self.next_bits(&self.chain)
     }
 
     pub fn add_block(&mut self, new_block: Block) -> bool {
         // Ensure the block's previous_hash is valid
         let last_block = self.chain.last().unwrap();
         if last_block.hash == new_block.previous_hash {
+            self.ledger.apply_block(&new_block);
+            if let Some(supply) = self.apply_coinbase(self.total_supply, &new_block) {
+                self.total_supply = supply;
+            }
             self.chain.push(new_block);
             return true;
         }
@@ -60,68 +498,329 @@ impl Blockchain {
 
     pub fn is_valid_new_block(&self, block: &Block) -> bool {
         if let Some(last_block) = self.chain.last() {
-            // 1. Validate previous hash
-            if block.previous_hash != last_block.hash {
+            // 1. Validate previous hash and index, the reward and the coinbase nonce
+            // depend on the index, so it can't be chosen by the miner
+            if block.previous_hash != last_block.hash || block.index != last_block.index + 1 {
+                return false;
+            }
+            if !Self::is_valid_timestamp(&self.chain, block.timestamp) {
                 return false;
             }
 
-            // 2. Validate block hash and PoW
+            // 2. Validate the merkle root, block hash and PoW
+            if block.merkle_root != block.calculate_merkle_root()
+                || !block.has_unique_transactions()
+            {
+                return false;
+            }
             let calculated_hash = block.calculate_hash();
             if block.hash != calculated_hash
-                || !block.hash.starts_with(&"0".repeat(self.difficulty))
+                || block.bits != self.next_block_bits()
+                || !block.is_valid()
             {
                 return false;
             }
 
             // 3. Validate all transactions in the block
             for tx in &block.transactions {
-                if tx.sender != "COINBASE" && !tx.verify() {
+                if !tx.is_coinbase() && !tx.verify() {
                     return false; // Invalid transaction
                 }
             }
 
-            return true;
+            // 4. Validate the coinbase, so the miner can't pay itself more than the reward
+            if self.apply_coinbase(self.total_supply, block).is_none() {
+                return false;
+            }
+
+            // 5. Validate the transactions against the balances and nonces as of our tip,
+            // rejecting replayed transactions, overdrafts and double spends
+            return self.ledger.can_apply_block(block);
         }
         false
     }
 
+    /// Checks that don't depend on the chain the block belongs to,
+    /// blocks failing them aren't worth keeping in the block tree.
+    /// Only the genesis block has index 0, the index is checked against the parent
+    /// once the block connects to the chain or to a side branch.
+    fn is_well_formed_block(block: &Block) -> bool {
+        block.index > 0
+            && block.merkle_root == block.calculate_merkle_root()
+            && block.has_unique_transactions()
+            && block.hash == block.calculate_hash()
+            && block.is_valid()
+            && block
+                .transactions
+                .iter()
+                .all(|tx| tx.is_coinbase() || tx.verify())
+    }
+
+    /// Index of the parent of the block, looked up in the chain and in the side branches
+    fn parent_index(&self, block: &Block) -> Option<u64> {
+        self.chain
+            .iter()
+            .rev()
+            .find(|parent| parent.hash == block.previous_hash)
+            .or_else(|| self.block_tree.get_side_block(&block.previous_hash))
+            .map(|parent| parent.index)
+    }
+
+    /// Builds the chain ending at a side block, the main chain up to the fork point
+    /// followed by the side branch
+    fn side_branch_chain(&self, tip_hash: &str) -> Option<Vec<Block>> {
+        let mut branch = vec![];
+        let mut hash = tip_hash.to_string();
+        while let Some(block) = self.block_tree.get_side_block(&hash) {
+            hash = block.previous_hash.clone();
+            branch.push(block.clone());
+        }
+
+        let fork_index = self.chain.iter().position(|block| block.hash == hash)?;
+        let mut chain = self.chain[..=fork_index].to_vec();
+        chain.extend(branch.into_iter().rev());
+        Some(chain)
+    }
+
+    /// Processes a block received from the network.
+    /// Blocks extending the tip are appended, blocks of other branches are kept in the block
+    /// tree, and blocks whose parent is unknown wait as orphans until it arrives. Every block
+    /// connected this way may unblock orphans waiting for it, and when a side branch ends up
+    /// with more work than the main chain, the chain is reorganized to it.
+    /// Blocks too far ahead of our clock are dropped before anything else, and aren't kept.
+    pub fn process_block(&mut self, block: Block) -> BlockOutcome {
+        if self.knows_block(&block.hash) {
+            return BlockOutcome::Duplicate;
+        }
+        if Self::is_too_new(block.timestamp) {
+            return BlockOutcome::TooNew;
+        }
+        if !Self::is_well_formed_block(&block) {
+            return BlockOutcome::Invalid;
+        }
+        if self.parent_index(&block).is_none() {
+            let parent_hash = block.previous_hash.clone();
+            self.block_tree.add_orphan(block);
+            return BlockOutcome::Orphan(parent_hash);
+        }
+
+        let received_hash = block.hash.clone();
+        let mut extended = vec![];
+        let mut attached = vec![];
+        let mut pending = vec![block];
+        while let Some(block) = pending.pop() {
+            let hash = block.hash.clone();
+            if self.is_valid_new_block(&block) {
+                self.add_block(block.clone());
+                extended.push(block);
+            } else if block.previous_hash != self.get_last_block().hash
+                && self.parent_index(&block).map(|index| index + 1) == Some(block.index)
+            {
+                // Only the target is checked against the branch before the block is kept,
+                // the rest is done once the branch has enough work to become the main chain
+                let chain_work = self
+                    .side_branch_chain(&block.previous_hash)
+                    .filter(|parent_chain| block.bits == self.next_bits(parent_chain))
+                    .map(|parent_chain| {
+                        Self::chain_work(&parent_chain).saturating_add(block_work(block.bits))
+                    });
+                match chain_work {
+                    Some(chain_work) => {
+                        if !self.block_tree.insert_side_block(block, chain_work) {
+                            continue;
+                        }
+                    }
+                    None if hash == received_hash => return BlockOutcome::Invalid,
+                    None => continue,
+                }
+                attached.push(hash.clone());
+            } else if hash == received_hash {
+                return BlockOutcome::Invalid;
+            } else {
+                continue;
+            }
+            pending.extend(self.block_tree.take_orphans(&hash));
+        }
+
+        // Looking for the heaviest valid branch among the ones that just grew
+        let mut heaviest_branch = None;
+        let mut max_work = self.total_work();
+        for hash in attached.iter() {
+            if let Some(chain) = self.side_branch_chain(hash) {
+                let work = Self::chain_work(&chain);
+                if work > max_work && self.is_valid_branch(&chain) {
+                    max_work = work;
+                    heaviest_branch = Some(chain);
+                }
+            }
+        }
+
+        let reorganization = match heaviest_branch {
+            Some(chain) => self.reorganize(chain),
+            None if extended.is_empty() => return BlockOutcome::SideBranch,
+            None => Reorganization {
+                disconnected: vec![],
+                connected: vec![],
+            },
+        };
+
+        // Blocks appended above can be disconnected right away by the reorganization,
+        // those never reached the database, so they are left out of both lists
+        let disconnected_hashes: HashSet<String> = reorganization
+            .disconnected
+            .iter()
+            .map(|block| block.hash.clone())
+            .collect();
+        let extended_hashes: HashSet<String> =
+            extended.iter().map(|block| block.hash.clone()).collect();
+        let mut connected: Vec<Block> = extended
+            .into_iter()
+            .filter(|block| !disconnected_hashes.contains(&block.hash))
+            .collect();
+        connected.extend(reorganization.connected);
+        let disconnected = reorganization
+            .disconnected
+            .into_iter()
+            .filter(|block| !extended_hashes.contains(&block.hash))
+            .collect();
+
+        self.prune_block_tree();
+        BlockOutcome::NewTip(Reorganization {
+            disconnected,
+            connected,
+        })
+    }
+
+    /// Drops the side branches too deep below the tip to ever be reorganized to
+    fn prune_block_tree(&mut self) {
+        let tip_index = self.get_last_block().index;
+        self.block_tree
+            .prune(tip_index.saturating_sub(MAX_SIDE_BRANCH_DEPTH));
+    }
+
     // pub fn validate_block(&self, block: &Block) -> bool {
-    //     // Check if the block's hash matches the difficulty
-    //     let target = "0".repeat(self.difficulty);
-    //     block.hash.starts_with(&target)
+    //     // Check if the block's hash meets the target
+    //     block.is_valid()
     // }
 
-    pub fn replace_chain(&mut self, new_chain: Vec<Block>) {
+    /// Switches to `new_chain`, which must have been validated by the fork choice.
+    /// Both chains share every block up to their common ancestor, the blocks after it
+    /// are returned, so the database and the transaction pool can be updated as well.
+    /// Only those blocks are rolled back from the ledger and the supply, and applied.
+    pub fn reorganize(&mut self, new_chain: Vec<Block>) -> Reorganization {
+        let fork_index = self.fork_index(&new_chain);
+
+        let reorganization = Reorganization {
+            disconnected: self.chain[fork_index..].to_vec(),
+            connected: new_chain[fork_index..].to_vec(),
+        };
+
+        // The previous branch is kept in the block tree, it may become the heaviest again
+        for block in &reorganization.connected {
+            self.block_tree.remove_side_block(&block.hash);
+        }
+        let mut chain_work = Self::chain_work(&self.chain[..fork_index]);
+        for block in &reorganization.disconnected {
+            chain_work = chain_work.saturating_add(block_work(block.bits));
+            self.block_tree.insert_side_block(block.clone(), chain_work);
+        }
+
+        for block in reorganization.disconnected.iter().rev() {
+            self.ledger.revert_block(block);
+            self.total_supply = self.total_supply.saturating_sub(Self::paid_subsidy(block));
+        }
+        for block in &reorganization.connected {
+            self.ledger.apply_block(block);
+            if let Some(supply) = self.apply_coinbase(self.total_supply, block) {
+                self.total_supply = supply;
+            }
+        }
         self.chain = new_chain;
+        self.prune_block_tree();
+        reorganization
     }
 
     #[allow(dead_code)]
     pub fn mine_new_block(&self, data: Vec<Transaction>) -> Block {
-        let (mut mined_block, _, difficult) = self.prepare_block_for_mining(data);
-        mined_block.mine(difficult);
+        let (mut mined_block, _) = self.prepare_block_for_mining(data);
+        mined_block.mine();
 
         mined_block
     }
 
-    pub fn prepare_block_for_mining(&self, data: Vec<Transaction>) -> (Block, f64, usize) {
+    pub fn prepare_block_for_mining(&self, data: Vec<Transaction>) -> (Block, Amount) {
         // We need to compute the sum of fees, because it's used as reward for miners
-        let mut fees = 0.0;
-        let _ = &data.iter().for_each(|tx| fees += tx.fee.into_inner());
+        let fees = data
+            .iter()
+            .fold(Amount::ZERO, |fees, tx| fees.saturating_add(tx.fee));
 
         let last_block = self.chain.last().unwrap();
         let new_index = last_block.index + 1;
-        let new_timestamp = chrono::Utc::now().timestamp() as u64;
-        let new_block = Block::new(new_index, new_timestamp, data, last_block.hash.clone());
+        // A clock behind the median time past would make the block invalid
+        let new_timestamp =
+            (chrono::Utc::now().timestamp() as u64).max(Self::median_time_past(&self.chain) + 1);
+        let new_block = Block::new(
+            new_index,
+            new_timestamp,
+            data,
+            last_block.hash.clone(),
+            self.next_block_bits(),
+        );
         let mined_block = new_block;
-        (mined_block, fees, self.difficulty)
+        (mined_block, fees)
     }
 
     pub fn get_last_block(&self) -> &Block {
         self.chain.last().unwrap()
     }
+}
+
+/// Result of processing a block received from the network
+pub enum BlockOutcome {
+    /// The block is already in the chain or in the block tree
+    Duplicate,
+    /// The block failed validation and was discarded
+    Invalid,
+    /// The block is too far ahead of our clock, it was discarded but may be valid later
+    TooNew,
+    /// The parent of the block is unknown, holds the hash of the missing parent
+    Orphan(String),
+    /// The block was kept on a side branch, with no more work than the main chain
+    SideBranch,
+    /// The main chain changed, either extended or reorganized to a heavier branch
+    NewTip(Reorganization),
+}
+
+/// Result of switching to another chain, the blocks are in chain order
+pub struct Reorganization {
+    /// Blocks of the previous chain after the common ancestor, they are orphaned now
+    pub disconnected: Vec<Block>,
+    /// Blocks of the new chain after the common ancestor
+    pub connected: Vec<Block>,
+}
+
+impl Reorganization {
+    /// Transactions of the new branch, they must not stay in the transaction pool
+    pub fn confirmed_transactions(&self) -> Vec<Transaction> {
+        self.connected
+            .iter()
+            .flat_map(|block| block.transactions.clone())
+            .collect()
+    }
 
-    pub fn get_chain(&self) -> Vec<Block> {
-        self.chain.clone()
+    /// Transactions of the orphaned blocks that aren't part of the new branch.
+    /// Coinbase transactions are left out, their reward only exists in the orphaned block
+    pub fn orphaned_transactions(&self) -> Vec<Transaction> {
+        let confirmed: HashSet<String> = self
+            .confirmed_transactions()
+            .iter()
+            .map(|tx| tx.hash())
+            .collect();
+        self.disconnected
+            .iter()
+            .flat_map(|block| block.transactions.clone())
+            .filter(|tx| !tx.is_coinbase() && !confirmed.contains(&tx.hash()))
+            .collect()
     }
 }
 
@@ -130,7 +829,9 @@ pub fn create_genesis_block() -> Block {
         index: 0,                                               // First block has index 0
         timestamp: 0,         // Placeholder for the timestamp (e.g., Unix epoch time 0)
         transactions: vec![], // No transactions in the genesis block
+        merkle_root: merkle_root(&[]), // No transactions to commit to
         previous_hash: String::from("0"), // Special value to denote no parent block
+        bits: INITIAL_BITS,   // Not checked, the genesis block isn't mined
         hash: String::from("00000000000000000000000000000000"), // Predefined hash for genesis
         nonce: 0,             // PoW value starts at 0
     }
@@ -0,0 +1,161 @@
+use crate::constants::MAX_MESSAGE_SIZE;
+use crate::error::CodecError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the length prefix of every frame
+const LENGTH_PREFIX_SIZE: usize = 4;
+/// Size of the message type and the request id, which follow the length prefix
+const HEADER_SIZE: usize = 9;
+
+/// Kind of message carried by a frame.
+/// Requests carry a JSON `Request`, responses carry the JSON data answering it,
+/// and the end of stream marks the last frame of a response split in several frames,
+/// such as the blocks of the whole chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Request = 1,
+    Response = 2,
+    EndOfStream = 3,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(MessageType::Request),
+            2 => Ok(MessageType::Response),
+            3 => Ok(MessageType::EndOfStream),
+            _ => Err(CodecError::UnknownMessageType(value)),
+        }
+    }
+}
+
+/// A message of the P2P protocol, sent over the wire as:
+/// - the length of the rest of the frame, 4 bytes big-endian
+/// - the message type, 1 byte
+/// - the request id, 8 bytes big-endian
+/// - the payload
+///
+/// Requests sent over the same connection are told apart by their id,
+/// responses and ends of stream carry the id of the request they answer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub message_type: MessageType,
+    pub request_id: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Builds a frame with the value serialized as JSON
+    pub fn message<T: Serialize>(
+        message_type: MessageType,
+        request_id: u64,
+        value: &T,
+    ) -> Result<Self, CodecError> {
+        Ok(Frame {
+            message_type,
+            request_id,
+            payload: serde_json::to_vec(value)?,
+        })
+    }
+
+    /// Builds the frame that ends the response to the request
+    pub fn end_of_stream(request_id: u64) -> Self {
+        Frame {
+            message_type: MessageType::EndOfStream,
+            request_id,
+            payload: vec![],
+        }
+    }
+
+    /// Deserializes the JSON payload
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(&self.payload)?)
+    }
+}
+
+/// Builds the bytes of a frame, failing when it exceeds the maximum message size
+pub fn encode_frame(frame: &Frame) -> Result<Vec<u8>, CodecError> {
+    let length = HEADER_SIZE + frame.payload.len();
+    if length > MAX_MESSAGE_SIZE {
+        return Err(CodecError::MessageTooLarge(length));
+    }
+
+    let mut bytes = Vec::with_capacity(LENGTH_PREFIX_SIZE + length);
+    bytes.extend_from_slice(&(length as u32).to_be_bytes());
+    bytes.push(frame.message_type as u8);
+    bytes.extend_from_slice(&frame.request_id.to_be_bytes());
+    bytes.extend_from_slice(&frame.payload);
+    Ok(bytes)
+}
+
+/// Reads the next frame from the stream.
+/// The length is checked before reading the rest of the frame,
+/// so a peer can't make us allocate more than the maximum message size.
+pub async fn read_frame<R>(reader: &mut R) -> Result<Frame, CodecError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut length_prefix = [0u8; LENGTH_PREFIX_SIZE];
+    let mut read = 0;
+    // Telling apart a connection closed between frames from one closed in the middle of a frame
+    while read < LENGTH_PREFIX_SIZE {
+        match reader.read(&mut length_prefix[read..]).await? {
+            0 if read == 0 => return Err(CodecError::ConnectionClosed),
+            0 => return Err(CodecError::Truncated),
+            n => read += n,
+        }
+    }
+
+    let length = u32::from_be_bytes(length_prefix) as usize;
+    if length < HEADER_SIZE {
+        return Err(CodecError::IncompleteHeader);
+    }
+    if length > MAX_MESSAGE_SIZE {
+        return Err(CodecError::MessageTooLarge(length));
+    }
+
+    let mut body = vec![0u8; length];
+    if let Err(err) = reader.read_exact(&mut body).await {
+        return match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => Err(CodecError::Truncated),
+            _ => Err(err.into()),
+        };
+    }
+    let payload = body.split_off(HEADER_SIZE);
+    let mut request_id = [0u8; 8];
+    request_id.copy_from_slice(&body[1..]);
+    Ok(Frame {
+        message_type: MessageType::try_from(body[0])?,
+        request_id: u64::from_be_bytes(request_id),
+        payload,
+    })
+}
+
+/// Writes the frame, and flushes the stream
+pub async fn write_frame<W>(writer: &mut W, frame: &Frame) -> Result<(), CodecError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let bytes = encode_frame(frame)?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Writes a frame with the value serialized as JSON
+pub async fn write_message<W, T>(
+    writer: &mut W,
+    message_type: MessageType,
+    request_id: u64,
+    value: &T,
+) -> Result<(), CodecError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_frame(writer, &Frame::message(message_type, request_id, value)?).await
+}
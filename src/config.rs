@@ -1,3 +1,10 @@
+use crate::amount::Amount;
+use crate::constants::{
+    DEFAULT_MAX_INBOUND_PEERS, DEFAULT_MAX_OUTBOUND_PEERS, DEFAULT_MAX_POOL_BYTES,
+    DEFAULT_MAX_POOL_TRANSACTIONS, DEFAULT_MIN_RELAY_FEE, DEFAULT_NETWORK_ID,
+    DEFAULT_POOL_TRANSACTION_TTL_SECS, DIFFICULTY_ADJUSTMENT_WINDOW, HALVING_INTERVAL,
+    TARGET_BLOCK_TIME,
+};
 use serde::{Deserialize, Serialize};
 use serde_yaml::Error;
 use std::fs::File;
@@ -11,6 +18,84 @@ pub struct Config {
     pub bootstrap_address: Option<String>,
     pub node_id: String,
     pub miner_wallet_address: String,
+    /// Expected time between blocks in seconds, all nodes of a network must use the same value
+    #[serde(default = "default_target_block_time")]
+    pub target_block_time: u64,
+    /// Number of blocks between difficulty adjustments
+    #[serde(default = "default_difficulty_adjustment_window")]
+    pub difficulty_adjustment_window: u64,
+    /// Number of blocks between halvings of the block reward
+    #[serde(default = "default_halving_interval")]
+    pub halving_interval: u64,
+    /// Name of the network, peers of other networks are rejected during the handshake
+    #[serde(default = "default_network_id")]
+    pub network_id: String,
+    /// Number of peers the node opens a connection to
+    #[serde(default = "default_max_outbound_peers")]
+    pub max_outbound_peers: usize,
+    /// Number of connections the node accepts from peers
+    #[serde(default = "default_max_inbound_peers")]
+    pub max_inbound_peers: usize,
+    /// Whether the node announces itself on a UDP multicast group, and connects to the nodes
+    /// announcing themselves there, so nodes of a LAN find each other without a bootstrap node
+    #[serde(default)]
+    pub local_discovery: bool,
+    /// Number of transactions the pool holds, the ones with the lowest fees are evicted past it
+    #[serde(default = "default_max_pool_transactions")]
+    pub max_pool_transactions: usize,
+    /// Size in bytes of the transactions the pool holds
+    #[serde(default = "default_max_pool_bytes")]
+    pub max_pool_bytes: usize,
+    /// Fee in base units a transaction must pay to be accepted in the pool and relayed
+    #[serde(default = "default_min_relay_fee")]
+    pub min_relay_fee: Amount,
+    /// Seconds a transaction waits in the pool before it's dropped, if it hasn't been mined
+    #[serde(default = "default_pool_transaction_ttl")]
+    pub pool_transaction_ttl: u64,
+    /// Whether a database written by an older version of the node, whose blocks can't be
+    /// converted, is dropped after its records are backed up. Otherwise the node won't start
+    #[serde(default)]
+    pub drop_incompatible_database: bool,
+}
+
+fn default_target_block_time() -> u64 {
+    TARGET_BLOCK_TIME
+}
+
+fn default_difficulty_adjustment_window() -> u64 {
+    DIFFICULTY_ADJUSTMENT_WINDOW
+}
+
+fn default_halving_interval() -> u64 {
+    HALVING_INTERVAL
+}
+
+fn default_network_id() -> String {
+    DEFAULT_NETWORK_ID.to_string()
+}
+
+fn default_max_outbound_peers() -> usize {
+    DEFAULT_MAX_OUTBOUND_PEERS
+}
+
+fn default_max_inbound_peers() -> usize {
+    DEFAULT_MAX_INBOUND_PEERS
+}
+
+fn default_max_pool_transactions() -> usize {
+    DEFAULT_MAX_POOL_TRANSACTIONS
+}
+
+fn default_max_pool_bytes() -> usize {
+    DEFAULT_MAX_POOL_BYTES
+}
+
+fn default_min_relay_fee() -> Amount {
+    Amount::from_base_units(DEFAULT_MIN_RELAY_FEE)
+}
+
+fn default_pool_transaction_ttl() -> u64 {
+    DEFAULT_POOL_TRANSACTION_TTL_SECS
 }
 
 pub fn load_config(file_path: &str) -> Result<Config, Error> {
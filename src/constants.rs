@@ -1,7 +1,68 @@
 pub const MAX_SUPPLY: u64 = 21_000_000; // Same as Bitcoin
 pub const REWARD: u64 = 5; // Bitcoin started with 50 BTC per block
+pub const HALVING_INTERVAL: u64 = 2_100_000; // Blocks between reward halvings, REWARD * 2 * HALVING_INTERVAL = MAX_SUPPLY
 
-pub const TRANSACTION: &str = "transaction";
-pub const NEW_BLOCK: &str = "new_block";
-pub const GET_BLOCKCHAIN: &str = "get_blockchain";
+pub const INITIAL_BITS: u32 = 0x1e00ffff; // Compact target before the first retarget, ~6 leading hex zeros
+pub const POW_LIMIT_BITS: u32 = 0x207fffff; // Easiest allowed target, same as Bitcoin's regtest
+pub const TARGET_BLOCK_TIME: u64 = 30; // Seconds, Bitcoin targets 10 minutes
+pub const DIFFICULTY_ADJUSTMENT_WINDOW: u64 = 10; // Blocks between retargets, Bitcoin uses 2016
+pub const MEDIAN_TIME_SPAN: usize = 11; // Blocks whose median timestamp a new block must exceed, same as Bitcoin
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60; // Seconds a block timestamp can be ahead of our clock, same as Bitcoin
+
+pub const MAX_ORPHAN_BLOCKS: usize = 100; // Blocks kept while waiting for their parent
+pub const MAX_SIDE_BLOCKS: usize = 1000; // Blocks kept from branches other than the main chain
+pub const MAX_SIDE_BRANCH_DEPTH: u64 = 100; // Side blocks further below the tip are dropped
+
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024; // Bytes, larger P2P messages are rejected
+pub const PROTOCOL_VERSION: u32 = 6; // Increased on every change of the P2P protocol
+pub const MIN_PROTOCOL_VERSION: u32 = 6; // Oldest protocol version we can talk to
+pub const NODE_NETWORK: u64 = 1; // Service bit, the node stores and serves the whole chain
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 5; // Time to wait for each message of the handshake
+pub const PEER_QUEUE_SIZE: usize = 256; // Messages waiting to be written to a peer connection
+pub const PEER_CHECK_INTERVAL_SECS: u64 = 1; // Time between checks for new peers to connect to
+pub const RECONNECT_BACKOFF_MIN_SECS: u64 = 1; // First wait before reconnecting to a peer
+pub const RECONNECT_BACKOFF_MAX_SECS: u64 = 60; // The wait doubles after every failed attempt
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 5; // Failed attempts in a row before a peer is dropped
+pub const REQUEST_TIMEOUT_SECS: u64 = 10; // Time to wait for each response frame of a request
+pub const PEER_EVICTION_GRACE_SECS: u64 = 600; // Unreachable peers are kept at least this long
+pub const BAN_SCORE: u32 = 100; // Misbehavior score a peer is banned at
+pub const BAN_DURATION_SECS: i64 = 24 * 60 * 60; // Same as Bitcoin's default ban time
+pub const RATE_LIMIT_PER_SEC: u32 = 50; // Requests a peer can send per second on average
+pub const RATE_LIMIT_BURST: u32 = 200; // Requests a peer can send at once
+pub const ADDRESS_BOOK_EXPIRY_SECS: i64 = 7 * 24 * 60 * 60; // Addresses not heard of for a week are forgotten
+pub const MAX_ADDRESS_BOOK_SIZE: usize = 10_000; // Addresses kept in the address book, the worst ranked make room for verified ones
+pub const MAX_ADDRESSES_PER_SOURCE: usize = 256; // New addresses a single peer can add to the address book through addr
+pub const DEFAULT_MAX_OUTBOUND_PEERS: usize = 8; // Peers we open a connection to, same as Bitcoin
+pub const DEFAULT_MAX_INBOUND_PEERS: usize = 117; // Connections accepted from peers, same as Bitcoin
+pub const ADDR_GOSSIP_COUNT: usize = 23; // Addresses shared in a single addr or register reply
+pub const ADDR_RECENT_SECS: i64 = 3 * 60 * 60; // Only addresses seen this recently are shared
+pub const ADDR_GOSSIP_INTERVAL_SECS: u64 = 60; // Time between two addr messages to the peers
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000; // Addresses read from a single addr, same as Bitcoin
+pub const LOCAL_DISCOVERY_GROUP: &str = "239.255.77.77:7777"; // Multicast group local nodes announce themselves on
+pub const LOCAL_DISCOVERY_INTERVAL_SECS: u64 = 5; // Time between two announcements on the multicast group
+pub const MAX_LOCAL_ANNOUNCEMENT_SIZE: usize = 1024; // Larger datagrams on the multicast group are ignored
+pub const MAX_HEADERS_PER_REQUEST: usize = 2000; // Headers sent in answer to a get_headers, same as Bitcoin
+pub const MAX_SYNC_HEADERS: usize = 50 * MAX_HEADERS_PER_REQUEST; // Headers downloaded from a peer in a single sync, the rest wait for the next one
+pub const MAX_BLOCKS_PER_REQUEST: usize = 50; // Blocks asked for in a single get_blocks
+pub const BLOCK_BATCHES_PER_PEER: usize = 2; // Block batches downloaded, or waiting to be connected, per sync source
+pub const MAX_INVENTORY_PER_REQUEST: usize = 1000; // Hashes read from a single inv or get_data
+pub const MAX_KNOWN_INVENTORY: usize = 10_000; // Hashes remembered per peer, so items aren't announced twice
+pub const DEFAULT_MAX_POOL_TRANSACTIONS: usize = 5000; // Transactions kept in the pool, the lowest fees are evicted past it
+pub const DEFAULT_MAX_POOL_BYTES: usize = 5 * 1024 * 1024; // Bytes of transactions kept in the pool
+pub const DEFAULT_MIN_RELAY_FEE: u64 = 1000; // Base units, transactions paying less aren't accepted nor relayed
+pub const POOL_COMPACTION_INTERVAL_SECS: u64 = 60; // Time between two compactions of the transaction pool
+pub const DEFAULT_POOL_TRANSACTION_TTL_SECS: u64 = 3 * 60 * 60; // Time a transaction waits in the pool before it's dropped
+pub const MAX_DROPPED_TRANSACTIONS: usize = 10_000; // Dropped transactions remembered, with the reason they were dropped
+pub const SYNC_INTERVAL_SECS: u64 = 120; // Time between syncs, unless a block from another branch arrives
+pub const DEFAULT_NETWORK_ID: &str = "artemis-local";
+
+pub const INV: &str = "inv";
+pub const GET_DATA: &str = "get_data";
 pub const REGISTER: &str = "register";
+pub const ADDR: &str = "addr";
+pub const GET_BLOCK: &str = "get_block";
+pub const GET_HEADERS: &str = "get_headers";
+pub const GET_BLOCKS: &str = "get_blocks";
+pub const VERSION: &str = "version";
+pub const VERACK: &str = "verack";
+pub const REJECT: &str = "reject";
@@ -1,14 +1,51 @@
+use crate::address_book::PeerRecord;
+use crate::amount::Amount;
 use crate::block::Block;
+use crate::blockchain::Reorganization;
 use crate::error::DatabaseError;
+use crate::node_warn;
 use crate::transaction::Transaction;
-use sled::Db;
+use sled::{Db, Tree};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Version of the layout of the stored records, it must be increased
+/// whenever a change makes the records written by older versions unreadable.
+/// 2 - fixed-point amounts and transaction nonces
+/// 3 - merkle root in the block header
+/// 4 - compact PoW target in the block header
+/// 5 - balance table
+/// 6 - transaction valid-until height
+const SCHEMA_VERSION: u64 = 6;
+const SCHEMA_VERSION_KEY: &str = "meta:schema_version";
+const BALANCES_TREE: &str = "balances";
+const BANS_TREE: &str = "bans";
+const PEERS_TREE: &str = "peers";
+/// Oldest schema version whose blocks can still be converted to the current one
+const MIN_CONVERTIBLE_SCHEMA_VERSION: u64 = 4;
+
+/// Where the identity key of the node is stored, next to its database.
+/// It's kept out of the database directory, which the `dev` feature deletes on every start
+pub fn identity_path(node_id: &str) -> PathBuf {
+    PathBuf::from(format!("./database/identity-{}.key", node_id))
+}
 
 pub struct Database {
     pub db: Db,
+    /// Balance of every address with coins in the stored chain, updated when blocks are
+    /// stored and reverted, so balances don't need to be computed from the transactions
+    balances: Tree,
+    /// Peers banned for misbehaving, with the timestamp the ban ends at.
+    /// Kept apart from the chain data, so it survives the migrations that drop it
+    bans: Tree,
+    /// Address book of the peers, by address
+    peers: Tree,
 }
 
 impl Database {
-    pub fn new(node_id: String) -> Self {
+    /// Opens the database of the node, and migrates it to the current schema.
+    /// `drop_incompatible` allows dropping blocks that can't be converted, see `migrate`
+    pub fn new(node_id: String, drop_incompatible: bool) -> Self {
         let db_path_for_node = format!("./database/blockchain-db-{}", node_id);
 
         // If running over dev feature, the DB will be recreated every time we run the program again
@@ -23,14 +60,100 @@ impl Database {
             }
         }
 
-        if let Ok(db) = sled::open(db_path_for_node) {
-            Self { db }
-        } else {
-            panic!("Failed to open database");
+        let db: Db = match sled::open(db_path_for_node) {
+            Ok(db) => db,
+            Err(_) => panic!("Failed to open database"),
+        };
+        let balances = match db.open_tree(BALANCES_TREE) {
+            Ok(balances) => balances,
+            Err(_) => panic!("Failed to open the balances tree"),
+        };
+        let bans = match db.open_tree(BANS_TREE) {
+            Ok(bans) => bans,
+            Err(_) => panic!("Failed to open the bans tree"),
+        };
+        let database = match db.open_tree(PEERS_TREE) {
+            Ok(peers) => Self {
+                db,
+                balances,
+                bans,
+                peers,
+            },
+            Err(_) => panic!("Failed to open the peers tree"),
+        };
+
+        if let Err(err) = database.migrate(drop_incompatible) {
+            panic!("Failed to migrate database: {}", err);
         }
+        database
+    }
+
+    /// Brings data stored by older versions of the node up to the current schema.
+    /// Databases created before the schema version was tracked (version 1) store amounts
+    /// as floats, and transactions without nonces. Every schema change so far modified
+    /// data that is part of the signed and hashed data, so older blocks can't be converted
+    /// without invalidating their hashes and signatures.
+    /// Instead, the legacy records are dropped, and the chain is synced again from peers,
+    /// only when `drop_incompatible` is set, after they are copied to a backup tree.
+    /// Otherwise the migration fails, and the records are left untouched.
+    /// Version 4 only lacks the balance table, which is built once the chain is restored.
+    /// Versions 4 and 5 store transactions without the valid-until height, which doesn't
+    /// change the hash of the transactions that don't set it, so they are written again.
+    pub fn migrate(&self, drop_incompatible: bool) -> Result<(), DatabaseError> {
+        let stored_version: u64 = match self.db.get(SCHEMA_VERSION_KEY)? {
+            Some(value) => bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)?,
+            None => 1,
+        };
+
+        if stored_version < MIN_CONVERTIBLE_SCHEMA_VERSION && !self.db.is_empty()? {
+            if !drop_incompatible {
+                return Err(DatabaseError::IncompatibleSchema(stored_version));
+            }
+            let backup = self.backup(stored_version)?;
+            node_warn!(
+                "Dropping blockchain data stored with schema version {}, it was copied to the {} tree and will be synced again",
+                stored_version,
+                backup
+            );
+            self.db.clear()?;
+            self.balances.clear()?;
+        } else if stored_version == 4 || stored_version == 5 {
+            node_warn!("Writing the stored transactions with their valid-until height");
+            self.rewrite_transactions()?;
+        }
+
+        if stored_version != SCHEMA_VERSION {
+            self.db.insert(
+                SCHEMA_VERSION_KEY,
+                bincode::serialize(&SCHEMA_VERSION).map_err(|_| DatabaseError::BinCode)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Copies the records of the default tree to a `backup-v<version>` tree,
+    /// which no migration drops. Returns the name of the tree
+    fn backup(&self, stored_version: u64) -> Result<String, DatabaseError> {
+        let name = format!("backup-v{}", stored_version);
+        let backup = self.db.open_tree(&name)?;
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            backup.insert(key, value)?;
+        }
+        backup.flush()?;
+        Ok(name)
     }
 
     pub fn store_transaction(&self, tx: &Transaction, tx_hash: &str) -> Result<(), DatabaseError> {
+        // Storing the same transaction again must not move the balances twice
+        if !self.db.contains_key(tx_hash)? {
+            self.apply_transaction_to_balances(tx)?;
+        }
+        self.write_transaction(tx, tx_hash)
+    }
+
+    /// Stores the transaction record, its index entries, and the next nonce of the sender
+    fn write_transaction(&self, tx: &Transaction, tx_hash: &str) -> Result<(), DatabaseError> {
         // Store transaction by hash
         self.db.insert(
             tx_hash,
@@ -43,9 +166,25 @@ impl Database {
 
         self.add_transaction_to_index(&sender_key, tx_hash)?;
         self.add_transaction_to_index(&recipient_key, tx_hash)?;
+
+        // Track the next nonce of the sender, coinbase transactions use the block index instead
+        if !tx.is_coinbase() && tx.nonce >= self.get_wallet_nonce(&tx.sender)? {
+            self.db.insert(
+                format!("nonce_{}", tx.sender),
+                bincode::serialize(&(tx.nonce + 1)).map_err(|_| DatabaseError::BinCode)?,
+            )?;
+        }
         Ok(())
     }
 
+    /// Returns the nonce the next transaction sent by this wallet must use
+    pub fn get_wallet_nonce(&self, wallet_address: &str) -> Result<u64, DatabaseError> {
+        match self.db.get(format!("nonce_{}", wallet_address))? {
+            Some(value) => Ok(bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)?),
+            None => Ok(0),
+        }
+    }
+
     pub fn add_transaction_to_index(&self, key: &str, tx_hash: &str) -> Result<(), DatabaseError> {
         let mut tx_list: Vec<String> = match self.db.get(key)? {
             Some(value) => bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)?,
@@ -63,6 +202,46 @@ impl Database {
         Ok(())
     }
 
+    pub fn remove_transaction_from_index(
+        &self,
+        key: &str,
+        tx_hash: &str,
+    ) -> Result<(), DatabaseError> {
+        let mut tx_list: Vec<String> = match self.db.get(key)? {
+            Some(value) => bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)?,
+            None => return Ok(()),
+        };
+
+        tx_list.retain(|hash| hash != tx_hash);
+        if tx_list.is_empty() {
+            self.db.remove(key)?;
+        } else {
+            self.db.insert(
+                key,
+                bincode::serialize(&tx_list).map_err(|_| DatabaseError::BinCode)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Undoes `store_transaction`, removing the transaction and its index entries,
+    /// and moving the nonce of the sender back to the transaction nonce
+    pub fn revert_transaction(&self, tx: &Transaction, tx_hash: &str) -> Result<(), DatabaseError> {
+        if self.db.remove(tx_hash)?.is_some() {
+            self.revert_transaction_from_balances(tx)?;
+        }
+        self.remove_transaction_from_index(&format!("addr_{}", tx.sender), tx_hash)?;
+        self.remove_transaction_from_index(&format!("addr_{}", tx.recipient), tx_hash)?;
+
+        if !tx.is_coinbase() && tx.nonce < self.get_wallet_nonce(&tx.sender)? {
+            self.db.insert(
+                format!("nonce_{}", tx.sender),
+                bincode::serialize(&tx.nonce).map_err(|_| DatabaseError::BinCode)?,
+            )?;
+        }
+        Ok(())
+    }
+
     pub fn get_transaction(&self, tx_hash: &str) -> Result<Option<Transaction>, DatabaseError> {
         match self.db.get(tx_hash)? {
             Some(value) => Ok(Some(
@@ -95,22 +274,92 @@ impl Database {
         }
     }
 
-    pub fn get_wallet_balance(&self, wallet_address: &str) -> Result<f64, DatabaseError> {
-        let transactions = self.get_transactions_by_wallet(wallet_address)?;
+    /// Returns the balance of the wallet in the stored chain, read from the balance table
+    pub fn get_wallet_balance(&self, wallet_address: &str) -> Result<Amount, DatabaseError> {
+        match self.balances.get(wallet_address)? {
+            Some(value) => Ok(bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)?),
+            None => Ok(Amount::ZERO),
+        }
+    }
+
+    /// Stores the new balance of the wallet, addresses left without coins are removed
+    fn set_wallet_balance(
+        &self,
+        wallet_address: &str,
+        balance: Amount,
+    ) -> Result<(), DatabaseError> {
+        if balance == Amount::ZERO {
+            self.balances.remove(wallet_address)?;
+        } else {
+            self.balances.insert(
+                wallet_address,
+                bincode::serialize(&balance).map_err(|_| DatabaseError::BinCode)?,
+            )?;
+        }
+        Ok(())
+    }
 
-        let mut balance: f64 = 0.0;
+    /// Moves the amount from the sender to the recipient, and takes the fee from the sender.
+    /// The fee gets to the miner through the coinbase transaction of the block.
+    fn apply_transaction_to_balances(&self, tx: &Transaction) -> Result<(), DatabaseError> {
+        if !tx.is_coinbase() {
+            let cost = tx.amount.saturating_add(tx.fee);
+            let balance = self.get_wallet_balance(&tx.sender)?;
+            self.set_wallet_balance(&tx.sender, balance.saturating_sub(cost))?;
+        }
+        let balance = self.get_wallet_balance(&tx.recipient)?;
+        self.set_wallet_balance(&tx.recipient, balance.saturating_add(tx.amount))
+    }
 
-        transactions.iter().for_each(|tx| {
-            if tx.recipient == wallet_address {
-                balance += tx.amount.into_inner(); // Add received amount
+    /// Undoes `apply_transaction_to_balances`
+    fn revert_transaction_from_balances(&self, tx: &Transaction) -> Result<(), DatabaseError> {
+        let balance = self.get_wallet_balance(&tx.recipient)?;
+        self.set_wallet_balance(&tx.recipient, balance.saturating_sub(tx.amount))?;
+        if !tx.is_coinbase() {
+            let cost = tx.amount.saturating_add(tx.fee);
+            let balance = self.get_wallet_balance(&tx.sender)?;
+            self.set_wallet_balance(&tx.sender, balance.saturating_add(cost))?;
+        }
+        Ok(())
+    }
+
+    /// Brings the stored records to the chain restored on startup. The stored blocks left out
+    /// of it, from replaced chains or invalid, are removed with their transactions, then the
+    /// balance table and the nonces are computed again from the transactions of the chain only,
+    /// so they match the ledger of the chain
+    pub fn rebuild_from_chain(&self, chain: &[Block]) -> Result<(), DatabaseError> {
+        let hashes: HashSet<&String> = chain.iter().map(|block| &block.hash).collect();
+        for block in self.get_all_blocks().iter().rev() {
+            if !hashes.contains(&block.hash) {
+                self.revert_block(block)?;
             }
-            if tx.sender == wallet_address {
-                balance -= tx.amount.into_inner(); // Subtract sent amount
-                balance -= tx.fee.into_inner(); // Subtract sent fee
+        }
+
+        self.balances.clear()?;
+        for item in self.db.scan_prefix("nonce_").keys() {
+            self.db.remove(item?)?;
+        }
+        for block in chain {
+            self.store_block(block)?;
+            for tx in &block.transactions {
+                self.apply_transaction_to_balances(tx)?;
+                self.write_transaction(tx, &tx.hash())?;
             }
-        });
+        }
+        Ok(())
+    }
 
-        Ok(balance)
+    /// Writes the transaction records again from the transactions of the stored blocks
+    fn rewrite_transactions(&self) -> Result<(), DatabaseError> {
+        for block in self.get_all_blocks() {
+            for tx in &block.transactions {
+                self.db.insert(
+                    tx.hash(),
+                    bincode::serialize(tx).map_err(|_| DatabaseError::BinCode)?,
+                )?;
+            }
+        }
+        Ok(())
     }
 
     pub fn store_block(&self, block: &Block) -> Result<(), DatabaseError> {
@@ -145,10 +394,34 @@ impl Database {
             .collect();
 
         // Sort by the block index field (assuming u64 or similar for sorting purposes)
-        blocks.sort_by(|a, b| a.index.cmp(&b.index));
+        blocks.sort_by_key(|block| block.index);
         blocks
     }
 
+    /// Removes a block orphaned by a reorganization, with all its transactions
+    pub fn revert_block(&self, block: &Block) -> Result<(), DatabaseError> {
+        // Reverting in the opposite order they were applied, so the sender nonces
+        // end up at the first reverted transaction
+        for tx in block.transactions.iter().rev() {
+            self.revert_transaction(tx, &tx.hash())?;
+        }
+        self.db.remove(format!("block:{}", block.hash))?;
+        Ok(())
+    }
+
+    /// Brings the database to the new chain after a reorganization,
+    /// the orphaned blocks are reverted from the tip down to the common ancestor,
+    /// then the blocks of the new branch are stored
+    pub fn apply_reorganization(
+        &self,
+        reorganization: &Reorganization,
+    ) -> Result<(), DatabaseError> {
+        for block in reorganization.disconnected.iter().rev() {
+            self.revert_block(block)?;
+        }
+        self.store_blocks_and_transactions(reorganization.connected.clone())
+    }
+
     // Store a list of blocks with all their internal transactions
     pub fn store_blocks_and_transactions(&self, blocks: Vec<Block>) -> Result<(), DatabaseError> {
         // Loop through each block
@@ -164,4 +437,60 @@ impl Database {
         }
         Ok(())
     }
+
+    /// Bans the peer until the timestamp, in seconds
+    pub fn ban_peer(&self, address: &str, until: i64) -> Result<(), DatabaseError> {
+        self.bans.insert(
+            address,
+            bincode::serialize(&until).map_err(|_| DatabaseError::BinCode)?,
+        )?;
+        Ok(())
+    }
+
+    /// Checks whether the peer is banned at the timestamp, expired bans are removed
+    pub fn is_banned(&self, address: &str, now: i64) -> Result<bool, DatabaseError> {
+        let until: i64 = match self.bans.get(address)? {
+            Some(value) => bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)?,
+            None => return Ok(false),
+        };
+        if until <= now {
+            self.bans.remove(address)?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    pub fn store_peer(&self, record: &PeerRecord) -> Result<(), DatabaseError> {
+        self.peers.insert(
+            &record.address,
+            bincode::serialize(record).map_err(|_| DatabaseError::BinCode)?,
+        )?;
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_peer(&self, address: &str) -> Result<Option<PeerRecord>, DatabaseError> {
+        match self.peers.get(address)? {
+            Some(value) => Ok(Some(
+                bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns every record of the address book
+    pub fn get_peers(&self) -> Result<Vec<PeerRecord>, DatabaseError> {
+        self.peers
+            .iter()
+            .map(|item| {
+                let (_, value) = item?;
+                bincode::deserialize(&value).map_err(|_| DatabaseError::BinCode)
+            })
+            .collect()
+    }
+
+    pub fn remove_peer(&self, address: &str) -> Result<(), DatabaseError> {
+        self.peers.remove(address)?;
+        Ok(())
+    }
 }
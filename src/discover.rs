@@ -1,25 +1,86 @@
-use crate::{discover_error, discover_info};
+use crate::constants::{
+    ADDR, ADDR_GOSSIP_COUNT, ADDR_GOSSIP_INTERVAL_SECS, LOCAL_DISCOVERY_GROUP,
+    LOCAL_DISCOVERY_INTERVAL_SECS, MAX_LOCAL_ANNOUNCEMENT_SIZE,
+};
+use crate::peer_manager::PeerManager;
+use crate::{discover_error, discover_info, discover_warn};
 use crate::server::Request;
 use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use tokio::select;
 use tokio::sync::Mutex;
 
+/// Source of the addresses announced on the local discovery group, in the address book
+const LOCAL_NETWORK_SOURCE: &str = "local network";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Peer {
     pub(crate) id: String,
     pub(crate) address: String,
 }
 
+/// Sent on the local discovery group by the nodes running local discovery
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LocalAnnouncement {
+    pub(crate) network_id: String,
+    #[serde(flatten)]
+    pub(crate) peer: Peer,
+}
+
+/// Returns the address advertised by a local announcement, unless it comes from this node,
+/// or from a node of another network
+pub(crate) fn announced_address(
+    data: &[u8],
+    sender: SocketAddr,
+    network_id: &str,
+    this_peer: &Peer,
+) -> Option<String> {
+    let announcement: LocalAnnouncement = serde_json::from_slice(data).ok()?;
+    if announcement.network_id != network_id || announcement.peer.id == this_peer.id {
+        return None;
+    }
+    let mut address: SocketAddr = announcement.peer.address.parse().ok()?;
+    // A node listening on every interface is reached on the one it announced itself from
+    if address.ip().is_unspecified() {
+        address.set_ip(sender.ip());
+    }
+    let address = address.to_string();
+    if address == this_peer.address {
+        return None;
+    }
+    Some(address)
+}
+
+/// Binds a socket to the port of the group, and joins it. Several nodes of the same host
+/// share the port, and every one of them receives the announcements sent on the host.
+/// The datagrams are sent with a TTL of 1, so they don't leave the LAN.
+fn multicast_socket(group: SocketAddrV4) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()).into())?;
+    socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
 pub struct Discover {
     peers: Arc<Mutex<HashSet<String>>>,
+    peer_manager: PeerManager,
 }
 
 impl Discover {
-    pub fn new(peers: Arc<Mutex<HashSet<String>>>) -> Self {
-        Self { peers }
+    pub fn new(peers: Arc<Mutex<HashSet<String>>>, peer_manager: PeerManager) -> Self {
+        Self {
+            peers,
+            peer_manager,
+        }
     }
 
     pub async fn find_peers(
@@ -28,79 +89,57 @@ impl Discover {
         tcp_address: String,
         first_discover_done: Arc<Mutex<bool>>,
     ) {
+        // Peers known from previous runs are tried along with the bootstrap node,
+        // so the node can join the network again even when the bootstrap node is down
+        self.fill_outbound().await;
         // First 3-seconds sleep
         tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
         loop {
             discover_info!("Looking for discovering new peers");
-            let peers = { self.peers.lock().await.clone() };
+            // The peer manager connects to the peers of the list,
+            // the ones it can't reach are dropped from it
+            let peers = self.peer_manager.connected_peers().await;
 
             let mut receive_one_response = false;
-            for peer_address in peers {
-                if peer_address == tcp_address {
-                    continue;
-                }
-                if let Ok(mut stream) = TcpStream::connect(&peer_address).await {
-                    let this_peer = Peer {
-                        id: node_id.clone(),
-                        address: tcp_address.clone(),
-                    };
-                    
-                    let data = match serde_json::to_string(&this_peer){
-                        Ok(result) => result,
-                        Err(err) => {
-                            discover_error!("failed to serialize peer data: {}", err);
-                            continue;
-                        }
-                    };
-
-                    // Send request to register itself in the bootstrap node
-                    let request = Request {
-                        command: "register".to_string(),
-                        data,
-                    };
-
-                    let marshalled_request = match serde_json::to_string(&request){
-                        Ok(result) => result,
-                        Err(err) => {
-                            discover_error!("failed to serialize request: {}", err);
-                            continue;
-                        }
-                    };
-
-                    if stream
-                        .write_all(marshalled_request.as_bytes())
-                        .await
-                        .is_err()
-                    {
+            for peer_id in peers {
+                let this_peer = Peer {
+                    id: node_id.clone(),
+                    address: tcp_address.clone(),
+                };
+                
+                let data = match serde_json::to_string(&this_peer){
+                    Ok(result) => result,
+                    Err(err) => {
+                        discover_error!("failed to serialize peer data: {}", err);
                         continue;
                     }
+                };
 
-                    let mut buffer = [0; 1024];
-                    if let Ok(n) = stream.read(&mut buffer).await {
-                        let data = String::from_utf8_lossy(&buffer[..n]);
-                        if let Ok(remote_peers) = serde_json::from_str::<HashSet<String>>(&data) {
+                // Send request to register itself in the bootstrap node
+                let request = Request {
+                    command: "register".to_string(),
+                    data,
+                };
+
+                match self.peer_manager.request_one(&peer_id, &request).await {
+                    Ok(Some(frame)) => {
+                        if let Ok(remote_peers) = frame.decode::<Vec<String>>() {
                             receive_one_response = true;
+                            // The addresses go to the address book, the peers to connect
+                            // to are picked from it
                             for address in remote_peers {
                                 if address != tcp_address {
-                                    {
-                                        let mut peers = self.peers.lock().await;
-                                        if !peers.contains(&address.clone()) {
-                                            discover_info!(
-                                                "New peer discovered on address: {}",
-                                                address
-                                            );
-                                            peers.insert(address);
-                                        }
-                                    }
+                                    self.peer_manager
+                                        .address_book()
+                                        .learned(&address, &peer_id)
+                                        .await;
                                 }
                             }
                         }
                     }
-                } else {
-                    discover_error!("Failed to connect to peer: {}", peer_address);
-                    {
-                        // In the case the node can't connect to that peer, it will remove from the list
-                        self.peers.lock().await.remove(&peer_address);
+                    Ok(None) => discover_warn!("No peers received from {}", peer_id),
+                    Err(err) => {
+                        discover_warn!("Failed to read peers from {}: {}", peer_id, err)
                     }
                 }
                 if receive_one_response {
@@ -108,6 +147,9 @@ impl Discover {
                 }
             }
 
+            self.fill_outbound().await;
+            self.share_addresses().await;
+
             // Using a mutex for letting other tasks aware that this process
             // executed at least once
             {
@@ -115,7 +157,138 @@ impl Discover {
                     *first_discover_done.lock().await = true;
                 }
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+            tokio::time::sleep(tokio::time::Duration::from_secs(ADDR_GOSSIP_INTERVAL_SECS)).await;
+        }
+    }
+
+    /// Announces the node on the `LOCAL_DISCOVERY_GROUP` multicast group every
+    /// `LOCAL_DISCOVERY_INTERVAL_SECS`, and adds the nodes of the same network announcing
+    /// themselves there to the peer list, so nodes started on the same host or LAN
+    /// find each other without a bootstrap node
+    pub async fn discover_locally(&self, network_id: String, node_id: String, tcp_address: String) {
+        let group: SocketAddrV4 = LOCAL_DISCOVERY_GROUP
+            .parse()
+            .expect("invalid local discovery group");
+        let socket = match multicast_socket(group) {
+            Ok(socket) => socket,
+            Err(err) => {
+                discover_error!("Failed to join the local discovery group: {}", err);
+                return;
+            }
+        };
+        let this_peer = Peer {
+            id: node_id,
+            address: tcp_address,
+        };
+        let announcement = LocalAnnouncement {
+            network_id: network_id.clone(),
+            peer: this_peer.clone(),
+        };
+        let announcement = match serde_json::to_vec(&announcement) {
+            Ok(announcement) => announcement,
+            Err(err) => {
+                discover_error!("failed to serialize local announcement: {}", err);
+                return;
+            }
+        };
+        discover_info!("Announcing {} on {}", this_peer.address, group);
+
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
+            LOCAL_DISCOVERY_INTERVAL_SECS,
+        ));
+        // Larger datagrams are truncated, and fail to deserialize
+        let mut buffer = [0u8; MAX_LOCAL_ANNOUNCEMENT_SIZE];
+        loop {
+            select! {
+                _ = interval.tick() => {
+                    if let Err(err) = socket.send_to(&announcement, group).await {
+                        discover_warn!("Failed to announce on {}: {}", group, err);
+                    }
+                }
+                received = socket.recv_from(&mut buffer) => {
+                    let (size, sender) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            discover_warn!("Failed to read from {}: {}", group, err);
+                            continue;
+                        }
+                    };
+                    if let Some(address) =
+                        announced_address(&buffer[..size], sender, &network_id, &this_peer)
+                    {
+                        self.add_local_peer(address).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records a node found on the local network, and adds it to the peer list
+    /// unless the peer manager already has `max_outbound` peers to connect to
+    async fn add_local_peer(&self, address: String) {
+        self.peer_manager
+            .address_book()
+            .learned(&address, LOCAL_NETWORK_SOURCE)
+            .await;
+        if self.peer_manager.missing_outbound().await == 0 {
+            return;
+        }
+        if self.peers.lock().await.insert(address.clone()) {
+            discover_info!("New local peer found: {}", address);
+        }
+    }
+
+    /// Adds peers of the address book to the peer list, until the peer manager
+    /// has `max_outbound` peers to connect to
+    async fn fill_outbound(&self) {
+        let missing = self.peer_manager.missing_outbound().await;
+        if missing == 0 {
+            return;
+        }
+        // Peers already connected to us aren't picked, nor the networks they belong to
+        let mut exclude: HashSet<String> = self.peers.lock().await.clone();
+        exclude.extend(self.peer_manager.connected_addresses().await);
+        let addresses = self
+            .peer_manager
+            .address_book()
+            .select_outbound(missing, &exclude)
+            .await;
+
+        let mut peers = self.peers.lock().await;
+        for address in addresses {
+            if peers.insert(address.clone()) {
+                discover_info!(
+                    "New outbound peer picked from the address book: {}",
+                    address
+                );
+            }
+        }
+    }
+
+    /// Sends an `addr` to every connected peer, with a random sample of the addresses
+    /// seen recently, so peers learn about each other without everyone knowing everyone
+    async fn share_addresses(&self) {
+        let addresses = self
+            .peer_manager
+            .address_book()
+            .sample_recent(ADDR_GOSSIP_COUNT)
+            .await;
+        for peer_id in self.peer_manager.connected_peers().await {
+            let peer_address = self.peer_manager.peer_address(&peer_id).await;
+            let shared: Vec<&String> = addresses
+                .iter()
+                .filter(|address| Some(*address) != peer_address.as_ref())
+                .collect();
+            if shared.is_empty() {
+                continue;
+            }
+            let request = Request {
+                command: ADDR.to_string(),
+                data: serde_json::to_string(&shared).unwrap_or_default(),
+            };
+            if let Err(err) = self.peer_manager.send(&peer_id, &request).await {
+                discover_warn!("Failed to share addresses with {}: {}", peer_id, err);
+            }
         }
     }
 }
@@ -1,3 +1,4 @@
+use crate::amount::Amount;
 use std::array::TryFromSliceError;
 use std::io;
 use thiserror::Error;
@@ -39,5 +40,148 @@ pub enum DatabaseError {
         #[from]
         source: JsonError,
     },
+
+    #[error("The database was written with schema version {0}, and its blocks can't be converted. Set dropIncompatibleDatabase to back it up and sync the chain again from peers")]
+    IncompatibleSchema(u64),
+}
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Error reading or writing a message: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+
+    #[error("Error serializing message payload: {source}")]
+    Payload {
+        #[from]
+        source: JsonError,
+    },
+
+    #[error("Message of {0} bytes exceeds the maximum message size")]
+    MessageTooLarge(usize),
+
+    #[error("Unknown message type {0}")]
+    UnknownMessageType(u8),
+
+    #[error("Frame shorter than its header")]
+    IncompleteHeader,
+
+    #[error("Connection closed in the middle of a frame")]
+    Truncated,
+
+    #[error("Connection closed")]
+    ConnectionClosed,
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("Error connecting to peer: {source}")]
+    Connect {
+        #[from]
+        source: io::Error,
+    },
+
+    #[error("Error exchanging handshake messages: {source}")]
+    Codec {
+        #[from]
+        source: CodecError,
+    },
+
+    #[error("Peer belongs to network {0}")]
+    NetworkMismatch(String),
+
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedProtocolVersion(u32),
+
+    #[error("Peer has a different genesis block {0}")]
+    GenesisMismatch(String),
+
+    #[error("Peer doesn't provide the required services, it provides {0:#x}")]
+    MissingServices(u64),
+
+    #[error("Connected to ourselves")]
+    SelfConnection,
+
+    #[error("Error in the encrypted handshake: {source}")]
+    Noise {
+        #[from]
+        source: snow::Error,
+    },
+
+    #[error("Peer claims the id {0}, which doesn't match its key")]
+    IdentityMismatch(String),
+
+    #[error("Unexpected {0} message during the handshake")]
+    UnexpectedMessage(String),
+
+    #[error("Handshake rejected by peer: {0}")]
+    Rejected(String),
+
+    #[error("Timed out waiting for the peer")]
+    Timeout,
+}
+
+#[derive(Error, Debug)]
+pub enum PeerError {
+    #[error("Not connected to peer {0}")]
+    NotConnected(String),
+
+    #[error("Connection closed before the peer answered")]
+    Disconnected,
+
+    #[error("Timed out waiting for the peer to answer")]
+    Timeout,
+
+    #[error("Too many messages queued for peer {0}")]
+    QueueFull(String),
+
+    #[error("Error exchanging messages with peer: {source}")]
+    Codec {
+        #[from]
+        source: CodecError,
+    },
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PoolError {
+    #[error("Nonce {0} was already used")]
+    NonceTooLow(u64),
+
+    #[error("Another transaction of the sender uses the nonce {0}")]
+    NonceInUse(u64),
+
+    #[error("Transaction already submitted")]
+    Duplicate,
+
+    #[error("Insufficient funds")]
+    InsufficientFunds,
+
+    #[error("Fee {fee} is below the minimum relay fee {min_relay_fee}")]
+    FeeTooLow { fee: Amount, min_relay_fee: Amount },
+
+    #[error("The transaction pool is full, and the fee is too low to replace other transactions")]
+    PoolFull,
+
+    #[error("Transaction expired, it was valid until block {valid_until} and the next block is {height}")]
+    Expired { valid_until: u64, height: u64 },
 }
 
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("Error reading or writing the identity key: {source}")]
+    Io {
+        #[from]
+        source: io::Error,
+    },
+
+    #[error("Error reading the identity key: {source}")]
+    Json {
+        #[from]
+        source: JsonError,
+    },
+
+    #[error("Invalid identity key: {0}")]
+    InvalidKey(String),
+}
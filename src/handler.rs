@@ -1,7 +1,7 @@
 use crate::broadcaster::BroadcastItem;
 use crate::server::ServerHandler;
 use crate::server_info;
-use crate::transaction::{SignTransactionRequest, Transaction};
+use crate::transaction::{SignTransactionRequest, Transaction, TransactionStatus};
 use crate::wallet::Wallet;
 use actix_web::{get, post, web, HttpResponse, Responder};
 use std::sync::Arc;
@@ -13,31 +13,32 @@ pub async fn submit_transaction(
     transaction_request: web::Json<Transaction>,
 ) -> impl Responder {
     let tx = transaction_request.into_inner();
+    if tx.is_coinbase() {
+        return HttpResponse::BadRequest().body("Coinbase transactions can't be submitted");
+    }
 
     if tx.verify() {
         let server_handler = handler.into_inner();
         {
-            if let Ok(balance) = server_handler
-                .database
+            // Checked against the ledger of the chain, the same state blocks are validated
+            // against, and the blockchain lock is taken before the pool one, same as the miner
+            let blockchain = server_handler.blockchain.read().await;
+            if let Err(err) = server_handler
+                .transaction_pool
                 .lock()
                 .await
-                .get_wallet_balance(&tx.sender)
+                .add_transaction(
+                    tx.clone(),
+                    blockchain.get_next_nonce(&tx.sender),
+                    blockchain.get_balance(&tx.sender),
+                )
             {
-                if balance < tx.amount.into_inner() + tx.fee.into_inner() {
-                    return HttpResponse::BadRequest().body("Insufficient funds");
-                }
-            } else {
-                return HttpResponse::InternalServerError().body("Couldn't get wallet balance.");
+                return HttpResponse::BadRequest().body(err.to_string());
             }
-            server_handler
-                .transaction_pool
-                .lock()
-                .await
-                .add_transaction(tx.clone());
+        }
+        {
             server_handler
                 .broadcaster
-                .lock()
-                .await
                 .broadcast_item(BroadcastItem::Transaction(tx))
                 .await;
         }
@@ -81,31 +82,30 @@ pub async fn sign_and_submit_transaction(
     };
 
     let mut transaction = request.transaction;
+    if transaction.is_coinbase() {
+        return HttpResponse::BadRequest().body("Coinbase transactions can't be submitted");
+    }
     transaction.sign(&wallet);
 
     let server_handler = handler.into_inner();
     {
-        if let Ok(balance) = server_handler
-            .database
+        let blockchain = server_handler.blockchain.read().await;
+        if let Err(err) = server_handler
+            .transaction_pool
             .lock()
             .await
-            .get_wallet_balance(&transaction.sender)
+            .add_transaction(
+                transaction.clone(),
+                blockchain.get_next_nonce(&transaction.sender),
+                blockchain.get_balance(&transaction.sender),
+            )
         {
-            if balance < transaction.amount.into_inner() {
-                return HttpResponse::BadRequest().body("Insufficient funds");
-            }
-        } else {
-            return HttpResponse::InternalServerError().body("Couldn't get wallet balance.");
+            return HttpResponse::BadRequest().body(err.to_string());
         }
-        server_handler
-            .transaction_pool
-            .lock()
-            .await
-            .add_transaction(transaction.clone());
+    }
+    {
         server_handler
             .broadcaster
-            .lock()
-            .await
             .broadcast_item(BroadcastItem::Transaction(transaction))
             .await;
     }
@@ -160,6 +160,73 @@ pub async fn get_transaction_by_hash(
     }
 }
 
+/// Returns the merkle branch proving that the transaction is part of a block
+/// of the current chain, along with the block hash
+#[get("/transaction/{hash}/proof")]
+pub async fn get_transaction_proof(
+    handler: web::Data<Arc<ServerHandler>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let hash = path.into_inner();
+    if hash.is_empty() {
+        return HttpResponse::BadRequest().body("Invalid transaction hash");
+    };
+    let server_handler = handler.into_inner();
+
+    let proof = {
+        let blockchain = server_handler.blockchain.read().await;
+        blockchain
+            .chain
+            .iter()
+            .find_map(|block| block.merkle_proof(hash.as_str()))
+    };
+
+    match proof {
+        Some(proof) => HttpResponse::Ok().json(proof),
+        None => HttpResponse::NotFound().body("The transaction was not found in the chain"),
+    }
+}
+
+/// Returns whether the transaction was mined, is waiting in the pool,
+/// or was dropped from the pool, along with the reason
+#[get("/transaction/{hash}/status")]
+pub async fn get_transaction_status(
+    handler: web::Data<Arc<ServerHandler>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let hash = path.into_inner();
+    if hash.is_empty() {
+        return HttpResponse::BadRequest().body("Invalid transaction hash");
+    };
+    let server_handler = handler.into_inner();
+
+    match server_handler
+        .database
+        .lock()
+        .await
+        .get_transaction(hash.as_str())
+    {
+        Ok(Some(_)) => return HttpResponse::Ok().json(TransactionStatus::Confirmed),
+        Ok(None) => {}
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    }
+
+    let status = {
+        let pool = server_handler.transaction_pool.lock().await;
+        if pool.get_transaction(hash.as_str()).is_some() {
+            Some(TransactionStatus::Pending)
+        } else {
+            pool.dropped_reason(hash.as_str())
+                .map(|reason| TransactionStatus::Dropped { reason })
+        }
+    };
+
+    match status {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => HttpResponse::NotFound().body("The transaction is unknown to this node"),
+    }
+}
+
 #[get("/transaction/wallet/{address}")]
 pub async fn get_transactions_by_wallet(
     handler: web::Data<Arc<ServerHandler>>,
@@ -206,6 +273,30 @@ pub async fn get_wallet_balance(
     }
 }
 
+/// Returns the nonce the next transaction sent by this wallet must be signed with,
+/// after the transactions of the wallet waiting in the pool
+#[get("/wallet/nonce/{address}")]
+pub async fn get_wallet_nonce(
+    handler: web::Data<Arc<ServerHandler>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let address = path.into_inner(); // Extract the address hash from the path
+    if address.is_empty() {
+        return HttpResponse::BadRequest().body("Invalid wallet address");
+    };
+    let server_handler = handler.into_inner();
+
+    // Read from the ledger of the chain, the same state transactions are admitted against,
+    // and the blockchain lock is taken before the pool one, same as the miner
+    let blockchain = server_handler.blockchain.read().await;
+    let nonce = server_handler
+        .transaction_pool
+        .lock()
+        .await
+        .next_nonce(&address, blockchain.get_next_nonce(&address));
+    HttpResponse::Ok().json(nonce)
+}
+
 #[get("/block/{hash}")]
 pub async fn get_block_by_hash(
     handler: web::Data<Arc<ServerHandler>>,
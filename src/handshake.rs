@@ -0,0 +1,219 @@
+use crate::blockchain::Blockchain;
+use crate::codec::{read_frame, write_message, MessageType};
+use crate::constants::{
+    HANDSHAKE_TIMEOUT_SECS, MIN_PROTOCOL_VERSION, NODE_NETWORK, PROTOCOL_VERSION, REJECT, VERACK,
+    VERSION,
+};
+use crate::discover::Peer;
+use crate::error::{CodecError, HandshakeError};
+use crate::identity::Identity;
+use crate::server::Request;
+use crate::transport::{self, SecureStream};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+
+/// Sent by both nodes when a connection is opened, before any other request.
+/// Handshake messages use the request id 0, they are exchanged in a fixed order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Version {
+    #[serde(flatten)]
+    pub(crate) peer: Peer,
+    pub(crate) protocol_version: u32,
+    /// Name of the network, nodes of different test networks must not exchange blocks
+    pub(crate) network_id: String,
+    pub(crate) genesis_hash: String,
+    /// Index of the last block of the chain of the node
+    pub(crate) best_height: u64,
+    /// Bit field of the services provided by the node
+    pub(crate) services: u64,
+}
+
+/// Runs the handshakes for the connections of this node. The connection is first encrypted
+/// with a noise handshake, which authenticates the identity key of both nodes.
+/// Then the initiator sends its version, the other node checks it, and answers with its own
+/// version and a `verack`. The initiator checks it back and acknowledges it with a `verack`.
+/// A node that doesn't accept the other sends `reject` with the reason, and closes the connection.
+#[derive(Clone)]
+pub struct Handshake {
+    identity: Identity,
+    peer: Peer,
+    network_id: String,
+    blockchain: Arc<RwLock<Blockchain>>,
+}
+
+impl Handshake {
+    pub fn new(
+        identity: Identity,
+        tcp_address: String,
+        network_id: String,
+        blockchain: Arc<RwLock<Blockchain>>,
+    ) -> Self {
+        Self {
+            peer: Peer {
+                id: identity.id(),
+                address: tcp_address,
+            },
+            identity,
+            network_id,
+            blockchain,
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.peer.id
+    }
+
+    pub(crate) async fn local_version(&self) -> Version {
+        let blockchain = self.blockchain.read().await;
+        Version {
+            peer: self.peer.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            network_id: self.network_id.clone(),
+            genesis_hash: blockchain.chain[0].hash.clone(),
+            best_height: blockchain.get_last_block().index,
+            services: NODE_NETWORK,
+        }
+    }
+
+    /// Checks that the remote node belongs to the same network, and can talk to us
+    pub(crate) fn check_version(local: &Version, remote: &Version) -> Result<(), HandshakeError> {
+        if remote.network_id != local.network_id {
+            return Err(HandshakeError::NetworkMismatch(remote.network_id.clone()));
+        }
+        if remote.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedProtocolVersion(
+                remote.protocol_version,
+            ));
+        }
+        if remote.genesis_hash != local.genesis_hash {
+            return Err(HandshakeError::GenesisMismatch(remote.genesis_hash.clone()));
+        }
+        if remote.services & NODE_NETWORK == 0 {
+            return Err(HandshakeError::MissingServices(remote.services));
+        }
+        if remote.peer.id == local.peer.id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        Ok(())
+    }
+
+    /// The id announced in the version must be the key authenticated by the noise handshake,
+    /// nodes can't claim the id of another one
+    fn check_identity<S>(remote: &Version, stream: &SecureStream<S>) -> Result<(), HandshakeError> {
+        if remote.peer.id != stream.remote_id() {
+            return Err(HandshakeError::IdentityMismatch(remote.peer.id.clone()));
+        }
+        Ok(())
+    }
+
+    /// Connects to the peer, and runs the handshake as the initiator
+    pub(crate) async fn connect(
+        &self,
+        peer_address: &str,
+    ) -> Result<(SecureStream<TcpStream>, Version), HandshakeError> {
+        let stream = TcpStream::connect(peer_address).await?;
+        self.initiate(stream).await
+    }
+
+    /// Runs the handshake on a connection opened by this node
+    pub(crate) async fn initiate<S>(
+        &self,
+        stream: S,
+    ) -> Result<(SecureStream<S>, Version), HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = transport::initiate(stream, &self.identity).await?;
+        let local = self.local_version().await;
+        send(&mut stream, VERSION, encode_version(&local)?).await?;
+
+        let remote = decode_version(&expect(&mut stream, VERSION).await?)?;
+        let checked = Self::check_version(&local, &remote)
+            .and_then(|_| Self::check_identity(&remote, &stream));
+        if let Err(err) = checked {
+            // The peer may not listen anymore, the handshake fails anyway
+            let _ = send(&mut stream, REJECT, err.to_string()).await;
+            return Err(err);
+        }
+        expect(&mut stream, VERACK).await?;
+        send(&mut stream, VERACK, String::new()).await?;
+        Ok((stream, remote))
+    }
+
+    /// Runs the handshake on a connection opened by the remote node
+    pub(crate) async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> Result<(SecureStream<S>, Version), HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = transport::respond(stream, &self.identity).await?;
+        let remote = decode_version(&expect(&mut stream, VERSION).await?)?;
+        let local = self.local_version().await;
+        let checked = Self::check_version(&local, &remote)
+            .and_then(|_| Self::check_identity(&remote, &stream));
+        if let Err(err) = checked {
+            let _ = send(&mut stream, REJECT, err.to_string()).await;
+            return Err(err);
+        }
+
+        send(&mut stream, VERSION, encode_version(&local)?).await?;
+        send(&mut stream, VERACK, String::new()).await?;
+        expect(&mut stream, VERACK).await?;
+        Ok((stream, remote))
+    }
+}
+
+fn encode_version(version: &Version) -> Result<String, HandshakeError> {
+    Ok(serde_json::to_string(version).map_err(CodecError::from)?)
+}
+
+fn decode_version(data: &str) -> Result<Version, HandshakeError> {
+    Ok(serde_json::from_str(data).map_err(CodecError::from)?)
+}
+
+async fn send<S>(stream: &mut S, command: &str, data: String) -> Result<(), HandshakeError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let request = Request {
+        command: command.to_string(),
+        data,
+    };
+    Ok(write_message(stream, MessageType::Request, 0, &request).await?)
+}
+
+/// Reads the next handshake message, which must be `command`, and returns its data
+async fn expect<S>(stream: &mut S, command: &str) -> Result<String, HandshakeError>
+where
+    S: AsyncRead + Unpin,
+{
+    let read = tokio::time::timeout(
+        tokio::time::Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+        read_frame(stream),
+    )
+    .await;
+    let frame = match read {
+        Ok(frame) => frame?,
+        Err(_) => return Err(HandshakeError::Timeout),
+    };
+    if frame.message_type != MessageType::Request {
+        return Err(HandshakeError::UnexpectedMessage(format!(
+            "{:?}",
+            frame.message_type
+        )));
+    }
+
+    let request: Request = frame.decode()?;
+    if request.command == REJECT {
+        return Err(HandshakeError::Rejected(request.data));
+    }
+    if request.command != command {
+        return Err(HandshakeError::UnexpectedMessage(request.command));
+    }
+    Ok(request.data)
+}
@@ -0,0 +1,84 @@
+use crate::error::IdentityError;
+use crate::transport::NOISE_PARAMS;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Size of the x25519 keys
+const KEY_SIZE: usize = 32;
+
+/// Static x25519 key pair of the node, used to authenticate it in the encrypted handshake.
+/// The public key is the id of the node on the network, a peer can only claim it
+/// if it holds the private key.
+#[derive(Clone)]
+pub struct Identity {
+    public_key: Vec<u8>,
+    private_key: Vec<u8>,
+}
+
+/// Hex encoded keys, as stored in the key file
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    public_key: String,
+    private_key: String,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse().expect("valid noise parameters"))
+            .generate_keypair()
+            .expect("failed to generate the identity key");
+        Identity {
+            public_key: keypair.public,
+            private_key: keypair.private,
+        }
+    }
+
+    /// Reads the key pair stored at the path, or generates one and stores it there,
+    /// so the node keeps the same id across restarts
+    pub fn load_or_create(path: &Path) -> Result<Self, IdentityError> {
+        if path.exists() {
+            let key_file: KeyFile = serde_json::from_slice(&fs::read(path)?)?;
+            let decode = |key: &str| match hex::decode(key) {
+                Ok(key) if key.len() == KEY_SIZE => Ok(key),
+                _ => Err(IdentityError::InvalidKey(path.display().to_string())),
+            };
+            return Ok(Identity {
+                public_key: decode(&key_file.public_key)?,
+                private_key: decode(&key_file.private_key)?,
+            });
+        }
+
+        let identity = Identity::generate();
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let key_file = KeyFile {
+            public_key: identity.id(),
+            private_key: hex::encode(&identity.private_key),
+        };
+        // Only the owner of the node may read its private key, the file is created with
+        // these permissions so the key is never readable by others, not even briefly
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(path)?
+            .write_all(&serde_json::to_vec(&key_file)?)?;
+        Ok(identity)
+    }
+
+    /// Id of the node on the network, its hex encoded public key
+    pub fn id(&self) -> String {
+        hex::encode(&self.public_key)
+    }
+
+    pub fn private_key(&self) -> &[u8] {
+        &self.private_key
+    }
+}
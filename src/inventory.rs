@@ -0,0 +1,83 @@
+use crate::block::Block;
+use crate::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InventoryKind {
+    Block,
+    Transaction,
+}
+
+/// Announces a block or a transaction by its hash, in an `inv` message.
+/// Peers that don't have it yet ask for the whole item with `get_data`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Inventory {
+    pub kind: InventoryKind,
+    pub hash: String,
+}
+
+impl Inventory {
+    pub fn block(hash: &str) -> Self {
+        Inventory {
+            kind: InventoryKind::Block,
+            hash: hash.to_string(),
+        }
+    }
+
+    pub fn transaction(hash: &str) -> Self {
+        Inventory {
+            kind: InventoryKind::Transaction,
+            hash: hash.to_string(),
+        }
+    }
+}
+
+/// Item sent in answer to `get_data`, one per frame
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum InventoryData {
+    Block(Block),
+    Transaction(Transaction),
+}
+
+impl InventoryData {
+    pub fn inventory(&self) -> Inventory {
+        match self {
+            InventoryData::Block(block) => Inventory::block(&block.hash),
+            InventoryData::Transaction(tx) => Inventory::transaction(&tx.hash()),
+        }
+    }
+}
+
+/// Hashes of the items a peer is known to have, because it announced them, sent them to us,
+/// or we sent them to it. Only the latest `capacity` hashes are remembered,
+/// forgetting one at worst makes us announce the item to the peer once more.
+pub struct KnownInventory {
+    capacity: usize,
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl KnownInventory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers the hash, returns false if it was already known
+    pub fn insert(&mut self, hash: &str) -> bool {
+        if !self.hashes.insert(hash.to_string()) {
+            return false;
+        }
+        self.order.push_back(hash.to_string());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+}
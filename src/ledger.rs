@@ -0,0 +1,189 @@
+use crate::amount::Amount;
+use crate::block::Block;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Account state after applying the transactions of a chain,
+/// the balance and the next expected nonce of every address
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Ledger {
+    balances: HashMap<String, Amount>,
+    nonces: HashMap<String, u64>,
+}
+
+/// Accounts modified by a block, applied to the ledger only when the whole block is valid
+pub type LedgerChanges = (HashMap<String, Amount>, HashMap<String, u64>);
+
+/// Balances and nonces the blocks are applied to, the ledger itself or a view on top of it
+pub trait Accounts {
+    fn balance(&self, address: &str) -> Amount;
+
+    /// Returns the nonce the next transaction of this address must use
+    fn next_nonce(&self, address: &str) -> u64;
+
+    /// Overwrites the accounts modified by a block
+    fn update(&mut self, changes: LedgerChanges);
+
+    /// Checks that the transactions of the block can be applied on top of these accounts
+    fn can_apply_block(&self, block: &Block) -> bool {
+        block_changes(self, block).is_some()
+    }
+
+    /// Applies the transactions of the block, the accounts are left untouched
+    /// and false is returned if any of them is invalid
+    fn apply_block(&mut self, block: &Block) -> bool {
+        match block_changes(self, block) {
+            Some(changes) => {
+                self.update(changes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Undoes the last block applied, so the accounts are back to the state of its parent
+    fn revert_block(&mut self, block: &Block) {
+        let changes = revert_changes(self, block);
+        self.update(changes);
+    }
+}
+
+impl Ledger {
+    /// Builds the ledger of a chain that has already been validated
+    pub fn from_chain(chain: &[Block]) -> Self {
+        let mut ledger = Ledger::default();
+        for block in chain.iter().skip(1) {
+            ledger.apply_block(block);
+        }
+        ledger
+    }
+
+    /// Starts a view of this ledger, blocks applied or reverted on it leave the ledger untouched
+    pub fn view(&self) -> LedgerView<'_> {
+        LedgerView {
+            ledger: self,
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+        }
+    }
+}
+
+impl Accounts for Ledger {
+    fn balance(&self, address: &str) -> Amount {
+        self.balances.get(address).copied().unwrap_or(Amount::ZERO)
+    }
+
+    fn next_nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).copied().unwrap_or(0)
+    }
+
+    fn update(&mut self, (balances, nonces): LedgerChanges) {
+        self.balances.extend(balances);
+        self.nonces.extend(nonces);
+    }
+}
+
+/// Ledger seen through the changes of a branch, used to validate the branch without
+/// copying the ledger, only the accounts it modifies are kept
+pub struct LedgerView<'a> {
+    ledger: &'a Ledger,
+    balances: HashMap<String, Amount>,
+    nonces: HashMap<String, u64>,
+}
+
+impl Accounts for LedgerView<'_> {
+    fn balance(&self, address: &str) -> Amount {
+        self.balances
+            .get(address)
+            .copied()
+            .unwrap_or_else(|| self.ledger.balance(address))
+    }
+
+    fn next_nonce(&self, address: &str) -> u64 {
+        self.nonces
+            .get(address)
+            .copied()
+            .unwrap_or_else(|| self.ledger.next_nonce(address))
+    }
+
+    fn update(&mut self, (balances, nonces): LedgerChanges) {
+        self.balances.extend(balances);
+        self.nonces.extend(nonces);
+    }
+}
+
+/// Applies the transactions in the order they appear in the block.
+/// Every transaction must use the next nonce of its sender, which prevents a transaction
+/// from being applied twice, and the sender must afford the amount plus the fee with
+/// its balance at that point, so spending the same coins twice in a block is rejected.
+/// Transactions whose valid-until height is lower than the block index have expired.
+/// The fees are paid to the miner through the coinbase, which is validated on its own.
+fn block_changes<A: Accounts + ?Sized>(accounts: &A, block: &Block) -> Option<LedgerChanges> {
+    let mut balances: HashMap<String, Amount> = HashMap::new();
+    let mut nonces: HashMap<String, u64> = HashMap::new();
+    for tx in &block.transactions {
+        // Coinbase transactions use the block index as nonce
+        if tx.is_coinbase() {
+            if tx.nonce != block.index {
+                return None;
+            }
+        } else {
+            if tx.is_expired(block.index) {
+                return None;
+            }
+            let expected = nonces
+                .get(&tx.sender)
+                .copied()
+                .unwrap_or_else(|| accounts.next_nonce(&tx.sender));
+            if tx.nonce != expected {
+                return None;
+            }
+            nonces.insert(tx.sender.clone(), expected + 1);
+
+            let balance = balances
+                .get(&tx.sender)
+                .copied()
+                .unwrap_or_else(|| accounts.balance(&tx.sender));
+            let cost = tx.total_cost()?;
+            if balance < cost {
+                return None;
+            }
+            balances.insert(tx.sender.clone(), balance.saturating_sub(cost));
+        }
+
+        let received = balances
+            .get(&tx.recipient)
+            .copied()
+            .unwrap_or_else(|| accounts.balance(&tx.recipient))
+            .checked_add(tx.amount)?;
+        balances.insert(tx.recipient.clone(), received);
+    }
+    Some((balances, nonces))
+}
+
+/// Undoes the transactions of a block that was applied, from the last one to the first.
+/// The block was valid when applied, so every sender gets back the amount plus the fee,
+/// and its next nonce is the nonce of the transaction again
+fn revert_changes<A: Accounts + ?Sized>(accounts: &A, block: &Block) -> LedgerChanges {
+    let mut balances: HashMap<String, Amount> = HashMap::new();
+    let mut nonces: HashMap<String, u64> = HashMap::new();
+    for tx in block.transactions.iter().rev() {
+        let received = balances
+            .get(&tx.recipient)
+            .copied()
+            .unwrap_or_else(|| accounts.balance(&tx.recipient))
+            .saturating_sub(tx.amount);
+        balances.insert(tx.recipient.clone(), received);
+
+        if !tx.is_coinbase() {
+            let balance = balances
+                .get(&tx.sender)
+                .copied()
+                .unwrap_or_else(|| accounts.balance(&tx.sender))
+                .saturating_add(tx.total_cost().unwrap_or(Amount::ZERO));
+            balances.insert(tx.sender.clone(), balance);
+            nonces.insert(tx.sender.clone(), tx.nonce);
+        }
+    }
+    (balances, nonces)
+}
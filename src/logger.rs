@@ -9,6 +9,13 @@ macro_rules! node_info {
     };
 }
 
+#[macro_export]
+macro_rules! node_warn {
+    ($($arg:tt)*) => {
+        warn!(target: "node", "{}", format_args!($($arg)*))
+    };
+}
+
 #[macro_export]
 macro_rules! server_info {
     ($($arg:tt)*) => {
@@ -114,6 +121,20 @@ macro_rules! discover_warn {
     };
 }
 
+#[macro_export]
+macro_rules! peer_info {
+    ($($arg:tt)*) => {
+        info!(target: "peer", "{}", format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! peer_warn {
+    ($($arg:tt)*) => {
+        warn!(target: "peer", "{}", format_args!($($arg)*))
+    };
+}
+
 pub fn init_logger() {
     Builder::new()
         .filter(None, LevelFilter::Debug) // Keep all debug logs
@@ -128,6 +149,7 @@ pub fn init_logger() {
                 "broadcaster" => "[BROADCASTER]",
                 "sync" => "[SYNC]",
                 "discover" => "[DISCOVER]",
+                "peer" => "[PEER]",
                 _ => "[GENERAL]", // Default prefix
             };
             writeln!(
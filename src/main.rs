@@ -1,23 +1,36 @@
 #[macro_use]
 extern crate log;
 
+mod address_book;
+mod amount;
 mod block;
+mod block_tree;
 mod blockchain;
 mod broadcaster;
+mod codec;
 mod config;
 mod constants;
 mod db;
 mod discover;
 mod error;
 mod handler;
+mod handshake;
+mod identity;
+mod inventory;
+mod ledger;
 mod logger;
+mod merkle;
 mod miner;
 mod node;
+mod peer_manager;
+mod peer_score;
 mod pool;
+mod pow;
 mod server;
 mod sync;
 mod tests;
 mod transaction;
+mod transport;
 mod utils;
 mod wallet;
 
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Proof that a transaction is included in a block, without shipping the whole block.
+/// The branch holds the sibling hashes from the transaction leaf up to the root,
+/// and the index of the transaction tells, on each level, which side the sibling is on.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MerkleProof {
+    pub transaction_hash: String,
+    pub block_hash: String,
+    pub merkle_root: String,
+    pub index: usize,
+    pub branch: Vec<String>,
+}
+
+impl MerkleProof {
+    /// Checks that the branch links the transaction hash to the merkle root.
+    /// Light clients must also check that the block header with this merkle root
+    /// hashes to `block_hash`, and that it's part of the chain they follow.
+    #[allow(dead_code)]
+    pub fn verify(&self) -> bool {
+        verify_merkle_proof(
+            &self.transaction_hash,
+            self.index,
+            &self.branch,
+            &self.merkle_root,
+        )
+    }
+}
+
+fn hash_pair(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}{}", left, right));
+    hex::encode(hasher.finalize())
+}
+
+/// Computes the next level of the tree, when a level has an odd number of nodes,
+/// the last one is paired with itself (same as Bitcoin). A list ending with its last
+/// hashes repeated gets the same root, so blocks must not repeat a transaction
+/// (see `Block::has_unique_transactions`)
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/// Computes the merkle root of a list of transaction hashes.
+/// A block without transactions has a root made of zeros.
+pub fn merkle_root(hashes: &[String]) -> String {
+    if hashes.is_empty() {
+        return "0".repeat(64);
+    }
+
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Returns the sibling hashes needed to prove the inclusion of the hash at `index`
+pub fn merkle_branch(hashes: &[String], mut index: usize) -> Option<Vec<String>> {
+    if index >= hashes.len() {
+        return None;
+    }
+
+    let mut branch = vec![];
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        let sibling = if index.is_multiple_of(2) {
+            level.get(index + 1).unwrap_or(&level[index])
+        } else {
+            &level[index - 1]
+        };
+        branch.push(sibling.clone());
+
+        level = next_level(&level);
+        index /= 2;
+    }
+
+    Some(branch)
+}
+
+/// Recomputes the merkle root from a transaction hash and its branch,
+/// and compares it with the expected root
+#[allow(dead_code)]
+pub fn verify_merkle_proof(
+    transaction_hash: &str,
+    mut index: usize,
+    branch: &[String],
+    merkle_root: &str,
+) -> bool {
+    let mut current = transaction_hash.to_string();
+    for sibling in branch {
+        current = if index.is_multiple_of(2) {
+            hash_pair(&current, sibling)
+        } else {
+            hash_pair(sibling, &current)
+        };
+        index /= 2;
+    }
+
+    // All the index bits must have been consumed by the branch
+    index == 0 && current == merkle_root
+}
@@ -12,7 +12,7 @@ use tokio::time::Duration;
 
 pub struct Miner {
     blockchain: Arc<RwLock<Blockchain>>,
-    broadcaster: Arc<Mutex<Broadcaster>>,
+    broadcaster: Arc<Broadcaster>,
     block_rx: Receiver<Option<Block>>,
     transaction_pool: Arc<Mutex<TransactionPool>>,
     database: Arc<Mutex<Database>>,
@@ -25,7 +25,7 @@ impl Miner {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         blockchain: Arc<RwLock<Blockchain>>,
-        broadcaster: Arc<Mutex<Broadcaster>>,
+        broadcaster: Arc<Broadcaster>,
         block_rx: Receiver<Option<Block>>,
         transaction_pool: Arc<Mutex<TransactionPool>>,
         database: Arc<Mutex<Database>>,
@@ -55,10 +55,14 @@ impl Miner {
             }
 
             let data = {
-                self.transaction_pool
-                    .lock()
-                    .await
-                    .get_transactions_to_mine(self.transactions_per_block)
+                // The blockchain lock is acquired before the pool one, same order used when
+                // committing a mined block, so both tasks can't deadlock
+                let blockchain_read = self.blockchain.read().await;
+                self.transaction_pool.lock().await.get_transactions_to_mine(
+                    self.transactions_per_block,
+                    |sender| blockchain_read.get_next_nonce(sender),
+                    |sender| blockchain_read.get_balance(sender),
+                )
             };
             // If there are no transactions,
             // and this miner is configured to mine only when there are
@@ -74,30 +78,31 @@ impl Miner {
             let mut mined_block: Option<Block> = None;
 
             // Prepare a new block for mining
-            let (mut candidate_block, difficulty, miner_reward_tx) = {
+            let (mut candidate_block, miner_reward_tx) = {
                 let blockchain_read = self.blockchain.read().await;
-                let (candidate_block, fees, difficulty) =
+                let (candidate_block, fees) =
                     blockchain_read.prepare_block_for_mining(data.clone());
                 let miner_reward_tx =
                     blockchain_read.get_miner_transaction(self.wallet_address.clone(), fees);
-                (candidate_block, difficulty, miner_reward_tx)
+                (candidate_block, miner_reward_tx)
             };
 
             // Inserting miner reward transaction into the block, since we don't need
             // it into the transaction pool
-            if let Some(tx) = miner_reward_tx {
-                candidate_block.transactions.push(tx);
-            }
+            candidate_block.add_transaction(miner_reward_tx);
 
-            miner_info!("Starting mining with difficulty: {}", difficulty);
+            miner_info!(
+                "Starting mining with target bits: {:#010x}",
+                candidate_block.bits
+            );
             let start_time = Instant::now();
 
             loop {
                 // Incrementally mine
                 candidate_block.mine_step();
 
-                // Check if the block meets the difficulty
-                if candidate_block.is_valid(difficulty) {
+                // Check if the block meets the target
+                if candidate_block.is_valid() {
                     mined_block = Some(candidate_block.clone());
                     break;
                 }
@@ -105,17 +110,10 @@ impl Miner {
                 select! {
                     // If a new block is received from the network
                     Some(received_option) = self.block_rx.recv() => {
-                        if let Some(new_block) = received_option {
+                        if received_option.is_some() {
                             miner_info!("Received valid updated state during mining, aborting the current process");
-                            {
-                             self.transaction_pool.lock().await.process_mined_transactions(false, &new_block.clone().transactions);
-                            }
-                            // Executing the database persistence concurrently
-                            let persist_block = new_block.clone();
-                            let database = self.database.clone();
-                            tokio::spawn(async move {
-                                Self::save_mine_result(database, persist_block).await;
-                            });
+                            // The task that changed the chain has already updated the pool
+                            // and the database, the pending transactions are back in the pool
                             break; // Exit the mining loop and restart
                         }
                     }
@@ -139,25 +137,23 @@ impl Miner {
                         new_block,
                         start_time.elapsed()
                     );
+                    // Persisting while the write lock is held, same as commit_reorganization,
+                    // so a reorganization can't disconnect the block before it's stored
+                    Self::save_mine_result(&self.database, &new_block).await;
                     {
                         self.transaction_pool
                             .lock()
                             .await
-                            .process_mined_transactions(true, &new_block.transactions);
+                            .process_mined_transactions(
+                                true,
+                                &new_block.transactions,
+                                blockchain_write.next_height(),
+                            );
                         self.broadcaster
-                            .lock()
-                            .await
                             .broadcast_item(BroadcastItem::NewBlock(new_block.clone()))
                             .await;
                     }
 
-                    // Executing the database persistence concurrently
-                    let persist_block = new_block.clone();
-                    let database = self.database.clone();
-                    tokio::spawn(async move {
-                        Self::save_mine_result(database, persist_block).await;
-                    });
-
                     // Adding a 2-second delay on the miner that wins to make the process fair
                     // In production blockchains,
                     // like bitcoin's, there are a lot of built-in redundancy
@@ -175,10 +171,10 @@ impl Miner {
         }
     }
 
-    pub async fn save_mine_result(database: Arc<Mutex<Database>>, new_block: Block) {
+    pub async fn save_mine_result(database: &Mutex<Database>, new_block: &Block) {
         {
             let block_hash = new_block.hash.clone();
-            match database.lock().await.store_block(&new_block) {
+            match database.lock().await.store_block(new_block) {
                 Ok(_) => {
                     miner_info!("block with hash {} saved to database", block_hash);
                 }
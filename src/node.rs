@@ -2,15 +2,20 @@ use crate::block::Block;
 use crate::blockchain::Blockchain;
 use crate::broadcaster::Broadcaster;
 use crate::config::Config;
-use crate::db::Database;
+use crate::constants::{PEER_QUEUE_SIZE, POOL_COMPACTION_INTERVAL_SECS};
+use crate::db::{self, Database};
 use crate::discover::Discover;
+use crate::handshake::Handshake;
+use crate::identity::Identity;
 use crate::miner::Miner;
+use crate::peer_manager::PeerManager;
 use crate::pool::TransactionPool;
 use crate::server::ServerHandler;
 use crate::sync::Sync;
+use crate::{node_info, node_warn};
 use std::collections::HashSet;
 use std::sync::Arc;
-use tokio::sync::{mpsc::channel, Mutex, RwLock};
+use tokio::sync::{mpsc::channel, Mutex, Notify, RwLock};
 
 pub struct Node {
     pub blockchain: Arc<RwLock<Blockchain>>,
@@ -28,7 +33,48 @@ impl Node {
         let mut peers_set = HashSet::new();
         peers_set.insert(config.tcp_address.clone());
         let peers = Arc::new(Mutex::new(peers_set));
-        let database = Arc::new(Mutex::new(Database::new(config.node_id.clone())));
+        let database = Arc::new(Mutex::new(Database::new(
+            config.node_id.clone(),
+            config.drop_incompatible_database,
+        )));
+        // Every node of the network must use the same values, otherwise they won't agree
+        // on the difficulty and the rewards of the blocks
+        blockchain.write().await.set_difficulty_adjustment(
+            config.target_block_time,
+            config.difficulty_adjustment_window,
+        );
+        blockchain
+            .write()
+            .await
+            .set_halving_interval(config.halving_interval);
+        {
+            // Restoring the chain persisted by a previous run, before syncing and mining start,
+            // so the node doesn't need to download the whole chain from peers again
+            let stored_blocks = database.lock().await.get_all_blocks();
+            if stored_blocks.len() > 1 {
+                let mut blockchain_write = blockchain.write().await;
+                if blockchain_write.restore_from_blocks(stored_blocks) {
+                    node_info!(
+                        "Restored blockchain from database with {} blocks",
+                        blockchain_write.chain.len()
+                    );
+                } else {
+                    node_warn!(
+                        "Couldn't restore a valid chain from database, starting from genesis"
+                    );
+                }
+                // The balances and the nonces served from the database must be the ones
+                // of the restored chain, not of every block stored
+                if database
+                    .lock()
+                    .await
+                    .rebuild_from_chain(&blockchain_write.chain)
+                    .is_err()
+                {
+                    panic!("Unable to rebuild the database from the restored chain");
+                }
+            }
+        }
         {
             if database
                 .lock()
@@ -40,15 +86,51 @@ impl Node {
             }
         }
 
+        // The public key of the node is its id on the network, it's kept across restarts
+        let identity = match Identity::load_or_create(&db::identity_path(&config.node_id)) {
+            Ok(identity) => identity,
+            Err(err) => panic!("Failed to load the identity key: {}", err),
+        };
+        node_info!("Node id {}", identity.id());
+        let node_id = identity.id();
+
+        // Every connection between nodes starts with a handshake, which encrypts it,
+        // and rejects nodes of other networks, or with another genesis block
+        let handshake = Handshake::new(
+            identity,
+            config.tcp_address.clone(),
+            config.network_id.clone(),
+            self.blockchain.clone(),
+        );
+        // A single connection is kept with every peer, the requests received
+        // through them are passed on to the TCP server
+        let (incoming_tx, incoming_rx) = channel(PEER_QUEUE_SIZE);
+        let mut peer_manager = PeerManager::new(
+            handshake,
+            config.tcp_address.clone(),
+            peers.clone(),
+            incoming_tx,
+            database.clone(),
+        );
+        peer_manager.set_connection_limits(config.max_inbound_peers, config.max_outbound_peers);
+
         let (block_tx, block_rx) = channel::<Option<Block>>(20);
 
         let tx = Arc::new(Mutex::new(block_tx));
         let server_tx = tx.clone();
-        let broadcaster = Arc::new(Mutex::new(Broadcaster::new(
-            peers.clone(),
-            config.tcp_address.clone(),
-        )));
-        let transaction_pool = Arc::new(Mutex::new(TransactionPool::new()));
+        let broadcaster = Arc::new(Broadcaster::new(peer_manager.clone()));
+        let mut pool = TransactionPool::new();
+        pool.set_limits(
+            config.max_pool_transactions,
+            config.max_pool_bytes,
+            config.min_relay_fee,
+        );
+        pool.set_transaction_ttl(config.pool_transaction_ttl);
+        pool.set_height(self.blockchain.read().await.next_height());
+        let transaction_pool = Arc::new(Mutex::new(pool));
+
+        // Lets the server wake up the sync task when a block from another branch is received
+        let sync_notify = Arc::new(Notify::new());
 
         let server_broadcaster = broadcaster.clone();
         let server_tx_pool = transaction_pool.clone();
@@ -58,8 +140,9 @@ impl Node {
             server_tx,
             server_broadcaster,
             server_tx_pool,
-            peers.clone(),
             database.clone(),
+            sync_notify.clone(),
+            peer_manager.clone(),
         ));
 
         // TCP Server will be used for p2p communication between nodes
@@ -78,7 +161,14 @@ impl Node {
         let first_discover_done = Arc::new(Mutex::new(false));
         let first_sync_done = Arc::new(Mutex::new(false));
 
-        let mut sync = Sync::new(blockchain, peers.clone(), sync_tx, database.clone());
+        let mut sync = Sync::new(
+            blockchain,
+            sync_tx,
+            database.clone(),
+            transaction_pool.clone(),
+            sync_notify,
+            peer_manager.clone(),
+        );
 
         let blockchain = self.blockchain.clone();
         let miner_broadcaster = broadcaster.clone();
@@ -101,14 +191,14 @@ impl Node {
                     .insert(address);
             }
         }
-        let peers = peers.clone();
-        let mut discover = Discover::new(peers);
+        let mut discover = Discover::new(peers.clone(), peer_manager.clone());
+        let local_discover = Discover::new(peers, peer_manager.clone());
 
         // Run everything concurrently
         let _ = tokio::join!(
             async {
                 if let Err(err) = tcp_server
-                    .start_tcp_server(config.tcp_address.clone())
+                    .start_tcp_server(config.tcp_address.clone(), incoming_rx)
                     .await {
                     panic!("Failed to start TCP server: {}", err);
                 }
@@ -120,18 +210,32 @@ impl Node {
                      panic!("Failed to start HTTP server: {}", err);
                 }
             },
+            async {
+                peer_manager.connect_to_peers().await;
+            },
             async {
                 discover
                     .find_peers(
-                        config.node_id.clone(),
+                        node_id.clone(),
                         config.tcp_address.clone(),
                         first_discover_done.clone(),
                     )
                     .await;
             },
+            async {
+                // Nodes of the same host or LAN find each other without a bootstrap node
+                if config.local_discovery {
+                    local_discover
+                        .discover_locally(
+                            config.network_id.clone(),
+                            node_id.clone(),
+                            config.tcp_address.clone(),
+                        )
+                        .await;
+                }
+            },
             async {
                 sync.sync_with_peers(
-                    config.tcp_address.clone(),
                     first_discover_done.clone(),
                     first_sync_done.clone(),
                 )
@@ -139,6 +243,22 @@ impl Node {
             },
             async {
                 miner.mine(first_sync_done.clone()).await;
+            },
+            async {
+                // Transactions waiting for longer than the TTL are dropped,
+                // and the removed ones are dropped from the heap
+                loop {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(
+                        POOL_COMPACTION_INTERVAL_SECS,
+                    ))
+                    .await;
+                    let mut pool = transaction_pool.lock().await;
+                    let stale = pool.drop_stale(chrono::Utc::now().timestamp());
+                    if stale > 0 {
+                        node_info!("{} stale transactions dropped from the pool", stale);
+                    }
+                    pool.compact();
+                }
             }
         );
     }
@@ -0,0 +1,646 @@
+use crate::address_book::AddressBook;
+use crate::codec::{read_frame, write_frame, Frame, MessageType};
+use crate::constants::{
+    BAN_DURATION_SECS, DEFAULT_MAX_INBOUND_PEERS, DEFAULT_MAX_OUTBOUND_PEERS, INV,
+    MAX_KNOWN_INVENTORY, MAX_RECONNECT_ATTEMPTS, PEER_CHECK_INTERVAL_SECS,
+    PEER_EVICTION_GRACE_SECS, PEER_QUEUE_SIZE, RECONNECT_BACKOFF_MAX_SECS,
+    RECONNECT_BACKOFF_MIN_SECS, REQUEST_TIMEOUT_SECS,
+};
+use crate::db::Database;
+use crate::error::{CodecError, PeerError};
+use crate::handshake::{Handshake, Version};
+use crate::inventory::{Inventory, KnownInventory};
+use crate::peer_score::{Misbehavior, PeerScores};
+use crate::server::Request;
+use crate::transport::SecureStream;
+use crate::{peer_info, peer_warn};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{Duration, Instant};
+
+/// Senders waiting for the responses of the requests sent through a connection, by request id
+type PendingRequests = Arc<std::sync::Mutex<HashMap<u64, Sender<Frame>>>>;
+
+/// Request received from a peer, handled by the server
+pub struct IncomingRequest {
+    /// Id of the peer the request came from
+    pub peer_id: String,
+    pub request: Request,
+    pub responder: Responder,
+}
+
+/// Sends the response of a request back through the connection it came from
+pub struct Responder {
+    request_id: u64,
+    outbound: Sender<Frame>,
+}
+
+impl Responder {
+    /// Sends a response frame, a response can be split in several frames
+    pub async fn respond<T: Serialize>(&self, value: &T) -> Result<(), PeerError> {
+        let frame = Frame::message(MessageType::Response, self.request_id, value)?;
+        self.outbound
+            .send(frame)
+            .await
+            .map_err(|_| PeerError::Disconnected)
+    }
+
+    /// Ends the response, or tells the peer that we don't have what it asked for
+    pub async fn end(&self) -> Result<(), PeerError> {
+        self.outbound
+            .send(Frame::end_of_stream(self.request_id))
+            .await
+            .map_err(|_| PeerError::Disconnected)
+    }
+}
+
+/// Frames answering a request, received until the end of stream
+pub struct ResponseStream {
+    request_id: u64,
+    receiver: Receiver<Frame>,
+    pending: PendingRequests,
+}
+
+impl ResponseStream {
+    /// Returns the next response frame, or None once the response is complete.
+    /// Fails with `Disconnected` if the connection closed, or if the response was dropped
+    /// because `PEER_QUEUE_SIZE` frames were waiting to be read
+    pub async fn next(&mut self) -> Result<Option<Frame>, PeerError> {
+        let received = tokio::time::timeout(
+            Duration::from_secs(REQUEST_TIMEOUT_SECS),
+            self.receiver.recv(),
+        )
+        .await;
+        match received {
+            Ok(Some(frame)) if frame.message_type == MessageType::EndOfStream => Ok(None),
+            Ok(Some(frame)) => Ok(Some(frame)),
+            Ok(None) => Err(PeerError::Disconnected),
+            Err(_) => Err(PeerError::Timeout),
+        }
+    }
+}
+
+impl Drop for ResponseStream {
+    fn drop(&mut self) {
+        // Responses arriving after the requester gave up are discarded
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(&self.request_id);
+        }
+    }
+}
+
+struct Connection {
+    id: u64,
+    /// Address the peer listens on
+    address: String,
+    /// Node id of the node that opened the connection
+    initiator: String,
+    /// Whether the peer opened the connection
+    inbound: bool,
+    outbound: Sender<Frame>,
+    pending: PendingRequests,
+    closed: Arc<Notify>,
+    /// Blocks and transactions the peer has, they aren't announced to it
+    known_inventory: Arc<std::sync::Mutex<KnownInventory>>,
+}
+
+/// Keeps a single long-lived connection with every peer, used in both directions.
+/// Each connection has a task writing the frames queued for the peer, while the frames sent
+/// by the peer are read in a loop. Requests are passed on to the server, and responses
+/// are routed by request id to whoever is waiting for them.
+/// The other components talk to peers only through the manager.
+/// Peers are identified by the public key they authenticated with in the handshake,
+/// not by their address, which any node can claim.
+/// Peers that misbehave get a penalty, and are banned once their score reaches `BAN_SCORE`.
+#[derive(Clone)]
+pub struct PeerManager {
+    handshake: Handshake,
+    tcp_address: String,
+    peers: Arc<Mutex<HashSet<String>>>,
+    /// Connections by peer id
+    connections: Arc<Mutex<HashMap<String, Connection>>>,
+    /// Peer addresses with a task keeping a connection to them open
+    maintained: Arc<Mutex<HashSet<String>>>,
+    incoming: Sender<IncomingRequest>,
+    next_id: Arc<AtomicU64>,
+    scores: Arc<std::sync::Mutex<PeerScores>>,
+    /// Where the bans are stored, so they last across restarts
+    database: Arc<Mutex<Database>>,
+    address_book: AddressBook,
+    max_inbound: usize,
+    max_outbound: usize,
+}
+
+impl PeerManager {
+    pub fn new(
+        handshake: Handshake,
+        tcp_address: String,
+        peers: Arc<Mutex<HashSet<String>>>,
+        incoming: Sender<IncomingRequest>,
+        database: Arc<Mutex<Database>>,
+    ) -> Self {
+        Self {
+            handshake,
+            peers,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            maintained: Arc::new(Mutex::new(HashSet::new())),
+            incoming,
+            next_id: Arc::new(AtomicU64::new(1)),
+            scores: Arc::new(std::sync::Mutex::new(PeerScores::default())),
+            address_book: AddressBook::new(database.clone(), tcp_address.clone()),
+            tcp_address,
+            database,
+            max_inbound: DEFAULT_MAX_INBOUND_PEERS,
+            max_outbound: DEFAULT_MAX_OUTBOUND_PEERS,
+        }
+    }
+
+    /// Sets the number of connections opened by peers the node accepts,
+    /// and the number of peers it opens a connection to
+    pub fn set_connection_limits(&mut self, max_inbound: usize, max_outbound: usize) {
+        self.max_inbound = max_inbound;
+        self.max_outbound = max_outbound;
+    }
+
+    /// Starts a task keeping a connection open for the peers of the list,
+    /// up to `max_outbound` of them
+    pub async fn connect_to_peers(&self) {
+        loop {
+            let peers = { self.peers.lock().await.clone() };
+            for address in peers {
+                if address == self.tcp_address || self.is_banned(&address).await {
+                    continue;
+                }
+                let mut maintained = self.maintained.lock().await;
+                if maintained.len() >= self.max_outbound {
+                    break;
+                }
+                if maintained.insert(address.clone()) {
+                    let manager = self.clone();
+                    tokio::spawn(async move {
+                        manager.maintain_connection(address).await;
+                    });
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(PEER_CHECK_INTERVAL_SECS)).await;
+        }
+    }
+
+    /// Connects to the peer, and connects again whenever the connection is lost.
+    /// Failed attempts are retried with an exponential backoff, and the peer is dropped
+    /// after `MAX_RECONNECT_ATTEMPTS` failures in a row, once it has been unreachable
+    /// for `PEER_EVICTION_GRACE_SECS`, so a peer restarting isn't forgotten.
+    async fn maintain_connection(self, address: String) {
+        let mut backoff = RECONNECT_BACKOFF_MIN_SECS;
+        let mut failures = 0;
+        let mut failing_since = None;
+        loop {
+            if !self.peers.lock().await.contains(&address) || self.is_banned(&address).await {
+                break;
+            }
+            // The peer may have connected to us first
+            if self.connected_addresses().await.contains(&address) {
+                tokio::time::sleep(Duration::from_secs(PEER_CHECK_INTERVAL_SECS)).await;
+                continue;
+            }
+
+            match self.handshake.connect(&address).await {
+                Ok((stream, remote)) => {
+                    self.address_book.connected(&address, &remote.peer.id).await;
+                    failures = 0;
+                    failing_since = None;
+                    backoff = RECONNECT_BACKOFF_MIN_SECS;
+                    let initiator = self.handshake.node_id().to_string();
+                    self.run_connection(stream, address.clone(), remote, initiator)
+                        .await;
+                    tokio::time::sleep(Duration::from_secs(RECONNECT_BACKOFF_MIN_SECS)).await;
+                }
+                Err(err) => {
+                    self.address_book.failed(&address).await;
+                    failures += 1;
+                    let unreachable_for = failing_since.get_or_insert_with(Instant::now).elapsed();
+                    peer_warn!(
+                        "Failed to connect to {}, attempt {}: {}",
+                        address,
+                        failures,
+                        err
+                    );
+                    if failures >= MAX_RECONNECT_ATTEMPTS
+                        && unreachable_for >= Duration::from_secs(PEER_EVICTION_GRACE_SECS)
+                    {
+                        peer_warn!("Dropping unreachable peer {}", address);
+                        self.peers.lock().await.remove(&address);
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_secs(backoff)).await;
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX_SECS);
+                }
+            }
+        }
+        self.maintained.lock().await.remove(&address);
+    }
+
+    /// Runs the handshake on a connection opened by a peer, and keeps it open.
+    /// Connections over `max_inbound` are closed right away
+    pub async fn accept(&self, stream: TcpStream) {
+        if self.inbound_count().await >= self.max_inbound {
+            peer_info!(
+                "Refusing incoming connection, {} peers connected to us",
+                self.max_inbound
+            );
+            return;
+        }
+        match self.handshake.accept(stream).await {
+            Ok((stream, remote)) => {
+                let address = remote.peer.address.clone();
+                if self.is_banned(&remote.peer.id).await || self.is_banned(&address).await {
+                    peer_warn!("Refusing connection from banned peer {}", remote.peer.id);
+                    return;
+                }
+                let initiator = remote.peer.id.clone();
+                // The address is only claimed by the peer, it's verified once we connect to it
+                self.address_book.learned(&address, &remote.peer.id).await;
+                self.run_connection(stream, address, remote, initiator)
+                    .await;
+            }
+            Err(err) => peer_warn!("Handshake with incoming peer failed: {}", err),
+        }
+    }
+
+    /// Reads the frames sent by the peer until the connection is closed
+    async fn run_connection(
+        &self,
+        stream: SecureStream<TcpStream>,
+        address: String,
+        remote: Version,
+        initiator: String,
+    ) {
+        let connection_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (outbound, mut outbound_rx) = channel::<Frame>(PEER_QUEUE_SIZE);
+        let pending: PendingRequests = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let closed = Arc::new(Notify::new());
+        let inbound = initiator != self.handshake.node_id();
+        let peer_id = remote.peer.id.clone();
+        {
+            let mut connections = self.connections.lock().await;
+            // Checked again under the lock, peers may have finished their handshakes together
+            let inbound_count = connections
+                .values()
+                .filter(|connection| connection.inbound)
+                .count();
+            if inbound && inbound_count >= self.max_inbound {
+                peer_info!("Refusing connection from {}, too many peers", address);
+                return;
+            }
+            if let Some(existing) = connections.get(&peer_id) {
+                // When two nodes connect to each other at the same time, both keep the
+                // connection opened by the node with the lowest id, so they agree on one
+                if existing.initiator < initiator {
+                    return;
+                }
+                existing.closed.notify_one();
+            }
+            connections.insert(
+                peer_id.clone(),
+                Connection {
+                    id: connection_id,
+                    address: address.clone(),
+                    inbound,
+                    initiator,
+                    outbound: outbound.clone(),
+                    pending: pending.clone(),
+                    closed: closed.clone(),
+                    known_inventory: Arc::new(std::sync::Mutex::new(KnownInventory::new(
+                        MAX_KNOWN_INVENTORY,
+                    ))),
+                },
+            );
+        }
+        peer_info!(
+            "Connected to {} ({}), best height {}",
+            address,
+            remote.peer.id,
+            remote.best_height
+        );
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let writer_address = address.clone();
+        let writer_task = tokio::spawn(async move {
+            while let Some(frame) = outbound_rx.recv().await {
+                if let Err(err) = write_frame(&mut writer, &frame).await {
+                    peer_warn!("Failed to send message to {}: {}", writer_address, err);
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let read = select! {
+                read = read_frame(&mut reader) => read,
+                _ = closed.notified() => break,
+            };
+            let frame = match read {
+                Ok(frame) => frame,
+                Err(CodecError::ConnectionClosed) => break,
+                Err(err) => {
+                    peer_warn!("Failed to read message from {}: {}", address, err);
+                    // A connection can drop in the middle of a frame, anything else is the peer's fault
+                    if !matches!(err, CodecError::Io { .. } | CodecError::Truncated) {
+                        self.misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                            .await;
+                    }
+                    break;
+                }
+            };
+
+            match frame.message_type {
+                MessageType::Request if !self.allow_message(&peer_id) => {
+                    // Requests over the rate limit are dropped
+                    self.misbehaving(&peer_id, Misbehavior::TooManyMessages)
+                        .await;
+                }
+                MessageType::Request => match frame.decode::<Request>() {
+                    Ok(request) => {
+                        let incoming = IncomingRequest {
+                            peer_id: peer_id.clone(),
+                            request,
+                            responder: Responder {
+                                request_id: frame.request_id,
+                                outbound: outbound.clone(),
+                            },
+                        };
+                        if self.incoming.send(incoming).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        peer_warn!("Invalid request received from {}: {}", address, err);
+                        self.misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                            .await;
+                    }
+                },
+                MessageType::Response | MessageType::EndOfStream => {
+                    let request_id = frame.request_id;
+                    let waiting = match pending.lock() {
+                        Ok(mut pending) if frame.message_type == MessageType::EndOfStream => {
+                            pending.remove(&request_id)
+                        }
+                        Ok(pending) => pending.get(&request_id).cloned(),
+                        Err(_) => None,
+                    };
+                    // Nobody waits for responses of requests that timed out.
+                    // A requester not reading its response fast enough loses it, instead of
+                    // holding back the frames of the other requests on the connection
+                    if let Some(sender) = waiting {
+                        if let Err(TrySendError::Full(_)) = sender.try_send(frame) {
+                            peer_warn!(
+                                "Dropping the response to request {} from {}, it isn't read",
+                                request_id,
+                                address
+                            );
+                            if let Ok(mut pending) = pending.lock() {
+                                pending.remove(&request_id);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        writer_task.abort();
+        // Dropping the senders lets the requests waiting for a response fail right away
+        if let Ok(mut pending) = pending.lock() {
+            pending.clear();
+        }
+        {
+            let mut connections = self.connections.lock().await;
+            if connections.get(&peer_id).map(|connection| connection.id) == Some(connection_id) {
+                connections.remove(&peer_id);
+            }
+        }
+        peer_info!("Disconnected from {}", address);
+    }
+
+    fn allow_message(&self, peer_id: &str) -> bool {
+        match self.scores.lock() {
+            Ok(mut scores) => scores.allow_message(peer_id, Instant::now().into_std()),
+            Err(_) => true,
+        }
+    }
+
+    /// Adds the penalty of the misbehavior to the score of the peer.
+    /// Once the score reaches `BAN_SCORE`, the peer is banned for `BAN_DURATION_SECS`:
+    /// it's disconnected and forgotten, and its connections are refused until the ban ends.
+    /// Its id is banned, and when we opened the connection the address we connected to as well,
+    /// a new key alone doesn't lift that ban. The address an inbound peer reports isn't banned,
+    /// it could claim the address of an honest node to get it banned.
+    pub async fn misbehaving(&self, peer_id: &str, misbehavior: Misbehavior) {
+        let (banned, score) = match self.scores.lock() {
+            Ok(mut scores) => (scores.penalize(peer_id, misbehavior), scores.score(peer_id)),
+            Err(_) => return,
+        };
+        if !banned {
+            peer_warn!(
+                "Peer {} misbehaved ({:?}), score {}",
+                peer_id,
+                misbehavior,
+                score
+            );
+            return;
+        }
+
+        peer_warn!("Banning peer {} ({:?})", peer_id, misbehavior);
+        let until = chrono::Utc::now().timestamp() + BAN_DURATION_SECS;
+        let address = self
+            .connections
+            .lock()
+            .await
+            .get(peer_id)
+            .filter(|connection| !connection.inbound)
+            .map(|connection| connection.address.clone());
+        {
+            let database = self.database.lock().await;
+            for banned in std::iter::once(peer_id).chain(address.as_deref()) {
+                if let Err(err) = database.ban_peer(banned, until) {
+                    peer_warn!("Failed to store the ban of {}: {}", banned, err);
+                }
+            }
+        }
+        if let Some(address) = address {
+            self.peers.lock().await.remove(&address);
+            self.address_book.forget(&address).await;
+        }
+        if let Some(connection) = self.connections.lock().await.get(peer_id) {
+            connection.closed.notify_one();
+        }
+    }
+
+    /// Peer addresses stored across restarts, updated with the outcome of every connection
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
+    /// Whether the peer id or address is banned
+    pub async fn is_banned(&self, peer: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        matches!(self.database.lock().await.is_banned(peer, now), Ok(true))
+    }
+
+    #[allow(dead_code)]
+    pub async fn is_connected(&self, peer_id: &str) -> bool {
+        self.connections.lock().await.contains_key(peer_id)
+    }
+
+    async fn inbound_count(&self) -> usize {
+        let connections = self.connections.lock().await;
+        connections
+            .values()
+            .filter(|connection| connection.inbound)
+            .count()
+    }
+
+    /// Number of peers to add to the list, so the node opens `max_outbound` connections
+    pub async fn missing_outbound(&self) -> usize {
+        let peers = self.peers.lock().await;
+        let listed = peers
+            .iter()
+            .filter(|address| **address != self.tcp_address)
+            .count();
+        self.max_outbound.saturating_sub(listed)
+    }
+
+    /// Ids of the peers with an open connection
+    pub async fn connected_peers(&self) -> Vec<String> {
+        self.connections.lock().await.keys().cloned().collect()
+    }
+
+    /// Addresses the peers with an open connection listen on
+    pub async fn connected_addresses(&self) -> HashSet<String> {
+        let connections = self.connections.lock().await;
+        connections
+            .values()
+            .map(|connection| connection.address.clone())
+            .collect()
+    }
+
+    /// Address the connected peer listens on
+    pub async fn peer_address(&self, peer_id: &str) -> Option<String> {
+        let connections = self.connections.lock().await;
+        connections
+            .get(peer_id)
+            .map(|connection| connection.address.clone())
+    }
+
+    /// Queues a request for the peer, no response is expected.
+    /// The request is dropped when the queue of the peer is full.
+    pub async fn send(&self, peer_id: &str, request: &Request) -> Result<(), PeerError> {
+        let frame = Frame::message(MessageType::Request, 0, request)?;
+        let connections = self.connections.lock().await;
+        let connection = connections
+            .get(peer_id)
+            .ok_or_else(|| PeerError::NotConnected(peer_id.to_string()))?;
+        connection
+            .outbound
+            .try_send(frame)
+            .map_err(|err| match err {
+                TrySendError::Full(_) => PeerError::QueueFull(peer_id.to_string()),
+                TrySendError::Closed(_) => PeerError::Disconnected,
+            })
+    }
+
+    /// Queues an `inv` announcing the item for every connected peer that doesn't have it yet,
+    /// without waiting for it to be sent. The peers ask for the item itself if they need it.
+    /// A peer with a full queue misses it, instead of holding back the others.
+    pub async fn announce(&self, inventory: &Inventory) {
+        let request = match serde_json::to_string(&[inventory]) {
+            Ok(data) => Request {
+                command: INV.to_string(),
+                data,
+            },
+            Err(err) => {
+                peer_warn!("Failed to serialize inventory: {}", err);
+                return;
+            }
+        };
+        let frame = match Frame::message(MessageType::Request, 0, &request) {
+            Ok(frame) => frame,
+            Err(err) => {
+                peer_warn!("Failed to serialize {} request: {}", request.command, err);
+                return;
+            }
+        };
+        let connections = self.connections.lock().await;
+        for (peer_id, connection) in connections.iter() {
+            let unknown = match connection.known_inventory.lock() {
+                Ok(mut known) => known.insert(&inventory.hash),
+                Err(_) => true,
+            };
+            if !unknown {
+                continue;
+            }
+            if let Err(TrySendError::Full(_)) = connection.outbound.try_send(frame.clone()) {
+                peer_warn!("{}", PeerError::QueueFull(peer_id.clone()));
+            }
+        }
+    }
+
+    /// Records that the peer has these items, it announced them or they were sent to it
+    pub async fn mark_known(&self, peer_id: &str, inventory: &[Inventory]) {
+        let connections = self.connections.lock().await;
+        if let Some(connection) = connections.get(peer_id) {
+            if let Ok(mut known) = connection.known_inventory.lock() {
+                for item in inventory {
+                    known.insert(&item.hash);
+                }
+            }
+        }
+    }
+
+    /// Sends a request to the peer, returning the stream its response frames arrive on
+    pub async fn request(
+        &self,
+        peer_id: &str,
+        request: &Request,
+    ) -> Result<ResponseStream, PeerError> {
+        let (outbound, pending) = {
+            let connections = self.connections.lock().await;
+            match connections.get(peer_id) {
+                Some(connection) => (connection.outbound.clone(), connection.pending.clone()),
+                None => return Err(PeerError::NotConnected(peer_id.to_string())),
+            }
+        };
+
+        let request_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = Frame::message(MessageType::Request, request_id, request)?;
+        let (sender, receiver) = channel(PEER_QUEUE_SIZE);
+        if let Ok(mut pending) = pending.lock() {
+            pending.insert(request_id, sender);
+        }
+        let response = ResponseStream {
+            request_id,
+            receiver,
+            pending,
+        };
+
+        if outbound.send(frame).await.is_err() {
+            return Err(PeerError::Disconnected);
+        }
+        Ok(response)
+    }
+
+    /// Sends a request answered with a single frame, None means the peer doesn't have the data
+    pub async fn request_one(
+        &self,
+        peer_id: &str,
+        request: &Request,
+    ) -> Result<Option<Frame>, PeerError> {
+        self.request(peer_id, request).await?.next().await
+    }
+}
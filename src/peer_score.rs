@@ -0,0 +1,86 @@
+use crate::constants::{BAN_SCORE, RATE_LIMIT_BURST, RATE_LIMIT_PER_SEC};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Something a peer did that an honest node wouldn't do, each one adds a penalty to its score
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A block failing the validation
+    InvalidBlock,
+    /// Headers that don't form a valid chain
+    InvalidHeaders,
+    /// A transaction with a wrong signature, or a coinbase outside a block
+    InvalidTransaction,
+    /// A frame or a request that can't be read
+    MalformedMessage,
+    /// A block or a transaction we didn't ask for
+    UnrequestedData,
+    /// A request over the rate limit
+    TooManyMessages,
+}
+
+impl Misbehavior {
+    /// Penalty added to the score of the peer, it's banned once the score reaches `BAN_SCORE`.
+    /// Invalid blocks and headers can't be sent by mistake, they ban the peer right away
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock | Misbehavior::InvalidHeaders => BAN_SCORE,
+            Misbehavior::MalformedMessage | Misbehavior::UnrequestedData => 20,
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::TooManyMessages => 5,
+        }
+    }
+}
+
+/// Tokens refilled at `RATE_LIMIT_PER_SEC`, every request of the peer takes one
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Misbehavior scores and rate limits of the peers, only kept in memory.
+/// The bans they lead to are stored in the database.
+#[derive(Default)]
+pub struct PeerScores {
+    scores: HashMap<String, u32>,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl PeerScores {
+    /// Adds the penalty to the score of the peer.
+    /// Returns true when the peer must be banned, its score starts over after the ban.
+    pub fn penalize(&mut self, address: &str, misbehavior: Misbehavior) -> bool {
+        let score = self.scores.entry(address.to_string()).or_default();
+        *score = score.saturating_add(misbehavior.penalty());
+        if *score < BAN_SCORE {
+            return false;
+        }
+        self.scores.remove(address);
+        true
+    }
+
+    pub fn score(&self, address: &str) -> u32 {
+        self.scores.get(address).copied().unwrap_or(0)
+    }
+
+    /// Takes a token from the bucket of the peer, returns false when it's empty.
+    /// A peer can send `RATE_LIMIT_BURST` requests at once, then `RATE_LIMIT_PER_SEC` a second
+    pub fn allow_message(&mut self, address: &str, now: Instant) -> bool {
+        let bucket = self
+            .buckets
+            .entry(address.to_string())
+            .or_insert(TokenBucket {
+                tokens: RATE_LIMIT_BURST as f64,
+                updated: now,
+            });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * RATE_LIMIT_PER_SEC as f64).min(RATE_LIMIT_BURST as f64);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
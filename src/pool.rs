@@ -1,5 +1,24 @@
+use crate::amount::Amount;
+use crate::constants::{
+    DEFAULT_MAX_POOL_BYTES, DEFAULT_MAX_POOL_TRANSACTIONS, DEFAULT_MIN_RELAY_FEE,
+    DEFAULT_POOL_TRANSACTION_TTL_SECS, MAX_DROPPED_TRANSACTIONS,
+};
+use crate::error::PoolError;
 use crate::transaction::Transaction;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use serde::Serialize;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+/// Why a transaction left the pool without being mined
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DropReason {
+    /// Its valid-until height was reached before it was mined
+    Expired,
+    /// It waited in the pool for longer than the transaction TTL
+    Stale,
+    /// It was evicted by transactions paying higher fees, when the pool was full
+    Evicted,
+}
 
 /// Here is where all the incoming transactions will be added
 /// for being processed
@@ -24,6 +43,28 @@ pub struct TransactionPool {
     /// Transactions return to the pool if mining is interrupted,
     /// but duplicates are avoided.
     pub pending_map: HashMap<String, Transaction>,
+    /// Hash and cost of the transaction using each nonce of each sender, waiting or being mined,
+    /// so a reused nonce is found without going through the whole pool
+    nonces: HashMap<(String, u64), (String, Amount)>,
+    /// Amount debited from each sender by its transactions waiting or being mined
+    spends: HashMap<String, Amount>,
+    /// Size in bytes of the transactions in the pool, waiting or being mined
+    pub bytes: usize,
+    /// Limits of the pool, so a flood of transactions can't exhaust the memory of the node
+    max_transactions: usize,
+    max_bytes: usize,
+    /// Transactions paying a lower fee are rejected
+    min_relay_fee: Amount,
+    /// Index of the next block, transactions valid until a lower height have expired
+    height: u64,
+    /// Time each transaction entered the pool, in seconds since the epoch,
+    /// transactions waiting for longer than the TTL are dropped
+    added_at: HashMap<String, i64>,
+    transaction_ttl: u64,
+    /// Transactions recently dropped from the pool, with the reason,
+    /// only the latest `MAX_DROPPED_TRANSACTIONS` are remembered
+    dropped: HashMap<String, DropReason>,
+    dropped_order: VecDeque<String>,
 }
 
 impl TransactionPool {
@@ -33,28 +74,293 @@ impl TransactionPool {
             tx_map: HashMap::new(),
             removed_set: HashSet::new(),
             pending_map: Default::default(),
+            nonces: HashMap::new(),
+            spends: HashMap::new(),
+            bytes: 0,
+            max_transactions: DEFAULT_MAX_POOL_TRANSACTIONS,
+            max_bytes: DEFAULT_MAX_POOL_BYTES,
+            min_relay_fee: Amount::from_base_units(DEFAULT_MIN_RELAY_FEE),
+            height: 1,
+            added_at: HashMap::new(),
+            transaction_ttl: DEFAULT_POOL_TRANSACTION_TTL_SECS,
+            dropped: HashMap::new(),
+            dropped_order: VecDeque::new(),
+        }
+    }
+
+    /// Sets the number of transactions and the bytes the pool holds at most,
+    /// and the fee transactions must pay to be accepted
+    pub fn set_limits(&mut self, max_transactions: usize, max_bytes: usize, min_relay_fee: Amount) {
+        self.max_transactions = max_transactions;
+        self.max_bytes = max_bytes;
+        self.min_relay_fee = min_relay_fee;
+    }
+
+    /// Sets the seconds a transaction waits in the pool before it's dropped
+    pub fn set_transaction_ttl(&mut self, transaction_ttl: u64) {
+        self.transaction_ttl = transaction_ttl;
+    }
+
+    /// Sets the index of the next block, the waiting transactions that can't be
+    /// included in it anymore are dropped
+    pub fn set_height(&mut self, height: u64) {
+        self.height = height;
+        let expired: Vec<String> = self
+            .tx_map
+            .iter()
+            .filter(|(_, tx)| tx.is_expired(height))
+            .map(|(tx_hash, _)| tx_hash.clone())
+            .collect();
+        for tx_hash in expired {
+            self.drop_waiting(&tx_hash, DropReason::Expired);
         }
     }
 
-    /// This function checks if the transaction already exists in the pool,
-    /// checking both tx_map, and pending_pool (transactions under a mining process).
-    pub fn transaction_already_exists(&self, transaction: &Transaction) -> bool {
-        self.tx_map.contains_key(&transaction.hash())
-            || self.pending_map.contains_key(&transaction.hash())
+    /// Number of transactions in the pool, waiting or being mined
+    pub fn transaction_count(&self) -> usize {
+        self.tx_map.len() + self.pending_map.len()
     }
 
     /// Add a transaction to both the heap and the map.
-    /// If the transaction is already present, it won't be added
-    pub fn add_transaction(&mut self, transaction: Transaction) {
+    /// If the transaction is already present, it won't be added.
+    /// The transaction nonce is checked against the next nonce of the sender's account,
+    /// rejecting transactions that have already been applied, or that reuse the nonce
+    /// of another transaction in the pool.
+    /// Transactions paying less than the minimum relay fee are rejected, and when the pool
+    /// is full, the transactions with the lowest fees are evicted to make room for the new one.
+    /// Transactions that can't be included in the next block anymore are rejected too.
+    /// `balance` is the balance of the sender's account, which must cover the transaction
+    /// on top of the other transactions of the sender in the pool.
+    pub fn add_transaction(
+        &mut self,
+        transaction: Transaction,
+        next_nonce: u64,
+        balance: Amount,
+    ) -> Result<(), PoolError> {
+        if transaction.nonce < next_nonce {
+            return Err(PoolError::NonceTooLow(transaction.nonce));
+        }
+        if let Some(valid_until) = transaction.valid_until {
+            if transaction.is_expired(self.height) {
+                return Err(PoolError::Expired {
+                    valid_until,
+                    height: self.height,
+                });
+            }
+        }
+        if self.get_transaction(&transaction.hash()).is_some() {
+            return Err(PoolError::Duplicate);
+        }
+        if transaction.fee < self.min_relay_fee {
+            return Err(PoolError::FeeTooLow {
+                fee: transaction.fee,
+                min_relay_fee: self.min_relay_fee,
+            });
+        }
+
+        if self
+            .nonces
+            .contains_key(&(transaction.sender.clone(), transaction.nonce))
+        {
+            return Err(PoolError::NonceInUse(transaction.nonce));
+        }
+        // Otherwise transactions from empty accounts, paying high fees they can't afford,
+        // would evict the ones that can be mined
+        let spent = self.pending_spend(&transaction.sender);
+        match transaction
+            .total_cost()
+            .and_then(|cost| cost.checked_add(spent))
+        {
+            Some(total) if total <= balance => {}
+            _ => return Err(PoolError::InsufficientFunds),
+        }
+
+        self.make_room(&transaction)?;
+        self.insert_transaction(transaction);
+        Ok(())
+    }
+
+    fn insert_transaction(&mut self, transaction: Transaction) -> bool {
         let tx_hash = transaction.hash();
 
         // Avoid duplicate transactions
         if self.tx_map.contains_key(&tx_hash) || self.pending_map.contains_key(&tx_hash) {
-            return;
+            return false;
         }
 
+        self.bytes += transaction.size();
+        // Transactions returning from the pending queue keep the time they were first added
+        self.added_at
+            .entry(tx_hash.clone())
+            .or_insert_with(|| chrono::Utc::now().timestamp());
+        self.index_nonce(&transaction, &tx_hash);
         self.tx_map.insert(tx_hash.clone(), transaction.clone());
         self.heap.push(transaction);
+        true
+    }
+
+    /// Returns the nonce the next transaction of the sender must use, following its transactions
+    /// in the pool. `next_nonce` is the next nonce of the sender's account, in the chain
+    pub fn next_nonce(&self, sender: &str, next_nonce: u64) -> u64 {
+        self.nonces
+            .keys()
+            .filter(|(pending_sender, _)| pending_sender == sender)
+            .map(|(_, nonce)| nonce.saturating_add(1))
+            .fold(next_nonce, u64::max)
+    }
+
+    /// Amount the transactions of the sender in the pool debit from its account
+    pub fn pending_spend(&self, sender: &str) -> Amount {
+        self.spends.get(sender).copied().unwrap_or(Amount::ZERO)
+    }
+
+    /// Records the nonce and the cost of a transaction entering the pool
+    fn index_nonce(&mut self, transaction: &Transaction, tx_hash: &str) {
+        let cost = transaction.total_cost().unwrap_or(Amount::ZERO);
+        let key = (transaction.sender.clone(), transaction.nonce);
+        if let Some((_, replaced)) = self.nonces.insert(key, (tx_hash.to_string(), cost)) {
+            self.release_spend(&transaction.sender, replaced);
+        }
+        let spent = self.pending_spend(&transaction.sender).saturating_add(cost);
+        self.spends.insert(transaction.sender.clone(), spent);
+    }
+
+    /// Frees the nonce of a transaction leaving the pool, unless another transaction uses it
+    fn unindex_nonce(&mut self, transaction: &Transaction, tx_hash: &str) {
+        let key = (transaction.sender.clone(), transaction.nonce);
+        if self
+            .nonces
+            .get(&key)
+            .is_some_and(|(indexed, _)| indexed == tx_hash)
+        {
+            if let Some((_, cost)) = self.nonces.remove(&key) {
+                self.release_spend(&transaction.sender, cost);
+            }
+        }
+    }
+
+    fn release_spend(&mut self, sender: &str, cost: Amount) {
+        let spent = self.pending_spend(sender).saturating_sub(cost);
+        if spent == Amount::ZERO {
+            self.spends.remove(sender);
+        } else {
+            self.spends.insert(sender.to_string(), spent);
+        }
+    }
+
+    /// Removes a transaction being mined
+    fn remove_pending(&mut self, tx_hash: &str) {
+        if let Some(tx) = self.pending_map.remove(tx_hash) {
+            self.bytes = self.bytes.saturating_sub(tx.size());
+            self.unindex_nonce(&tx, tx_hash);
+        }
+    }
+
+    /// Evicts the waiting transactions with the lowest fees, until the transaction fits
+    /// in the pool. Nothing is evicted if the transaction doesn't have a higher priority
+    /// than all the ones it would replace, transactions being mined are never evicted.
+    fn make_room(&mut self, transaction: &Transaction) -> Result<(), PoolError> {
+        let mut count = self.transaction_count() + 1;
+        let mut bytes = self.bytes + transaction.size();
+        let fits =
+            |count: usize, bytes: usize| count <= self.max_transactions && bytes <= self.max_bytes;
+        if fits(count, bytes) {
+            return Ok(());
+        }
+
+        let mut candidates: Vec<&Transaction> = self.tx_map.values().collect();
+        candidates.sort();
+        let mut evicted = vec![];
+        for candidate in candidates {
+            if fits(count, bytes) || candidate >= transaction {
+                break;
+            }
+            count -= 1;
+            bytes = bytes.saturating_sub(candidate.size());
+            evicted.push(candidate.hash());
+        }
+        if !fits(count, bytes) {
+            return Err(PoolError::PoolFull);
+        }
+
+        for tx_hash in evicted {
+            self.drop_waiting(&tx_hash, DropReason::Evicted);
+        }
+        Ok(())
+    }
+
+    /// Removes a transaction waiting in the pool, it's left in the heap until it's popped
+    fn remove_waiting(&mut self, tx_hash: &str) {
+        if let Some(tx) = self.tx_map.remove(tx_hash) {
+            self.bytes = self.bytes.saturating_sub(tx.size());
+            self.unindex_nonce(&tx, tx_hash);
+            self.removed_set.insert(tx_hash.to_string());
+        }
+    }
+
+    /// Removes a waiting transaction that won't be mined, remembering why
+    fn drop_waiting(&mut self, tx_hash: &str, reason: DropReason) {
+        if self.tx_map.contains_key(tx_hash) {
+            self.remove_waiting(tx_hash);
+            self.record_dropped(tx_hash, reason);
+        }
+    }
+
+    fn record_dropped(&mut self, tx_hash: &str, reason: DropReason) {
+        if self.dropped.insert(tx_hash.to_string(), reason).is_none() {
+            self.dropped_order.push_back(tx_hash.to_string());
+        }
+        while self.dropped_order.len() > MAX_DROPPED_TRANSACTIONS {
+            if let Some(oldest) = self.dropped_order.pop_front() {
+                self.dropped.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns why the transaction was dropped from the pool, if it was recently
+    pub fn dropped_reason(&self, tx_hash: &str) -> Option<DropReason> {
+        self.dropped.get(tx_hash).copied()
+    }
+
+    /// Drops the transactions that have been waiting in the pool for longer than the TTL,
+    /// `now` is in seconds since the epoch. Transactions being mined are kept, and return
+    /// to the pool with their original time if the block isn't mined.
+    /// Returns how many transactions were dropped.
+    pub fn drop_stale(&mut self, now: i64) -> usize {
+        let oldest_allowed = now.saturating_sub(self.transaction_ttl as i64);
+        let stale: Vec<String> = self
+            .tx_map
+            .keys()
+            .filter(|tx_hash| {
+                self.added_at
+                    .get(*tx_hash)
+                    .is_some_and(|added_at| *added_at < oldest_allowed)
+            })
+            .cloned()
+            .collect();
+        for tx_hash in &stale {
+            self.drop_waiting(tx_hash, DropReason::Stale);
+        }
+        stale.len()
+    }
+
+    /// Rebuilds the heap from the transactions still waiting, so the removed transactions
+    /// don't pile up in the heap and the removed set until they are popped
+    pub fn compact(&mut self) {
+        let tx_map = &self.tx_map;
+        let pending_map = &self.pending_map;
+        self.added_at
+            .retain(|tx_hash, _| tx_map.contains_key(tx_hash) || pending_map.contains_key(tx_hash));
+        if self.removed_set.is_empty() {
+            return;
+        }
+        self.heap = self.tx_map.values().cloned().collect();
+        self.removed_set.clear();
+    }
+
+    /// Looks up a transaction waiting in the pool, or being mined
+    pub fn get_transaction(&self, hash: &str) -> Option<&Transaction> {
+        self.tx_map.get(hash).or_else(|| self.pending_map.get(hash))
     }
 
     /// Get the next valid transaction, skipping removed ones.
@@ -75,6 +381,8 @@ impl TransactionPool {
 
             // Remove from tx_map and return the valid transaction
             self.tx_map.remove(&tx_hash);
+            self.bytes = self.bytes.saturating_sub(tx.size());
+            self.unindex_nonce(&tx, &tx_hash);
             return Some(tx);
         }
         None
@@ -83,37 +391,153 @@ impl TransactionPool {
     /// Get n number of next prioritized transactions, to be mined.
     /// All popped transactions will be placed in the pending queue,
     /// which represents transactions under the mining process.
-    pub fn get_transactions_to_mine(&mut self, amount: i32) -> Vec<Transaction> {
+    /// Since transactions of the same sender must be mined in nonce order,
+    /// `next_nonce` gives the next nonce of each sender's account;
+    /// transactions with an already used nonce are dropped, and the ones
+    /// that can't be mined yet are returned to the pool.
+    /// Same as the block validation, `balance` gives the balance of each account, and
+    /// transactions their sender can't afford at that point of the block are returned
+    /// to the pool as well, they may be mined once the sender receives more coins.
+    /// Transactions that can't be included in the next block anymore are dropped.
+    pub fn get_transactions_to_mine<F, G>(
+        &mut self,
+        amount: i32,
+        next_nonce: F,
+        balance: G,
+    ) -> Vec<Transaction>
+    where
+        F: Fn(&str) -> u64,
+        G: Fn(&str) -> Amount,
+    {
         let mut transactions: Vec<Transaction> = vec![];
-        for _ in 0..amount {
-            match self.get_next_transaction() {
-                Some(tx) => {
-                    self.pending_map.insert(tx.hash(), tx.clone());
-                    transactions.push(tx);
-                }
-                None => break,
+        let mut selected_nonces: HashMap<String, u64> = HashMap::new();
+        let mut balances: HashMap<String, Amount> = HashMap::new();
+        let mut deferred: Vec<Transaction> = vec![];
+        let mut unaffordable: Vec<Transaction> = vec![];
+
+        let expected_nonce = |selected_nonces: &HashMap<String, u64>, sender: &str| {
+            selected_nonces
+                .get(sender)
+                .copied()
+                .unwrap_or_else(|| next_nonce(sender))
+        };
+
+        while transactions.len() < amount.max(0) as usize {
+            // A deferred transaction may have become minable after selecting its predecessor,
+            // and it was popped before the ones still in the heap, so it has priority
+            let ready = deferred
+                .iter()
+                .position(|tx| tx.nonce == expected_nonce(&selected_nonces, &tx.sender));
+            let tx = match ready {
+                Some(position) => deferred.swap_remove(position),
+                None => match self.get_next_transaction() {
+                    Some(tx) => tx,
+                    None => break,
+                },
+            };
+
+            if tx.is_expired(self.height) {
+                self.record_dropped(&tx.hash(), DropReason::Expired);
+                continue;
+            }
+
+            let expected = expected_nonce(&selected_nonces, &tx.sender);
+            if tx.nonce < expected {
+                // Already applied, it can never be mined
+                continue;
+            }
+            if tx.nonce > expected {
+                deferred.push(tx);
+                continue;
             }
+
+            let available = balances
+                .get(&tx.sender)
+                .copied()
+                .unwrap_or_else(|| balance(&tx.sender));
+            let cost = match tx.total_cost() {
+                Some(cost) if cost <= available => cost,
+                _ => {
+                    unaffordable.push(tx);
+                    continue;
+                }
+            };
+            balances.insert(tx.sender.clone(), available.saturating_sub(cost));
+            let received = balances
+                .get(&tx.recipient)
+                .copied()
+                .unwrap_or_else(|| balance(&tx.recipient))
+                .saturating_add(tx.amount);
+            balances.insert(tx.recipient.clone(), received);
+
+            selected_nonces.insert(tx.sender.clone(), expected + 1);
+            self.bytes += tx.size();
+            let tx_hash = tx.hash();
+            self.index_nonce(&tx, &tx_hash);
+            self.pending_map.insert(tx_hash, tx.clone());
+            transactions.push(tx);
+        }
+
+        for tx in deferred.into_iter().chain(unaffordable) {
+            self.insert_transaction(tx);
         }
 
         transactions
     }
 
+    /// Updates the pool after a chain reorganization.
+    /// Transactions confirmed by the new branch are removed, same as for a block mined by
+    /// another node, and the transactions of the orphaned blocks are queued again,
+    /// unless their nonce has already been used in the new branch, or they expired.
+    /// `height` is the index of the block following the new tip.
+    /// Returns how many orphaned transactions went back to the pool.
+    pub fn process_reorganization<F, G>(
+        &mut self,
+        orphaned_transactions: Vec<Transaction>,
+        confirmed_transactions: &[Transaction],
+        next_nonce: F,
+        balance: G,
+        height: u64,
+    ) -> usize
+    where
+        F: Fn(&str) -> u64,
+        G: Fn(&str) -> Amount,
+    {
+        self.process_mined_transactions(false, confirmed_transactions, height);
+
+        orphaned_transactions
+            .into_iter()
+            .filter(|tx| {
+                let (next_nonce, balance) = (next_nonce(&tx.sender), balance(&tx.sender));
+                self.add_transaction(tx.clone(), next_nonce, balance)
+                    .is_ok()
+            })
+            .count()
+    }
+
     /// Compute the results of a new mined block, against the pool.
     /// If the new block has been mined by this own node,
     /// we just remove all the transactions from the pending queue.
     /// In the case the new block comes from another miner,
     /// We need to make sure the transactions present in the incoming block,
     /// are removed from this pool, to avoid processing transactions twice.
+    /// `height` is the index of the block following the new one, the transactions
+    /// that can't be included in it anymore are dropped.
     pub fn process_mined_transactions(
         &mut self,
         mined_by_self: bool,
         confirmed_transactions: &[Transaction],
+        height: u64,
     ) {
         // If the block has been mined by this own node,
         // the pending queue transactions represent all the block's transactions;
         // therefore, we just need to clear the pending queue.
         if mined_by_self {
-            self.pending_map.clear();
+            let mined: Vec<String> = self.pending_map.keys().cloned().collect();
+            for tx_hash in mined {
+                self.remove_pending(&tx_hash);
+            }
+            self.set_height(height);
             return;
         }
 
@@ -127,10 +551,9 @@ impl TransactionPool {
         for tx in confirmed_transactions {
             let tx_hash = tx.hash();
             if self.pending_map.contains_key(&tx_hash) {
-                self.pending_map.remove(&tx_hash);
-            } else if self.tx_map.contains_key(&tx.hash()) {
-                self.tx_map.remove(&tx.hash());
-                self.removed_set.insert(tx.hash());
+                self.remove_pending(&tx_hash);
+            } else {
+                self.remove_waiting(&tx_hash);
             }
         }
 
@@ -139,11 +562,16 @@ impl TransactionPool {
         // Therefore, we need to return the pending queue back to the heap.
         if !self.pending_map.is_empty() {
             let tx_to_add: Vec<_> = self.pending_map.values().cloned().collect();
-            self.pending_map.clear();
+            for tx in &tx_to_add {
+                self.remove_pending(&tx.hash());
+            }
 
             for tx in tx_to_add {
-                self.add_transaction(tx);
+                self.insert_transaction(tx);
             }
         }
+
+        // Pending transactions returned to the pool may have expired with the new block
+        self.set_height(height);
     }
 }
@@ -0,0 +1,124 @@
+/// A 256-bit proof of work target, big-endian.
+/// A block is valid when its hash, read as a 256-bit number, is lower or equal than the target.
+pub type Target = [u8; 32];
+
+/// Decodes a compact target (Bitcoin's nBits).
+/// The highest byte is the size of the target in bytes, and the lower 3 bytes are its
+/// most significant digits, so the target is `mantissa * 256^(size - 3)`.
+/// Returns None for negative targets or targets that don't fit in 256 bits.
+pub fn compact_to_target(bits: u32) -> Option<Target> {
+    let size = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    // The sign bit is kept for compatibility with Bitcoin, targets can't be negative
+    if bits & 0x0080_0000 != 0 && mantissa != 0 {
+        return None;
+    }
+
+    let mut target = [0u8; 32];
+    let mantissa_bytes = mantissa.to_be_bytes();
+    for (i, byte) in mantissa_bytes[1..].iter().enumerate() {
+        // Position of the byte counted from the least significant one
+        let position = size as isize - 1 - i as isize;
+        if position < 0 || *byte == 0 {
+            continue;
+        }
+        if position >= 32 {
+            return None;
+        }
+        target[31 - position as usize] = *byte;
+    }
+    Some(target)
+}
+
+/// Encodes a target in its compact form, the lower digits that don't fit
+/// in the 3 bytes mantissa are dropped
+pub fn target_to_compact(target: &Target) -> u32 {
+    let mut size = 32 - target.iter().take_while(|byte| **byte == 0).count();
+    // Bytes past the end of the target are zeros, for targets smaller than the mantissa
+    let mut mantissa = (0..3).fold(0u32, |mantissa, i| {
+        let byte = target.get(32 - size + i).copied().unwrap_or(0);
+        (mantissa << 8) | byte as u32
+    });
+    // The highest bit of the mantissa is the sign, so it must stay unset
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    ((size as u32) << 24) | mantissa
+}
+
+/// Checks the hex encoded hash against the target
+pub fn hash_meets_target(hash: &str, target: &Target) -> bool {
+    match hex::decode(hash) {
+        Ok(bytes) if bytes.len() == 32 => bytes.as_slice() <= target.as_slice(),
+        _ => false,
+    }
+}
+
+/// Returns `target * numerator / denominator`, capped at `limit`.
+/// Used by the retargeting to scale the target by the observed block time
+pub fn scale_target(target: &Target, numerator: u64, denominator: u64, limit: &Target) -> Target {
+    // Multiplying into 40 bytes, so the product can't overflow
+    let mut product = [0u8; 40];
+    let mut carry: u128 = 0;
+    for i in (0..32).rev() {
+        let value = target[i] as u128 * numerator as u128 + carry;
+        product[i + 8] = value as u8;
+        carry = value >> 8;
+    }
+    for byte in product[..8].iter_mut().rev() {
+        *byte = carry as u8;
+        carry >>= 8;
+    }
+
+    // Long division, byte by byte
+    let denominator = denominator.max(1) as u128;
+    let mut quotient = [0u8; 40];
+    let mut remainder: u128 = 0;
+    for (i, byte) in product.iter().enumerate() {
+        let current = (remainder << 8) | *byte as u128;
+        quotient[i] = (current / denominator) as u8;
+        remainder = current % denominator;
+    }
+
+    if quotient[..8].iter().any(|byte| *byte != 0) {
+        return *limit;
+    }
+    let mut scaled = [0u8; 32];
+    scaled.copy_from_slice(&quotient[8..]);
+    if scaled > *limit {
+        return *limit;
+    }
+    scaled
+}
+
+/// Expected number of hashes needed to meet the target, `2^256 / target`.
+/// Chains are compared by the sum of the work of their blocks, saturating at `u128::MAX`,
+/// which is far beyond any target reachable by the retargeting.
+pub fn block_work(bits: u32) -> u128 {
+    let target = match compact_to_target(bits) {
+        Some(target) => target,
+        None => return 0,
+    };
+    // Normalizing the encoding, so the mantissa holds the most significant bytes
+    let bits = target_to_compact(&target);
+    let size = bits >> 24;
+    let mantissa = (bits & 0x007f_ffff) as u128;
+    if mantissa == 0 {
+        return 0;
+    }
+    if size <= 3 {
+        return u128::MAX;
+    }
+
+    // target = mantissa * 2^(8 * (size - 3)), so work = 2^(280 - 8 * size) / mantissa
+    let shift = 280 - 8 * size;
+    if shift <= 127 {
+        return (1u128 << shift) / mantissa;
+    }
+    let work = (1u128 << 127) / mantissa;
+    if work.leading_zeros() < shift - 127 {
+        return u128::MAX;
+    }
+    work << (shift - 127)
+}
@@ -1,26 +1,33 @@
 use crate::block::Block;
-use crate::blockchain::Blockchain;
+use crate::blockchain::{BlockOutcome, Blockchain};
 use crate::broadcaster::{BroadcastItem, Broadcaster};
-use crate::constants::{GET_BLOCKCHAIN, NEW_BLOCK, REGISTER, TRANSACTION};
+use crate::constants::{
+    ADDR, ADDR_GOSSIP_COUNT, GET_BLOCK, GET_BLOCKS, GET_DATA, GET_HEADERS, INV,
+    MAX_ADDR_PER_MESSAGE, MAX_BLOCKS_PER_REQUEST, MAX_HEADERS_PER_REQUEST,
+    MAX_INVENTORY_PER_REQUEST, MAX_ORPHAN_BLOCKS, REGISTER,
+};
 use crate::db::Database;
 use crate::discover::Peer;
 use crate::handler::{
     create_wallet, get_all_blocks, get_block_by_hash, get_transaction_by_hash,
-    get_transactions_by_wallet, get_wallet_balance, health_check, sign_and_submit_transaction,
-    sign_transaction, submit_transaction,
+    get_transaction_proof, get_transaction_status, get_transactions_by_wallet, get_wallet_balance,
+    get_wallet_nonce, health_check, sign_and_submit_transaction, sign_transaction,
+    submit_transaction,
 };
+use crate::inventory::{Inventory, InventoryData, InventoryKind};
+use crate::peer_manager::{IncomingRequest, PeerManager};
+use crate::peer_score::Misbehavior;
 use crate::pool::TransactionPool;
+use crate::sync::commit_reorganization;
 use crate::transaction::Transaction;
 use crate::{server_error, server_info, server_warn};
 use actix_web::{web, App, HttpServer};
 use serde::{Deserialize, Serialize};
-use serde_json::to_string;
-use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::Sender;
-use tokio::sync::{Mutex, RwLock};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{Mutex, Notify, RwLock};
 
 #[derive(Serialize, Deserialize)]
 pub struct Request {
@@ -30,30 +37,34 @@ pub struct Request {
 
 #[derive(Clone)]
 pub struct ServerHandler {
-    blockchain: Arc<RwLock<Blockchain>>,
+    pub blockchain: Arc<RwLock<Blockchain>>,
     block_tx: Arc<Mutex<Sender<Option<Block>>>>,
-    pub broadcaster: Arc<Mutex<Broadcaster>>,
+    pub broadcaster: Arc<Broadcaster>,
     pub transaction_pool: Arc<Mutex<TransactionPool>>,
-    pub peers: Arc<Mutex<HashSet<String>>>,
     pub database: Arc<Mutex<Database>>,
+    sync_notify: Arc<Notify>,
+    peer_manager: PeerManager,
 }
 
 impl ServerHandler {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         blockchain: Arc<RwLock<Blockchain>>,
         block_tx: Arc<Mutex<Sender<Option<Block>>>>,
-        broadcaster: Arc<Mutex<Broadcaster>>,
+        broadcaster: Arc<Broadcaster>,
         transaction_pool: Arc<Mutex<TransactionPool>>,
-        peers: Arc<Mutex<HashSet<String>>>,
         database: Arc<Mutex<Database>>,
+        sync_notify: Arc<Notify>,
+        peer_manager: PeerManager,
     ) -> Self {
         Self {
             blockchain,
             block_tx,
             broadcaster,
             transaction_pool,
-            peers,
             database,
+            sync_notify,
+            peer_manager,
         }
     }
 
@@ -70,162 +81,466 @@ impl ServerHandler {
                 .service(sign_and_submit_transaction)
                 .service(sign_transaction)
                 .service(get_transaction_by_hash)
+                .service(get_transaction_proof)
+                .service(get_transaction_status)
                 .service(get_transactions_by_wallet)
                 .service(get_block_by_hash)
                 .service(get_all_blocks)
                 .service(get_wallet_balance)
+                .service(get_wallet_nonce)
         })
         .bind(http_addr)?
         .run()
         .await
     }
 
-    /// Handles incoming TCP connections, for P2P communication
-    pub async fn start_tcp_server(self: Arc<Self>, tcp_addr: String) -> std::io::Result<()> {
+    /// Handles incoming TCP connections, for P2P communication.
+    /// The connections are kept open by the peer manager, which passes on the requests
+    /// received through them, whichever node opened the connection.
+    pub async fn start_tcp_server(
+        self: Arc<Self>,
+        tcp_addr: String,
+        mut incoming: Receiver<IncomingRequest>,
+    ) -> std::io::Result<()> {
         let listener = TcpListener::bind(tcp_addr.clone()).await?;
         server_info!("TCP Server listening on {}", tcp_addr);
 
+        let handler = self.clone();
+        tokio::spawn(async move {
+            // Each request is handled on its own task,
+            // so a long response doesn't hold back the other requests of the connection
+            while let Some(incoming_request) = incoming.recv().await {
+                let handler_clone = handler.clone();
+                tokio::spawn(async move {
+                    handler_clone.handle_request(incoming_request).await;
+                });
+            }
+        });
+
         while let Ok((stream, _)) = listener.accept().await {
-            let handler_clone = self.clone();
+            let peer_manager = self.peer_manager.clone();
             tokio::spawn(async move {
-                handler_clone.handle_connection(stream).await;
+                peer_manager.accept(stream).await;
             });
         }
         Ok(())
     }
 
-    pub async fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buffer = [0; 1024];
-        if let Ok(n) = stream.read(&mut buffer).await {
-            let request: Result<Request, _> = serde_json::from_slice(&buffer[..n]);
-            if let Ok(req) = request {
-                match req.command.as_str() {
-                    TRANSACTION => {
-                        if let Ok(tx) = serde_json::from_str::<Transaction>(&req.data) {
-                            // Inside the function,
-                            // there is already a validation,
-                            // for avoiding duplicate transactions
-                            if !self
-                                .transaction_pool
-                                .lock()
-                                .await
-                                .transaction_already_exists(&tx)
-                            {
-                                self.broadcaster
-                                    .lock()
-                                    .await
-                                    .broadcast_item(BroadcastItem::Transaction(tx.clone()))
-                                    .await;
-                            };
-                            self.transaction_pool.lock().await.add_transaction(tx);
-                        } else {
-                            server_warn!("Invalid transaction received")
-                        }
+    pub async fn handle_request(&self, incoming_request: IncomingRequest) {
+        let IncomingRequest {
+            peer_id,
+            request: req,
+            responder,
+        } = incoming_request;
+        match req.command.as_str() {
+            INV => {
+                let inventory = match serde_json::from_str::<Vec<Inventory>>(&req.data) {
+                    Ok(inventory) => inventory,
+                    Err(_) => {
+                        server_warn!("Invalid inventory received");
+                        self.peer_manager
+                            .misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                            .await;
+                        return;
+                    }
+                };
+                let inventory: Vec<Inventory> = inventory
+                    .into_iter()
+                    .take(MAX_INVENTORY_PER_REQUEST)
+                    .collect();
+                // The peer has these items, so they are never announced back to it
+                self.peer_manager.mark_known(&peer_id, &inventory).await;
+
+                let mut missing = vec![];
+                for item in inventory {
+                    if !self.has_inventory(&item).await {
+                        missing.push(item);
+                    }
+                }
+                if !missing.is_empty() {
+                    self.request_data(&peer_id, &missing).await;
+                }
+            }
+            GET_DATA => {
+                let inventory = match serde_json::from_str::<Vec<Inventory>>(&req.data) {
+                    Ok(inventory) => inventory,
+                    Err(_) => {
+                        server_warn!("Invalid inventory requested");
+                        self.peer_manager
+                            .misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                            .await;
+                        return;
+                    }
+                };
+                let mut items = vec![];
+                for item in inventory.iter().take(MAX_INVENTORY_PER_REQUEST) {
+                    let data = match item.kind {
+                        InventoryKind::Block => self
+                            .blockchain
+                            .read()
+                            .await
+                            .get_block(&item.hash)
+                            .cloned()
+                            .map(InventoryData::Block),
+                        InventoryKind::Transaction => self
+                            .transaction_pool
+                            .lock()
+                            .await
+                            .get_transaction(&item.hash)
+                            .cloned()
+                            .map(InventoryData::Transaction),
+                    };
+                    items.extend(data);
+                }
+
+                // Every item goes in its own frame, the ones we don't have are skipped
+                for data in items {
+                    self.peer_manager
+                        .mark_known(&peer_id, &[data.inventory()])
+                        .await;
+                    if let Err(e) = responder.respond(&data).await {
+                        server_error!("Failed to send inventory item: {}", e);
+                        return;
+                    }
+                }
+                if let Err(e) = responder.end().await {
+                    server_error!("Failed to send end of inventory: {}", e);
+                }
+            }
+            GET_HEADERS => {
+                // The data is the block locator of the peer,
+                // answered with the headers of our chain after the last block in common
+                let locator = match serde_json::from_str::<Vec<String>>(&req.data) {
+                    Ok(locator) => locator,
+                    Err(_) => {
+                        server_warn!("Invalid block locator received");
+                        self.peer_manager
+                            .misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                            .await;
+                        return;
+                    }
+                };
+                let headers = {
+                    self.blockchain
+                        .read()
+                        .await
+                        .headers_after(&locator, MAX_HEADERS_PER_REQUEST)
+                };
+                if let Err(e) = responder.respond(&headers).await {
+                    server_error!("Failed to send headers: {}", e);
+                }
+            }
+            GET_BLOCKS => {
+                let hashes = match serde_json::from_str::<Vec<String>>(&req.data) {
+                    Ok(hashes) => hashes,
+                    Err(_) => {
+                        server_warn!("Invalid block hashes received");
+                        self.peer_manager
+                            .misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                            .await;
+                        return;
+                    }
+                };
+                let blocks: Vec<Block> = {
+                    let blockchain = self.blockchain.read().await;
+                    hashes
+                        .iter()
+                        .take(MAX_BLOCKS_PER_REQUEST)
+                        .filter_map(|hash| blockchain.get_block(hash).cloned())
+                        .collect()
+                };
+
+                // Every block goes in its own frame, so the size of a message
+                // doesn't grow with the number of blocks asked for
+                for block in blocks {
+                    if let Err(e) = responder.respond(&block).await {
+                        server_error!("Failed to send block: {}", e);
+                        return;
                     }
-                    NEW_BLOCK => {
-                        if let Ok(block) = serde_json::from_str::<Block>(&req.data) {
-                            let latest_block =
-                                { self.blockchain.read().await.get_last_block().clone() };
-                            // Checking if the received block, has already been received by this node
-                            // avoiding extra checks, and broadcasting it again.
-                            if latest_block.index >= block.index || latest_block.hash == block.hash
-                            {
-                                return;
-                            }
-                            self.handle_new_block(block).await;
-                        } else {
-                            server_warn!("Invalid block received")
-                        }
+                }
+                if let Err(e) = responder.end().await {
+                    server_error!("Failed to send end of blocks: {}", e);
+                }
+            }
+            GET_BLOCK => {
+                // Side blocks are served as well, the peer may be following that branch
+                let block = { self.blockchain.read().await.get_block(&req.data).cloned() };
+                let sent = match block {
+                    Some(block) => responder.respond(&block).await,
+                    // Letting the peer know we don't have it
+                    None => responder.end().await,
+                };
+                if let Err(e) = sent {
+                    server_error!("Failed to send block: {}", e);
+                }
+            }
+            REGISTER => {
+                let peer = serde_json::from_str::<Peer>(&req.data)
+                    .ok()
+                    .filter(|peer| peer.address.parse::<SocketAddr>().is_ok());
+                if let Some(peer) = peer {
+                    server_info!("Peer {} registered from {}", peer_id, peer.address);
+                    // Nothing proves the peer listens on the address it claims,
+                    // so it's recorded like the addresses of an addr, until we connect to it
+                    let address_book = self.peer_manager.address_book();
+                    address_book.learned(&peer.address, &peer_id).await;
+                    // Only a sample of the addresses we know is shared, not all of them
+                    let peers = address_book.sample_recent(ADDR_GOSSIP_COUNT).await;
+                    if let Err(e) = responder.respond(&peers).await {
+                        server_error!("Failed to send peers: {}", e);
                     }
-                    GET_BLOCKCHAIN => {
-                        let chain = { self.blockchain.read().await.get_chain() };
-
-                        for block in chain {
-                            let block_json_string = match to_string(&block) {
-                                Ok(result) => result,
-                                Err(e) => {
-                                    server_error!("Failed to serialize block: {}", e);
-                                    break;
-                                }
-                            };
-                            let block_chunk = format!("{}{}\n", block_json_string, "<END_BLOCK>"); // Append delimiter
-
-                            if let Err(e) = stream.write_all(block_chunk.as_bytes()).await {
-                                server_error!("Failed to send block: {}", e);
-                                break;
-                            }
-
-                            if let Err(e) = stream.flush().await {
-                                server_error!("Failed to flush stream: {}", e);
-                                break;
-                            }
-                        }
-                        // Send a final message indicating completion
-                        let _ = stream.write_all(b"<END_CHAIN>\n").await;
-                        let _ = stream.flush().await;
+                } else {
+                    server_warn!("Invalid new peer received");
+                    self.peer_manager
+                        .misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                        .await;
+                }
+            }
+            ADDR => {
+                let addresses = match serde_json::from_str::<Vec<String>>(&req.data) {
+                    Ok(addresses) => addresses,
+                    Err(_) => {
+                        server_warn!("Invalid addresses received");
+                        self.peer_manager
+                            .misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                            .await;
+                        return;
                     }
-                    REGISTER => {
-                        if let Ok(peer) = serde_json::from_str::<Peer>(&req.data) {
-                            let peers = {
-                                let mut peers_lock = self.peers.lock().await;
-                                if !peers_lock.contains(&peer.address) {
-                                    server_info!(
-                                        "received a new peer in address: {}",
-                                        peer.address
-                                    );
-                                    peers_lock.insert(peer.address);
-                                }
-                                peers_lock.clone()
-                            };
-                            let response = match serde_json::to_string(&peers) {
-                                Ok(result) => result,
-                                Err(e) => {
-                                    server_error!("Failed to serialize peers: {}", e);
-                                    return;
-                                }
-                            };
-
-                            let _ = stream.write_all(response.as_bytes()).await;
-                        } else {
-                            server_warn!("Invalid new peer received")
-                        }
+                };
+                // The addresses go to the address book, outbound peers are picked from it.
+                // Nothing proves they are reachable, so the ones we know aren't refreshed
+                let address_book = self.peer_manager.address_book();
+                for address in addresses.into_iter().take(MAX_ADDR_PER_MESSAGE) {
+                    if address.parse::<SocketAddr>().is_ok() {
+                        address_book.learned(&address, &peer_id).await;
                     }
-                    _ => server_error!("Unknown command: {}", req.command),
                 }
-            } else {
-                server_error!("Failed to parse request.");
+            }
+            _ => {
+                server_error!("Unknown command: {}", req.command);
+                self.peer_manager
+                    .misbehaving(&peer_id, Misbehavior::MalformedMessage)
+                    .await;
             }
         }
     }
 
-    async fn handle_new_block(&self, block: Block) {
-        let is_valid_block = {
-            // Acquire the write lock and validate the block
-            let mut chain = self.blockchain.write().await;
-            if chain.is_valid_new_block(&block) {
-                server_info!("Appending valid block: {:?}", block);
-                chain.add_block(block.clone()); // Append the block
-                true // Block is valid
-            } else {
-                server_warn!("Invalid block received: {:?}", block);
-                false // Block is invalid
-            }
-            // The lock is released here since it goes out of scope
+    /// Checks whether we already have the announced item, in which case it isn't requested.
+    /// A block with a lower index can still belong to a heavier branch,
+    /// so it isn't discarded by its index.
+    async fn has_inventory(&self, item: &Inventory) -> bool {
+        match item.kind {
+            InventoryKind::Block => self.blockchain.read().await.knows_block(&item.hash),
+            InventoryKind::Transaction => {
+                if self
+                    .transaction_pool
+                    .lock()
+                    .await
+                    .get_transaction(&item.hash)
+                    .is_some()
+                {
+                    return true;
+                }
+                // Transactions already mined aren't requested either
+                matches!(
+                    self.database.lock().await.get_transaction(&item.hash),
+                    Ok(Some(_))
+                )
+            }
+        }
+    }
+
+    /// Asks the peer for the items it announced that we don't have, and processes them
+    async fn request_data(&self, peer_id: &str, inventory: &[Inventory]) {
+        let request = match serde_json::to_string(inventory) {
+            Ok(data) => Request {
+                command: GET_DATA.to_string(),
+                data,
+            },
+            Err(e) => {
+                server_error!("Failed to serialize inventory: {}", e);
+                return;
+            }
         };
+        let mut response = match self.peer_manager.request(peer_id, &request).await {
+            Ok(response) => response,
+            Err(e) => {
+                server_warn!("Failed to ask {} for data: {}", peer_id, e);
+                return;
+            }
+        };
+
+        loop {
+            let frame = match response.next().await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    server_warn!("Failed to receive data from {}: {}", peer_id, e);
+                    break;
+                }
+            };
+            // Making sure the peer sent what we asked for
+            let data = match frame.decode::<InventoryData>() {
+                Ok(data) if inventory.contains(&data.inventory()) => data,
+                _ => {
+                    server_warn!("Peer {} sent unexpected data", peer_id);
+                    self.peer_manager
+                        .misbehaving(peer_id, Misbehavior::UnrequestedData)
+                        .await;
+                    break;
+                }
+            };
+            match data {
+                InventoryData::Transaction(tx) => self.handle_transaction(tx, peer_id).await,
+                InventoryData::Block(block) => {
+                    // The same block may have arrived from another peer in the meantime
+                    if !self.blockchain.read().await.knows_block(&block.hash) {
+                        self.handle_new_block(block, peer_id).await;
+                    }
+                }
+            }
+        }
+    }
 
-        // Broadcast the block only after releasing the lock
-        if is_valid_block {
-            self.block_tx
-                .lock()
-                .await
-                .send(Some(block.clone()))
-                .await
-                .expect("TODO: panic message");
+    /// Adds a transaction received from a peer to the pool, and announces it to the others
+    async fn handle_transaction(&self, tx: Transaction, peer_id: &str) {
+        // Coinbase transactions only exist inside blocks,
+        // and they would pass the signature check
+        if tx.is_coinbase() || !tx.verify() {
+            server_warn!("Invalid transaction received from {}", peer_id);
+            self.peer_manager
+                .misbehaving(peer_id, Misbehavior::InvalidTransaction)
+                .await;
+            return;
+        }
+        // Inside the function,
+        // there is already a validation,
+        // for avoiding duplicate and replayed transactions, or paying a too low fee,
+        // only the ones added to the pool are announced.
+        // The nonce comes from the ledger of the chain, the blockchain lock is taken
+        // before the pool one, same as the miner
+        let added = {
+            let blockchain = self.blockchain.read().await;
+            self.transaction_pool.lock().await.add_transaction(
+                tx.clone(),
+                blockchain.get_next_nonce(&tx.sender),
+                blockchain.get_balance(&tx.sender),
+            )
+        };
+        if added.is_ok() {
             self.broadcaster
-                .lock()
-                .await
-                .broadcast_item(BroadcastItem::NewBlock(block.clone()))
+                .broadcast_item(BroadcastItem::Transaction(tx))
                 .await;
+        };
+    }
+
+    /// Processes a block received from the peer, and then the missing parents asked back to it,
+    /// until the block connects to our chain or to a side branch
+    async fn handle_new_block(&self, block: Block, peer_id: &str) {
+        let mut next_block = Some(block);
+        let mut requested_parents = 0;
+        while let Some(block) = next_block.take() {
+            let outcome = {
+                // The write lock is kept until the database and the pool are updated
+                let mut blockchain_write = self.blockchain.write().await;
+                let outcome = blockchain_write.process_block(block.clone());
+                if let BlockOutcome::NewTip(reorganization) = &outcome {
+                    commit_reorganization(
+                        &blockchain_write,
+                        reorganization,
+                        &self.database,
+                        &self.transaction_pool,
+                    )
+                    .await;
+                }
+                outcome
+            };
+
+            match outcome {
+                BlockOutcome::NewTip(reorganization) => {
+                    let tip = match reorganization.connected.last() {
+                        Some(tip) => tip.clone(),
+                        None => continue,
+                    };
+                    server_info!(
+                        "New tip {} at index {}, {} blocks connected",
+                        tip.hash,
+                        tip.index,
+                        reorganization.connected.len()
+                    );
+                    self.block_tx
+                        .lock()
+                        .await
+                        .send(Some(tip.clone()))
+                        .await
+                        .expect("TODO: panic message");
+                    // Peers missing the blocks below the tip will ask us for them
+                    self.broadcaster
+                        .broadcast_item(BroadcastItem::NewBlock(tip))
+                        .await;
+                }
+                BlockOutcome::SideBranch => {
+                    server_info!("Block {} stored on a side branch", block.hash);
+                }
+                BlockOutcome::Orphan(parent_hash) => {
+                    server_info!(
+                        "Block {} is an orphan, its parent {} is missing",
+                        block.hash,
+                        parent_hash
+                    );
+                    if requested_parents < MAX_ORPHAN_BLOCKS {
+                        requested_parents += 1;
+                        next_block = self.request_block(peer_id, &parent_hash).await;
+                    }
+                    // Falling back to the sync task, which asks the peers for the blocks we miss
+                    if next_block.is_none() {
+                        self.sync_notify.notify_one();
+                    }
+                }
+                BlockOutcome::Duplicate => {}
+                // Our clock may be behind, the peer isn't penalized,
+                // and the sync fetches the block once it's no longer too new
+                BlockOutcome::TooNew => {
+                    server_info!("Block {} is too far in the future, dropped", block.hash);
+                }
+                BlockOutcome::Invalid => {
+                    server_warn!("Invalid block received: {:?}", block);
+                    self.peer_manager
+                        .misbehaving(peer_id, Misbehavior::InvalidBlock)
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Asks a peer for the block with this hash
+    async fn request_block(&self, peer_id: &str, hash: &str) -> Option<Block> {
+        let request = Request {
+            command: GET_BLOCK.to_string(),
+            data: hash.to_string(),
+        };
+        let frame = match self.peer_manager.request_one(peer_id, &request).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                server_warn!("Peer {} doesn't have block {}", peer_id, hash);
+                return None;
+            }
+            Err(e) => {
+                server_warn!("Failed to get block {} from {}: {}", hash, peer_id, e);
+                return None;
+            }
+        };
+
+        match frame.decode::<Block>() {
+            // Making sure the peer sent the block we asked for
+            Ok(block) if block.hash == hash => Some(block),
+            _ => {
+                server_warn!("Peer {} didn't send block {}", peer_id, hash);
+                self.peer_manager
+                    .misbehaving(peer_id, Misbehavior::UnrequestedData)
+                    .await;
+                None
+            }
         }
     }
 }
@@ -1,41 +1,53 @@
-use crate::block::Block;
-use crate::blockchain::Blockchain;
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::{Blockchain, Reorganization};
+use crate::constants::{
+    BLOCK_BATCHES_PER_PEER, GET_BLOCKS, GET_HEADERS, MAX_BLOCKS_PER_REQUEST,
+    MAX_HEADERS_PER_REQUEST, MAX_SYNC_HEADERS, SYNC_INTERVAL_SECS,
+};
 use crate::db::Database;
+use crate::peer_manager::PeerManager;
+use crate::peer_score::Misbehavior;
+use crate::pool::TransactionPool;
+use crate::pow::block_work;
 use crate::server::Request;
-use crate::sync_info;
-use serde_json::from_str;
-use std::collections::HashSet;
+use crate::{sync_info, sync_warn};
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::select;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, Notify, RwLock};
+use tokio::task::JoinSet;
 
 pub struct Sync {
     blockchain: Arc<RwLock<Blockchain>>,
-    peers: Arc<Mutex<HashSet<String>>>,
     block_tx: Arc<Mutex<Sender<Option<Block>>>>,
     database: Arc<Mutex<Database>>,
+    transaction_pool: Arc<Mutex<TransactionPool>>,
+    sync_notify: Arc<Notify>,
+    peer_manager: PeerManager,
 }
 
 impl Sync {
     pub fn new(
         blockchain: Arc<RwLock<Blockchain>>,
-        peers: Arc<Mutex<HashSet<String>>>,
         watch_tx: Arc<Mutex<Sender<Option<Block>>>>,
         database: Arc<Mutex<Database>>,
+        transaction_pool: Arc<Mutex<TransactionPool>>,
+        sync_notify: Arc<Notify>,
+        peer_manager: PeerManager,
     ) -> Self {
         Self {
             blockchain,
-            peers,
             block_tx: watch_tx,
             database,
+            transaction_pool,
+            sync_notify,
+            peer_manager,
         }
     }
 
     pub async fn sync_with_peers(
         &mut self,
-        tcp_address: String,
         first_discover_done: Arc<Mutex<bool>>,
         first_sync_done: Arc<Mutex<bool>>,
     ) {
@@ -47,118 +59,419 @@ impl Sync {
                 }
             }
 
-            let peers = { self.peers.lock().await.clone() };
-            let mut longest_chain = None;
-            let mut max_length = self.blockchain.read().await.get_chain().len();
+            match self.find_heaviest_headers().await {
+                Some((headers, sources)) => {
+                    sync_info!(
+                        "Peers {:?} have a heavier chain, downloading {} blocks",
+                        sources,
+                        headers.len()
+                    );
+                    self.download_blocks(&sources, &headers).await;
+                }
+                None => sync_info!("Local chain is the heaviest."),
+            }
+            {
+                if !*first_sync_done.lock().await {
+                    *first_sync_done.lock().await = true;
+                }
+            }
+            // Sleep for some time before the next sync, unless a block from a competing branch
+            // is received, then the heaviest chain is looked up right away
+            select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(SYNC_INTERVAL_SECS)) => {}
+                _ = self.sync_notify.notified() => {
+                    sync_info!("Sync requested after receiving a block from another branch");
+                }
+            }
+        }
+    }
 
-            for peer_address in peers {
-                if peer_address == tcp_address {
+    /// Asks every connected peer, in parallel, for the headers after our block locator.
+    /// The headers are validated before anything else is downloaded, and the heaviest valid
+    /// ones are returned, with the peers that sent them, when they have more work than our chain
+    async fn find_heaviest_headers(&self) -> Option<(Vec<BlockHeader>, Vec<String>)> {
+        let locator = self.blockchain.read().await.block_locator();
+        let mut downloads = JoinSet::new();
+        for peer_id in self.peer_manager.connected_peers().await {
+            let peer_manager = self.peer_manager.clone();
+            let locator = locator.clone();
+            downloads.spawn(async move {
+                let headers = Self::download_headers(&peer_manager, &peer_id, locator).await;
+                (peer_id, headers)
+            });
+        }
+
+        let mut heaviest: Option<(Vec<BlockHeader>, Vec<String>)> = None;
+        let mut max_work = self.blockchain.read().await.total_work();
+        while let Some(downloaded) = downloads.join_next().await {
+            let (peer_id, headers) = match downloaded {
+                Ok(downloaded) if !downloaded.1.is_empty() => downloaded,
+                _ => continue,
+            };
+            // Peers announcing the same tip can all serve its blocks
+            if let Some((best, sources)) = heaviest.as_mut() {
+                if best.last() == headers.last() {
+                    sources.push(peer_id);
                     continue;
                 }
-                if let Ok(mut stream) = TcpStream::connect(&peer_address).await {
-                    let request = Request {
-                        command: "get_blockchain".to_string(),
-                        data: "".to_string(),
-                    };
-                    let marshalled_request = match serde_json::to_string(&request) {
-                        Ok(result) => result,
-                        Err(e) => {
-                            sync_info!("Failed to serialize request: {}", e);
-                            continue;
-                        }
-                    };
-
-                    if stream
-                        .write_all(marshalled_request.as_bytes())
-                        .await
-                        .is_err()
-                    {
-                        continue;
-                    }
+            }
 
-                    let peer_chain = Self::receive_blockchain(stream).await;
-                    if peer_chain.len() > max_length && Blockchain::is_valid_chain(&peer_chain) {
-                        max_length = peer_chain.len();
-                        longest_chain = Some(peer_chain);
-                    }
-                } else {
-                    {
-                        // In the case the node can't connect to that peer, it will remove from the list
-                        self.peers.lock().await.remove(&peer_address);
+            // The heaviest chain wins, not the longest one, a long chain of
+            // easy blocks could otherwise replace a shorter chain with more work
+            let (work, connects) = {
+                let blockchain = self.blockchain.read().await;
+                (
+                    blockchain.validate_headers(&headers),
+                    blockchain.contains_block(&headers[0].previous_hash),
+                )
+            };
+            match work {
+                Some(work) if work > max_work => {
+                    max_work = work;
+                    heaviest = Some((headers, vec![peer_id]));
+                }
+                Some(_) => {}
+                // Our chain may have changed since the locator was sent,
+                // only headers following one of our blocks can be proven invalid
+                None if !connects => {}
+                // They may be valid by the next sync, or our clock may be behind
+                None if headers
+                    .iter()
+                    .any(|header| Blockchain::is_too_new(header.timestamp)) =>
+                {
+                    sync_info!(
+                        "Headers from {} are too far in the future, ignored",
+                        peer_id
+                    );
+                }
+                None => {
+                    sync_warn!("Invalid headers received from {}", peer_id);
+                    self.peer_manager
+                        .misbehaving(&peer_id, Misbehavior::InvalidHeaders)
+                        .await;
+                }
+            }
+        }
+        heaviest
+    }
+
+    /// Downloads the headers of the peer's chain after the fork point with our locator.
+    /// A peer sends at most `MAX_HEADERS_PER_REQUEST` headers at a time, so the next batch
+    /// is asked for from the last header received, until a shorter batch arrives or
+    /// `MAX_SYNC_HEADERS` are buffered. A peer whose chain changed during the download answers
+    /// with headers that don't follow the previous batch, the download stops there and the
+    /// rest is looked up on the next sync. Headers that don't form a chain within a batch,
+    /// or don't meet their target, get the peer banned, and nothing it sent is kept
+    pub async fn download_headers(
+        peer_manager: &PeerManager,
+        peer_id: &str,
+        mut locator: Vec<String>,
+    ) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        loop {
+            let request = Request {
+                command: GET_HEADERS.to_string(),
+                data: serde_json::to_string(&locator).unwrap_or_default(),
+            };
+            let batch = match peer_manager.request_one(peer_id, &request).await {
+                Ok(Some(frame)) => match frame.decode::<Vec<BlockHeader>>() {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        sync_warn!("Failed to deserialize headers: {}", e);
+                        break;
                     }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    sync_warn!("Failed to ask {} for headers: {}", peer_id, e);
+                    break;
                 }
+            };
+
+            // Its locator fell back to an earlier block when the peer reorganized,
+            // an honest peer does that, the headers received so far are still a chain
+            if let (Some(last), Some(first)) = (headers.last(), batch.first()) {
+                if first.previous_hash != last.hash {
+                    sync_info!(
+                        "Chain of {} changed during the headers download, stopping at {} headers",
+                        peer_id,
+                        headers.len()
+                    );
+                    break;
+                }
+            }
+            if batch.len() > MAX_HEADERS_PER_REQUEST || !is_linked_batch(headers.last(), &batch) {
+                sync_warn!("Peer {} sent headers that don't form a chain", peer_id);
+                peer_manager
+                    .misbehaving(peer_id, Misbehavior::InvalidHeaders)
+                    .await;
+                return vec![];
+            }
+            let complete = batch.len() < MAX_HEADERS_PER_REQUEST;
+            headers.extend(batch);
+            if headers.len() >= MAX_SYNC_HEADERS {
+                // The heaviest chain is looked up again on the next sync,
+                // from the blocks connected out of these headers
+                sync_info!(
+                    "Stopping the headers download from {} at {} headers",
+                    peer_id,
+                    headers.len()
+                );
+                break;
+            }
+            match headers.last() {
+                Some(last) if !complete => locator = vec![last.hash.clone()],
+                _ => break,
             }
+        }
+        headers
+    }
 
-            if let Some(new_chain) = longest_chain {
-                sync_info!("Replacing chain with longer chain from peer.");
-                self.blockchain
-                    .write()
-                    .await
-                    .replace_chain(new_chain.clone());
-                // notify miners that a new chain has been found
-                self.block_tx
-                    .lock()
-                    .await
-                    .send(Some(self.blockchain.read().await.get_last_block().clone()))
-                    .await
-                    .expect("could not send message");
-                sync_info!("Saving the copy of the blockchain from peer, into the DB");
-                {
-                    if self
-                        .database
-                        .lock()
-                        .await
-                        .store_blocks_and_transactions(new_chain.clone())
-                        .is_err()
-                    {
-                        panic!("Unable to store the copy of the blockchain from peer, into the DB")
+    /// Downloads the blocks of the headers, split in batches of `MAX_BLOCKS_PER_REQUEST`
+    /// spread over the peers, which are asked in parallel. At most `BLOCK_BATCHES_PER_PEER`
+    /// batches per peer are downloaded or waiting for an earlier batch at a time, and the
+    /// batches are connected in order as they complete. A batch a peer fails to send is asked
+    /// from the next one, the download stops if no peer could send it.
+    pub async fn download_blocks(&self, sources: &[String], headers: &[BlockHeader]) {
+        let batches: Vec<&[BlockHeader]> = headers.chunks(MAX_BLOCKS_PER_REQUEST).collect();
+        let max_batches = BLOCK_BATCHES_PER_PEER * sources.len().max(1);
+        let mut downloads = JoinSet::new();
+        let mut downloaded: BTreeMap<usize, Vec<Block>> = BTreeMap::new();
+        let mut next_batch = 0;
+        let mut connected_batches = 0;
+        // Blocks of a branch forking below our tip, kept until it has more work than our chain
+        let mut branch = vec![];
+        while connected_batches < batches.len() {
+            while next_batch < batches.len() && next_batch < connected_batches + max_batches {
+                let peer_manager = self.peer_manager.clone();
+                let batch = batches[next_batch].to_vec();
+                let batch_index = next_batch;
+                // Each batch starts from a different peer
+                let mut peers = sources.to_vec();
+                peers.rotate_left(batch_index % sources.len().max(1));
+                downloads.spawn(async move {
+                    for peer_id in peers {
+                        if let Some(blocks) =
+                            Self::download_batch(&peer_manager, &peer_id, &batch).await
+                        {
+                            return Some((batch_index, blocks));
+                        }
                     }
+                    None
+                });
+                next_batch += 1;
+            }
+
+            match downloads.join_next().await {
+                Some(Ok(Some((batch_index, blocks)))) => {
+                    downloaded.insert(batch_index, blocks);
+                }
+                _ => {
+                    sync_warn!("Failed to download the blocks of the heavier chain");
+                    return;
                 }
-            } else {
-                sync_info!("Local chain is the longest.");
             }
-            {
-                if !*first_sync_done.lock().await {
-                    *first_sync_done.lock().await = true;
+            while let Some(blocks) = downloaded.remove(&connected_batches) {
+                connected_batches += 1;
+                if !self.connect_blocks(&mut branch, blocks).await {
+                    return;
                 }
             }
-            // Sleep for some time before the next sync
-            tokio::time::sleep(tokio::time::Duration::from_secs(120)).await;
+        }
+        if !branch.is_empty() {
+            sync_warn!("The downloaded chain is no longer the heaviest");
         }
     }
 
-    pub async fn receive_blockchain(mut stream: TcpStream) -> Vec<Block> {
-        let mut blocks = Vec::new();
-        let mut buffer = String::new();
-        let mut temp = [0u8; 1024]; // Read in chunks
-
-        while let Ok(n) = stream.read(&mut temp).await {
-            if n == 0 {
-                break; // Connection closed
+    /// Asks a peer for the blocks of the headers, they must match the headers we validated
+    async fn download_batch(
+        peer_manager: &PeerManager,
+        peer_id: &str,
+        headers: &[BlockHeader],
+    ) -> Option<Vec<Block>> {
+        let hashes: Vec<&String> = headers.iter().map(|header| &header.hash).collect();
+        let request = Request {
+            command: GET_BLOCKS.to_string(),
+            data: serde_json::to_string(&hashes).ok()?,
+        };
+        let mut response = match peer_manager.request(peer_id, &request).await {
+            Ok(response) => response,
+            Err(e) => {
+                sync_warn!("Failed to ask {} for blocks: {}", peer_id, e);
+                return None;
             }
+        };
 
-            // Append received data to the buffer
-            buffer.push_str(&String::from_utf8_lossy(&temp[..n]));
+        let mut blocks = Vec::with_capacity(headers.len());
+        loop {
+            let frame = match response.next().await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    sync_warn!("Failed to receive blocks from {}: {}", peer_id, e);
+                    return None;
+                }
+            };
+            match frame.decode::<Block>() {
+                Ok(block) if headers.get(blocks.len()) == Some(&block.header()) => {
+                    blocks.push(block)
+                }
+                _ => {
+                    sync_warn!("Peer {} sent an unexpected block", peer_id);
+                    peer_manager
+                        .misbehaving(peer_id, Misbehavior::UnrequestedData)
+                        .await;
+                    return None;
+                }
+            }
+        }
 
-            // Process complete blocks
-            while let Some(pos) = buffer.find("<END_BLOCK>\n") {
-                let extracted_block = buffer[..pos].trim().to_string(); // Extract the JSON part
-                                                                        // Using buffer drain, to change the same string, instead of allocating a new one
-                                                                        // which may impact in performance
-                buffer.drain(..pos + "<END_BLOCK>\n".len());
+        if blocks.len() != headers.len() {
+            sync_warn!("Peer {} is missing some of the blocks", peer_id);
+            return None;
+        }
+        Some(blocks)
+    }
 
-                if extracted_block == "<END_CHAIN>" {
-                    return blocks; // Stop when the end marker is received
+    /// Connects a batch of downloaded blocks after their parent in our chain. Blocks following
+    /// our tip are validated and appended one by one. Blocks of a branch forking below it are
+    /// added to `branch`, and once it has more work than our chain, its blocks are validated
+    /// and the chain is reorganized to it. Returns false when the download should stop.
+    async fn connect_blocks(&self, branch: &mut Vec<Block>, blocks: Vec<Block>) -> bool {
+        let mut valid = true;
+        let tip = {
+            // The blockchain lock is kept until the database and the pool are updated,
+            // so no block can be added in between
+            let mut blockchain_write = self.blockchain.write().await;
+            branch.extend(blocks);
+            let fork_index = match branch.first().and_then(|first| {
+                blockchain_write
+                    .chain
+                    .iter()
+                    .rposition(|block| block.hash == first.previous_hash)
+            }) {
+                Some(fork_index) => fork_index,
+                None => {
+                    sync_warn!("The downloaded blocks don't connect to our chain anymore");
+                    return false;
                 }
+            };
 
-                // Attempt deserialization
-                match from_str::<Block>(&extracted_block) {
-                    Ok(block) => blocks.push(block),
-                    Err(e) => eprintln!("Failed to deserialize block: {}", e),
+            let reorganization = if fork_index + 1 == blockchain_write.chain.len() {
+                let mut connected = vec![];
+                for block in branch.drain(..) {
+                    if !blockchain_write.is_valid_new_block(&block) {
+                        valid = false;
+                        break;
+                    }
+                    blockchain_write.add_block(block.clone());
+                    connected.push(block);
                 }
+                Reorganization {
+                    disconnected: vec![],
+                    connected,
+                }
+            } else {
+                let work = Blockchain::chain_work(&blockchain_write.chain[..=fork_index])
+                    .saturating_add(
+                        branch
+                            .iter()
+                            .fold(0, |work, block| work.saturating_add(block_work(block.bits))),
+                    );
+                if work <= blockchain_write.total_work() {
+                    return true;
+                }
+                let mut new_chain = blockchain_write.chain[..=fork_index].to_vec();
+                new_chain.append(branch);
+                if !blockchain_write.is_heavier_valid_chain(&new_chain) {
+                    sync_warn!("The downloaded chain is invalid");
+                    return false;
+                }
+                sync_info!("Switching to heavier chain from peers.");
+                blockchain_write.reorganize(new_chain)
+            };
+
+            if !valid {
+                sync_warn!("The downloaded chain is invalid");
             }
+            if reorganization.connected.is_empty() {
+                return false;
+            }
+            commit_reorganization(
+                &blockchain_write,
+                &reorganization,
+                &self.database,
+                &self.transaction_pool,
+            )
+            .await;
+            blockchain_write.get_last_block().clone()
+        };
+        // notify miners that a new chain has been found
+        self.block_tx
+            .lock()
+            .await
+            .send(Some(tip))
+            .await
+            .expect("could not send message");
+        valid
+    }
+}
+
+/// Checks what can be checked of a batch of headers without our chain: every header follows
+/// the previous one, starting from the last header of the previous batch, and its hash meets
+/// its own target. `Blockchain::validate_headers` checks the rest once the download is done
+pub fn is_linked_batch(previous: Option<&BlockHeader>, batch: &[BlockHeader]) -> bool {
+    let mut parent = previous;
+    for header in batch {
+        if let Some(parent) = parent {
+            if header.previous_hash != parent.hash || header.index != parent.index + 1 {
+                return false;
+            }
+        }
+        if header.hash != header.calculate_hash() || !header.is_valid() {
+            return false;
         }
+        parent = Some(header);
+    }
+    true
+}
+
+/// Brings the database and the transaction pool to the chain after a reorganization.
+/// Callers must keep holding the blockchain write lock, so no block can be added in between
+pub async fn commit_reorganization(
+    blockchain: &Blockchain,
+    reorganization: &Reorganization,
+    database: &Mutex<Database>,
+    transaction_pool: &Mutex<TransactionPool>,
+) {
+    if !reorganization.disconnected.is_empty() {
+        sync_info!(
+            "Reorganized chain, {} blocks disconnected and {} connected",
+            reorganization.disconnected.len(),
+            reorganization.connected.len()
+        );
+    }
+
+    if database
+        .lock()
+        .await
+        .apply_reorganization(reorganization)
+        .is_err()
+    {
+        panic!("Unable to store the new blocks of the chain, into the DB")
+    }
 
-        blocks
+    let returned = transaction_pool.lock().await.process_reorganization(
+        reorganization.orphaned_transactions(),
+        &reorganization.confirmed_transactions(),
+        |sender| blockchain.get_next_nonce(sender),
+        |sender| blockchain.get_balance(sender),
+        blockchain.next_height(),
+    );
+    if returned > 0 {
+        sync_info!(
+            "{} transactions from orphaned blocks returned to the pool",
+            returned
+        );
     }
 }
@@ -1,19 +1,56 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
+    use crate::address_book::{network_group, rank_peers, select_diverse, AddressBook, PeerRecord};
+    use crate::amount::Amount;
+    use crate::block::{Block, BlockHeader};
+    use crate::block_tree::BlockTree;
     use crate::blockchain;
-    use crate::blockchain::create_genesis_block;
+    use crate::blockchain::{create_genesis_block, BlockOutcome};
+    use crate::broadcaster::Broadcaster;
+    use crate::codec::{encode_frame, read_frame, write_frame, write_message, Frame, MessageType};
     use crate::config::load_config;
+    use crate::constants::{
+        ADDRESS_BOOK_EXPIRY_SECS, ADDR_RECENT_SECS, BAN_SCORE, BLOCK_BATCHES_PER_PEER,
+        DEFAULT_MAX_INBOUND_PEERS, DEFAULT_MAX_OUTBOUND_PEERS, DEFAULT_MAX_POOL_BYTES,
+        DEFAULT_MAX_POOL_TRANSACTIONS, DEFAULT_MIN_RELAY_FEE, DEFAULT_NETWORK_ID,
+        DEFAULT_POOL_TRANSACTION_TTL_SECS, DIFFICULTY_ADJUSTMENT_WINDOW, GET_BLOCK, GET_BLOCKS,
+        GET_DATA, GET_HEADERS, HALVING_INTERVAL, INITIAL_BITS, INV, MAX_ADDRESSES_PER_SOURCE,
+        MAX_ADDRESS_BOOK_SIZE, MAX_BLOCKS_PER_REQUEST, MAX_FUTURE_BLOCK_TIME,
+        MAX_HEADERS_PER_REQUEST, MAX_MESSAGE_SIZE, MAX_SIDE_BLOCKS, MAX_SUPPLY,
+        MIN_PROTOCOL_VERSION, PEER_QUEUE_SIZE, POW_LIMIT_BITS, PROTOCOL_VERSION, RATE_LIMIT_BURST,
+        RATE_LIMIT_PER_SEC, REWARD, TARGET_BLOCK_TIME, VERSION,
+    };
     use crate::db::Database;
-    use crate::pool::TransactionPool;
+    use crate::discover::{announced_address, LocalAnnouncement, Peer};
+    use crate::error::{
+        CodecError, DatabaseError, HandshakeError, IdentityError, PeerError, PoolError,
+    };
+    use crate::handler::{get_wallet_nonce, submit_transaction};
+    use crate::handshake::Handshake;
+    use crate::identity::Identity;
+    use crate::inventory::{Inventory, InventoryData, KnownInventory};
+    use crate::peer_manager::{IncomingRequest, PeerManager};
+    use crate::peer_score::{Misbehavior, PeerScores};
+    use crate::pool::{DropReason, TransactionPool};
+    use crate::pow::{
+        block_work, compact_to_target, hash_meets_target, scale_target, target_to_compact,
+    };
+    use crate::server::{Request, ServerHandler};
+    use crate::sync::is_linked_batch;
     use crate::transaction::Transaction;
+    use crate::transport;
     use crate::wallet::Wallet;
-    use ordered_float::OrderedFloat;
+    use std::collections::HashSet;
     use std::fs::write;
     use std::string::String;
+    use std::sync::Arc;
+    use tokio::sync::{Mutex, RwLock};
 
     #[test]
     fn create_dummy_blockchain() {
         let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
 
         // Add blocks to the blockchain with data
         let second_block = blockchain.mine_new_block(vec![]);
@@ -57,82 +94,1782 @@ mod tests {
     fn verify_transaction_signature() {
         let sender_wallet = Wallet::new();
         let recipient_wallet = Wallet::new();
-        let amount = 1.0;
-        let fee = 0.1;
+        let amount = Amount::from_coins(1);
+        let fee = Amount::from_base_units(10_000_000);
 
         let mut transaction = Transaction::new(
             sender_wallet.address(),
             recipient_wallet.address(),
             amount,
             fee,
+            0,
             chrono::Utc::now().timestamp(),
         );
         transaction.sign(&sender_wallet);
-        assert_eq!(transaction.verify(), true);
+        assert!(transaction.verify());
     }
 
     #[test]
     fn verify_transaction_signature_invalid() {
         let sender_wallet = Wallet::new();
         let recipient_wallet = Wallet::new();
-        let amount = 1.0;
-        let fee = 0.1;
+        let amount = Amount::from_coins(1);
+        let fee = Amount::from_base_units(10_000_000);
 
         let mut transaction = Transaction::new(
             sender_wallet.address(),
             recipient_wallet.address(),
             amount,
             fee,
+            0,
             chrono::Utc::now().timestamp(),
         );
         transaction.sign(&sender_wallet);
 
         // Modifying the value of the amount, therefore the signature will be invalid
         // since the signing hash, will be different
-        transaction.amount = OrderedFloat::from(10.0);
+        transaction.amount = Amount::from_coins(10);
 
-        assert_eq!(transaction.verify(), false);
+        assert!(!transaction.verify());
     }
 
     #[test]
     fn transaction_pool_get_next_transaction_priority_order_correct() {
         let mut pool = TransactionPool::new();
 
-        let tx1 = Transaction::new("Alice".into(), "Bob".into(), 10.0, 1.0, 100);
-        let tx2 = Transaction::new("Charlie".into(), "Dave".into(), 5.0, 2.0, 101);
+        let tx1 = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(10),
+            Amount::from_coins(1),
+            0,
+            100,
+        );
+        let tx2 = Transaction::new(
+            "Charlie".into(),
+            "Dave".into(),
+            Amount::from_coins(5),
+            Amount::from_coins(2),
+            0,
+            101,
+        );
+
+        pool.add_transaction(tx1, 0, Amount::from_coins(100))
+            .unwrap();
+        pool.add_transaction(tx2, 0, Amount::from_coins(100))
+            .unwrap();
+        // Make sure the transaction with the higher fee will be popped first
+        assert_eq!(
+            pool.get_next_transaction().unwrap().fee,
+            Amount::from_coins(2)
+        )
+    }
+
+    #[test]
+    fn transaction_pool_get_next_transaction_same_fee_order_correct() {
+        // When comparing transactions with the same fee, the oldest one will be prioritized
+        let mut pool = TransactionPool::new();
+
+        let tx1 = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(10),
+            Amount::from_coins(1),
+            0,
+            100,
+        );
+        let tx2 = Transaction::new(
+            "Charlie".into(),
+            "Dave".into(),
+            Amount::from_coins(5),
+            Amount::from_coins(1),
+            0,
+            101,
+        );
+        pool.add_transaction(tx1, 0, Amount::from_coins(100))
+            .unwrap();
+        pool.add_transaction(tx2, 0, Amount::from_coins(100))
+            .unwrap();
+
+        let tx = pool.get_next_transaction().unwrap();
+        assert_eq!(tx.amount, Amount::from_coins(10));
+        assert_eq!(tx.timestamp, 100);
+    }
+
+    #[test]
+    fn verify_transaction_signature_covers_nonce() {
+        let sender_wallet = Wallet::new();
+        let mut transaction = Transaction::new(
+            sender_wallet.address(),
+            "Bob".into(),
+            Amount::from_coins(1),
+            Amount::from_base_units(10_000_000),
+            0,
+            1,
+        );
+        transaction.sign(&sender_wallet);
+
+        // Changing the nonce invalidates the signature, so it can't be used for a replay
+        transaction.nonce = 1;
+        assert!(!transaction.verify());
+    }
+
+    #[test]
+    fn transaction_pool_rejects_used_nonces() {
+        let mut pool = TransactionPool::new();
+
+        let stale = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(10),
+            Amount::from_coins(1),
+            2,
+            100,
+        );
+        let first = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(10),
+            Amount::from_coins(1),
+            3,
+            101,
+        );
+        let same_nonce = Transaction::new(
+            "Alice".into(),
+            "Dave".into(),
+            Amount::from_coins(5),
+            Amount::from_coins(2),
+            3,
+            102,
+        );
+
+        // The account already used nonces 0, 1 and 2
+        assert_eq!(
+            pool.add_transaction(stale, 3, Amount::from_coins(100)),
+            Err(PoolError::NonceTooLow(2))
+        );
+        assert_eq!(
+            pool.add_transaction(first.clone(), 3, Amount::from_coins(100)),
+            Ok(())
+        );
+        // The next nonce follows the pending transactions, or the account when it's ahead
+        assert_eq!(pool.next_nonce("Alice", 3), 4);
+        assert_eq!(pool.next_nonce("Alice", 5), 5);
+        assert_eq!(pool.next_nonce("Bob", 0), 0);
+        assert_eq!(
+            pool.add_transaction(first, 3, Amount::from_coins(100)),
+            Err(PoolError::Duplicate)
+        );
+        assert_eq!(
+            pool.add_transaction(same_nonce.clone(), 3, Amount::from_coins(100)),
+            Err(PoolError::NonceInUse(3))
+        );
+
+        // The nonce stays in use while the transaction is mined, and is freed once it's confirmed
+        let mined = pool.get_transactions_to_mine(1, |_| 3, |_| Amount::from_coins(100));
+        assert_eq!(mined.len(), 1);
+        assert_eq!(
+            pool.add_transaction(same_nonce.clone(), 3, Amount::from_coins(100)),
+            Err(PoolError::NonceInUse(3))
+        );
+        pool.process_mined_transactions(true, &mined, 2);
+        assert_eq!(
+            pool.add_transaction(same_nonce, 3, Amount::from_coins(100)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn transaction_pool_rejects_unaffordable_transactions() {
+        let transaction = |sender: &str, fee: u64, nonce: u64| {
+            Transaction::new(
+                sender.into(),
+                "Bob".into(),
+                Amount::from_coins(1),
+                Amount::from_base_units(fee),
+                nonce,
+                100,
+            )
+        };
+        let mut pool = TransactionPool::new();
+        pool.set_limits(2, DEFAULT_MAX_POOL_BYTES, Amount::from_base_units(1000));
+        let funded = transaction("Alice", 1000, 0);
+        pool.add_transaction(funded.clone(), 0, Amount::from_coins(2))
+            .unwrap();
+        assert_eq!(
+            pool.pending_spend("Alice"),
+            Amount::from_base_units(100_001_000)
+        );
+
+        // The balance must cover the other transactions of the sender in the pool as well
+        assert_eq!(
+            pool.add_transaction(transaction("Alice", 1000, 1), 0, Amount::from_coins(2)),
+            Err(PoolError::InsufficientFunds)
+        );
+
+        // A full pool doesn't evict a funded transaction for an unfunded one paying more
+        pool.add_transaction(transaction("Carol", 2000, 0), 0, Amount::from_coins(2))
+            .unwrap();
+        assert_eq!(
+            pool.add_transaction(transaction("Mallory", 1_000_000, 0), 0, Amount::ZERO),
+            Err(PoolError::InsufficientFunds)
+        );
+        assert!(pool.tx_map.contains_key(&funded.hash()));
+        assert_eq!(pool.dropped_reason(&funded.hash()), None);
+
+        // The spend is released once the transaction is mined
+        let mined = pool.get_transactions_to_mine(2, |_| 0, |_| Amount::from_coins(2));
+        assert_eq!(mined.len(), 2);
+        assert_eq!(
+            pool.pending_spend("Alice"),
+            Amount::from_base_units(100_001_000)
+        );
+        pool.process_mined_transactions(true, &mined, 2);
+        assert_eq!(pool.pending_spend("Alice"), Amount::ZERO);
+    }
+
+    #[test]
+    fn transaction_pool_evicts_lowest_fees_when_full() {
+        let transaction = |sender: &str, fee: u64, timestamp: i64| {
+            Transaction::new(
+                sender.into(),
+                "Bob".into(),
+                Amount::from_coins(1),
+                Amount::from_base_units(fee),
+                0,
+                timestamp,
+            )
+        };
+        let mut pool = TransactionPool::new();
+        pool.set_limits(2, DEFAULT_MAX_POOL_BYTES, Amount::from_base_units(1000));
+
+        assert_eq!(
+            pool.add_transaction(transaction("Alice", 999, 100), 0, Amount::from_coins(100)),
+            Err(PoolError::FeeTooLow {
+                fee: Amount::from_base_units(999),
+                min_relay_fee: Amount::from_base_units(1000),
+            })
+        );
+        let low = transaction("Alice", 1000, 100);
+        let high = transaction("Carol", 3000, 101);
+        pool.add_transaction(low.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        pool.add_transaction(high.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        assert_eq!(pool.bytes, low.size() + high.size());
+
+        // A transaction paying no more than the lowest one can't get in
+        assert_eq!(
+            pool.add_transaction(transaction("Dave", 1000, 102), 0, Amount::from_coins(100)),
+            Err(PoolError::PoolFull)
+        );
+        // A higher fee replaces the lowest one
+        let medium = transaction("Erin", 2000, 103);
+        pool.add_transaction(medium.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        assert_eq!(pool.transaction_count(), 2);
+        assert!(!pool.tx_map.contains_key(&low.hash()));
+        assert_eq!(pool.dropped_reason(&low.hash()), Some(DropReason::Evicted));
+        assert_eq!(pool.bytes, high.size() + medium.size());
+
+        // The evicted transaction is dropped from the heap once the pool is compacted
+        assert_eq!(pool.heap.len(), 3);
+        assert_eq!(pool.removed_set.len(), 1);
+        pool.compact();
+        assert_eq!(pool.heap.len(), 2);
+        assert!(pool.removed_set.is_empty());
+        assert_eq!(pool.get_next_transaction().unwrap().hash(), high.hash());
+        assert_eq!(pool.get_next_transaction().unwrap().hash(), medium.hash());
+        assert!(pool.get_next_transaction().is_none());
+        assert_eq!(pool.bytes, 0);
+
+        // The size limit applies as well
+        pool.set_limits(10, high.size(), Amount::from_base_units(1000));
+        pool.add_transaction(medium.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        pool.add_transaction(high.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        assert_eq!(pool.transaction_count(), 1);
+        assert!(pool.tx_map.contains_key(&high.hash()));
+    }
+
+    #[test]
+    fn transaction_pool_drops_expired_and_stale_transactions() {
+        let transaction = |sender: &str, valid_until: u64, timestamp: i64| {
+            Transaction::new(
+                sender.into(),
+                "Bob".into(),
+                Amount::from_coins(1),
+                Amount::from_coins(1),
+                0,
+                timestamp,
+            )
+            .with_valid_until(valid_until)
+        };
+        let mut pool = TransactionPool::new();
+        pool.set_height(5);
+
+        assert_eq!(
+            pool.add_transaction(transaction("Alice", 4, 100), 0, Amount::from_coins(100)),
+            Err(PoolError::Expired {
+                valid_until: 4,
+                height: 5
+            })
+        );
+        let waiting = transaction("Alice", 5, 101);
+        let mined = transaction("Carol", 5, 100);
+        pool.add_transaction(waiting.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        pool.add_transaction(mined.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+
+        // Carol's transaction is being mined when a block from another miner arrives,
+        // it returns to the pool, and both are dropped since they can't be in the next block
+        let transactions = pool.get_transactions_to_mine(1, |_| 0, |_| Amount::from_coins(100));
+        assert_eq!(transactions[0].hash(), mined.hash());
+        pool.process_mined_transactions(false, &[], 6);
+        assert_eq!(pool.transaction_count(), 0);
+        assert_eq!(pool.bytes, 0);
+        assert_eq!(
+            pool.dropped_reason(&waiting.hash()),
+            Some(DropReason::Expired)
+        );
+        assert_eq!(
+            pool.dropped_reason(&mined.hash()),
+            Some(DropReason::Expired)
+        );
+
+        // Transactions waiting for longer than the TTL are dropped
+        let stale = transaction("Dave", 100, 100);
+        pool.add_transaction(stale.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(pool.drop_stale(now), 0);
+        assert_eq!(
+            pool.drop_stale(now + DEFAULT_POOL_TRANSACTION_TTL_SECS as i64 + 1),
+            1
+        );
+        assert!(pool.get_transaction(&stale.hash()).is_none());
+        assert_eq!(pool.dropped_reason(&stale.hash()), Some(DropReason::Stale));
+    }
+
+    #[test]
+    fn transaction_pool_get_transactions_to_mine_in_nonce_order() {
+        let mut pool = TransactionPool::new();
+
+        // The second transaction pays a higher fee, but it can only be mined after the first one
+        let first = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(10),
+            Amount::from_coins(1),
+            0,
+            100,
+        );
+        let second = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(10),
+            Amount::from_coins(5),
+            1,
+            101,
+        );
+        pool.add_transaction(first, 0, Amount::from_coins(100))
+            .unwrap();
+        pool.add_transaction(second, 0, Amount::from_coins(100))
+            .unwrap();
+
+        let transactions = pool.get_transactions_to_mine(2, |_| 0, |_| Amount::from_coins(100));
+        let nonces: Vec<u64> = transactions.iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, vec![0, 1]);
+    }
+
+    #[test]
+    fn transaction_pool_skips_unaffordable_transactions() {
+        let mut pool = TransactionPool::new();
+        let first = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(3),
+            Amount::from_coins(1),
+            0,
+            100,
+        );
+        let second = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(3),
+            Amount::from_coins(1),
+            1,
+            101,
+        );
+        pool.add_transaction(first.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        pool.add_transaction(second.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+
+        // Alice can only afford the first one, the second one stays in the pool
+        let transactions = pool.get_transactions_to_mine(
+            2,
+            |_| 0,
+            |sender| match sender {
+                "Alice" => Amount::from_coins(5),
+                _ => Amount::ZERO,
+            },
+        );
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].hash(), first.hash());
+        assert!(pool.tx_map.contains_key(&second.hash()));
+    }
+
+    #[test]
+    fn block_rejects_overdrafts_and_double_spends() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+
+        // Alice gets the reward of the first block
+        let alice = Wallet::new();
+        let funded = mine_block(
+            &blockchain,
+            &[create_genesis_block()],
+            vec![coinbase(&alice.address(), 1)],
+            1,
+        );
+        assert!(blockchain.is_valid_new_block(&funded));
+        blockchain.add_block(funded);
+        assert_eq!(
+            blockchain.get_balance(&alice.address()),
+            Amount::from_coins(REWARD)
+        );
+
+        let payment = |amount: u64, nonce: u64| {
+            let mut transaction = Transaction::new(
+                alice.address(),
+                "Bob".into(),
+                Amount::from_coins(amount),
+                Amount::from_coins(1),
+                nonce,
+                nonce as i64,
+            );
+            transaction.sign(&alice);
+            transaction
+        };
+        let chain = blockchain.chain.clone();
+
+        // Spending more than the balance, counting the fee
+        let overdraft = mine_block(
+            &blockchain,
+            &chain,
+            with_coinbase(&blockchain, vec![payment(REWARD, 0)]),
+            1,
+        );
+        assert!(!blockchain.is_valid_new_block(&overdraft));
+
+        // Spending the same coins twice in the same block
+        let double_spend = mine_block(
+            &blockchain,
+            &chain,
+            with_coinbase(&blockchain, vec![payment(2, 0), payment(2, 1)]),
+            1,
+        );
+        assert!(!blockchain.is_valid_new_block(&double_spend));
+        let mut invalid_chain = chain.clone();
+        invalid_chain.push(double_spend);
+        assert!(!blockchain.is_valid_chain(&invalid_chain));
+
+        let block = mine_block(
+            &blockchain,
+            &chain,
+            with_coinbase(&blockchain, vec![payment(2, 0)]),
+            1,
+        );
+        assert!(blockchain.is_valid_new_block(&block));
+        blockchain.add_block(block);
+        assert_eq!(
+            blockchain.get_balance(&alice.address()),
+            Amount::from_coins(REWARD - 3)
+        );
+        assert_eq!(blockchain.get_balance("Bob"), Amount::from_coins(2));
+        // The fee is paid to the miner through the coinbase
+        assert_eq!(
+            blockchain.get_balance("Miner"),
+            Amount::from_coins(REWARD + 1)
+        );
+    }
+
+    #[test]
+    fn block_rejects_expired_transactions() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+
+        let alice = Wallet::new();
+        let funded = mine_block(
+            &blockchain,
+            &[create_genesis_block()],
+            vec![coinbase(&alice.address(), 1)],
+            1,
+        );
+        blockchain.add_block(funded);
+        assert_eq!(blockchain.next_height(), 2);
+
+        let payment = |valid_until: u64| {
+            let mut transaction = Transaction::new(
+                alice.address(),
+                "Bob".into(),
+                Amount::from_coins(1),
+                Amount::from_coins(1),
+                0,
+                1,
+            )
+            .with_valid_until(valid_until);
+            transaction.sign(&alice);
+            transaction
+        };
+        let chain = blockchain.chain.clone();
+
+        // The valid-until height is signed, so it can't be extended
+        let mut extended = payment(1);
+        extended.valid_until = Some(2);
+        assert!(!extended.verify());
+
+        let expired = mine_block(
+            &blockchain,
+            &chain,
+            with_coinbase(&blockchain, vec![payment(1)]),
+            1,
+        );
+        assert!(!blockchain.is_valid_new_block(&expired));
+
+        let block = mine_block(
+            &blockchain,
+            &chain,
+            with_coinbase(&blockchain, vec![payment(2)]),
+            1,
+        );
+        assert!(blockchain.is_valid_new_block(&block));
+    }
+
+    #[test]
+    fn blockchain_rejects_replayed_transaction() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+
+        let sender_wallet = Wallet::new();
+        fund(&mut blockchain, &sender_wallet.address());
+        let mut transaction = Transaction::new(
+            sender_wallet.address(),
+            "Bob".into(),
+            Amount::from_coins(1),
+            Amount::from_base_units(10_000_000),
+            0,
+            1,
+        );
+        transaction.sign(&sender_wallet);
+
+        let block =
+            blockchain.mine_new_block(with_coinbase(&blockchain, vec![transaction.clone()]));
+        assert!(blockchain.is_valid_new_block(&block));
+        blockchain.add_block(block);
+        assert_eq!(blockchain.get_next_nonce(&sender_wallet.address()), 1);
+
+        // Submitting the same signed transaction again must be rejected
+        let replay = blockchain.mine_new_block(with_coinbase(&blockchain, vec![transaction]));
+        assert!(!blockchain.is_valid_new_block(&replay));
+    }
+
+    #[test]
+    fn heavier_chain_with_forged_transfer_is_rejected() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let alice = Wallet::new();
+        fund(&mut blockchain, &alice.address());
+        let funded = blockchain.chain.clone();
+
+        // Mallory signs a transfer draining Alice's account with its own key
+        let mallory = Wallet::new();
+        let mut forged = Transaction::new(
+            alice.address(),
+            mallory.address(),
+            Amount::from_coins(REWARD - 1),
+            Amount::from_coins(1),
+            0,
+            1,
+        );
+        forged.sign(&mallory);
+        assert!(!forged.verify());
+
+        let mut theirs = funded.clone();
+        let block = mine_block(
+            &blockchain,
+            &theirs,
+            with_coinbase(&blockchain, vec![forged]),
+            1,
+        );
+        theirs.push(block);
+        let theirs = mine_branch(&blockchain, &theirs, 2, 1);
+        assert!(blockchain::Blockchain::chain_work(&theirs) > blockchain.total_work());
+        assert!(!blockchain.is_heavier_valid_chain(&theirs));
+    }
+
+    #[test]
+    fn coinbase_must_pay_reward_plus_fees() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let alice = Wallet::new();
+        fund(&mut blockchain, &alice.address());
+        let parent = blockchain.chain.clone();
+
+        let mut transaction = Transaction::new(
+            alice.address(),
+            "Bob".into(),
+            Amount::from_coins(1),
+            Amount::from_coins(1),
+            0,
+            1,
+        );
+        transaction.sign(&alice);
+
+        let block = mine_block(
+            &blockchain,
+            &parent,
+            with_coinbase(&blockchain, vec![transaction.clone()]),
+            1,
+        );
+        assert_eq!(block.transactions[1].amount, Amount::from_coins(REWARD + 1));
+        assert!(blockchain.is_valid_new_block(&block));
+
+        // Paying the miner more than the reward plus the fees
+        let mut inflated = with_coinbase(&blockchain, vec![transaction.clone()]);
+        inflated[1].amount = inflated[1]
+            .amount
+            .saturating_add(Amount::from_base_units(1));
+        let inflated = mine_block(&blockchain, &parent, inflated, 1);
+        assert!(!blockchain.is_valid_new_block(&inflated));
+        let mut inflated_chain = parent.clone();
+        inflated_chain.push(inflated);
+        assert!(!blockchain.is_valid_chain(&inflated_chain));
+
+        // Blocks without a coinbase, or with two of them
+        let missing = mine_block(&blockchain, &parent, vec![transaction.clone()], 1);
+        assert!(!blockchain.is_valid_new_block(&missing));
+        let duplicated = mine_block(
+            &blockchain,
+            &parent,
+            vec![coinbase("Miner", 2), coinbase("Other", 2)],
+            1,
+        );
+        assert!(!blockchain.is_valid_new_block(&duplicated));
+
+        blockchain.add_block(block);
+        assert_eq!(blockchain.total_supply, Amount::from_coins(2 * REWARD));
+
+        // The last reward is cut short, so the supply never exceeds the maximum
+        blockchain.total_supply = Amount::from_coins(MAX_SUPPLY - 2);
+        let chain = blockchain.chain.clone();
+        let full_reward = mine_block(&blockchain, &chain, vec![coinbase("Miner", 3)], 1);
+        assert!(!blockchain.is_valid_new_block(&full_reward));
+        let block = mine_block(&blockchain, &chain, with_coinbase(&blockchain, vec![]), 1);
+        assert_eq!(block.transactions[0].amount, Amount::from_coins(2));
+        assert!(blockchain.is_valid_new_block(&block));
+        blockchain.add_block(block);
+        assert_eq!(blockchain.total_supply, Amount::from_coins(MAX_SUPPLY));
+        assert_eq!(
+            blockchain.block_subsidy(4, blockchain.total_supply),
+            Amount::ZERO
+        );
+    }
+
+    #[test]
+    fn block_reward_halving() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        blockchain.set_halving_interval(2);
+
+        let reward = Amount::from_coins(REWARD);
+        assert_eq!(blockchain.block_subsidy(1, Amount::ZERO), reward);
+        assert_eq!(
+            blockchain.block_subsidy(2, Amount::ZERO),
+            Amount::from_base_units(reward.base_units() / 2)
+        );
+        assert_eq!(
+            blockchain.block_subsidy(5, Amount::ZERO),
+            Amount::from_base_units(reward.base_units() / 4)
+        );
+        assert_eq!(blockchain.block_subsidy(200, Amount::ZERO), Amount::ZERO);
+
+        // The emission is a geometric series, approaching 2 * interval * REWARD,
+        // minus the reward of the genesis block, which doesn't mint anything
+        let emitted = (1..200).fold(Amount::ZERO, |supply, index| {
+            supply.saturating_add(blockchain.block_subsidy(index, supply))
+        });
+        assert!(emitted < Amount::from_coins(3 * REWARD));
+        assert!(emitted > Amount::from_coins(3 * REWARD - 1));
+
+        // Blocks after a halving must claim the halved reward
+        let chain = mine_branch(&blockchain, &[create_genesis_block()], 1, 1);
+        blockchain.add_block(chain[1].clone());
+        let full_reward = mine_block(&blockchain, &chain, vec![coinbase("Miner", 2)], 1);
+        assert!(!blockchain.is_valid_new_block(&full_reward));
+        let halved = mine_block(&blockchain, &chain, with_coinbase(&blockchain, vec![]), 1);
+        assert!(blockchain.is_valid_new_block(&halved));
+        blockchain.add_block(halved.clone());
+        assert!(blockchain.is_valid_chain(&blockchain.chain));
+        assert_eq!(
+            blockchain.total_supply,
+            reward.saturating_add(Amount::from_base_units(reward.base_units() / 2))
+        );
+
+        // A block claiming a pre-halving index to collect the full reward
+        let chain = blockchain.chain.clone();
+        let mut low_index = Block::new(
+            1,
+            2_000,
+            vec![coinbase("Miner", 1)],
+            halved.hash.clone(),
+            blockchain.next_block_bits(),
+        );
+        low_index.mine();
+        assert!(!blockchain.is_valid_new_block(&low_index));
+        let mut invalid_chain = chain.clone();
+        invalid_chain.push(low_index);
+        assert!(!blockchain.is_valid_chain(&invalid_chain));
+    }
+
+    /// Adds a block paying the reward to `address`, so it has coins to spend
+    fn fund(blockchain: &mut blockchain::Blockchain, address: &str) {
+        let chain = blockchain.chain.clone();
+        let index = chain.len() as u64;
+        let block = mine_block(blockchain, &chain, vec![coinbase(address, index)], 1);
+        assert!(blockchain.is_valid_new_block(&block));
+        blockchain.add_block(block);
+    }
+
+    /// Appends the coinbase a miner would add, paying the reward plus the fees
+    fn with_coinbase(
+        blockchain: &blockchain::Blockchain,
+        mut transactions: Vec<Transaction>,
+    ) -> Vec<Transaction> {
+        let fees = transactions
+            .iter()
+            .fold(Amount::ZERO, |fees, tx| fees.saturating_add(tx.fee));
+        transactions.push(blockchain.get_miner_transaction("Miner".into(), fees));
+        transactions
+    }
+
+    #[test]
+    fn amount_fixed_point_representation() {
+        let amount = Amount::from_base_units(150_000_000);
+        assert_eq!(
+            amount,
+            Amount::from_coins(1).saturating_add(Amount::from_base_units(50_000_000))
+        );
+        assert_eq!(amount.to_string(), "1.50000000");
+
+        // Amounts are exchanged in JSON as integer base units
+        let transaction =
+            Transaction::new("Alice".into(), "Bob".into(), amount, Amount::ZERO, 0, 1);
+        let json = serde_json::to_value(&transaction).unwrap();
+        assert_eq!(json["amount"], 150_000_000);
+        assert_eq!(json["fee"], 0);
+    }
+
+    #[test]
+    fn amount_sums_are_exact() {
+        // With floats, adding 0.1 ten times doesn't give exactly 1.0
+        let tenth = Amount::from_base_units(10_000_000);
+        let mut balance = Amount::ZERO;
+        for _ in 0..10 {
+            balance = balance.saturating_add(tenth);
+        }
+        assert_eq!(balance, Amount::from_coins(1));
+    }
+
+    #[test]
+    fn test_migrate_legacy_database() {
+        let node_id = "test-migrate";
+        {
+            // A database written before the schema version was tracked
+            let legacy_path = format!("./database/blockchain-db-{}", node_id);
+            let legacy_db: sled::Db = sled::open(legacy_path).unwrap();
+            legacy_db.insert("block:legacy", b"{}".to_vec()).unwrap();
+        }
+
+        // The node allows dropping the legacy records, they are backed up first
+        let db = Database::new(node_id.to_string(), true);
+        assert!(db.get_block("legacy").is_none());
+        let backup = db.db.open_tree("backup-v1").unwrap();
+        assert!(backup.get("block:legacy").unwrap().is_some());
+        // Running the migration again keeps the current data
+        db.store_block(&create_genesis_block()).unwrap();
+        db.migrate(false).unwrap();
+        assert_eq!(db.get_all_blocks().len(), 1);
+
+        // Without the opt-in, blocks that can't be converted are kept, and the migration fails
+        db.db
+            .insert("meta:schema_version", bincode::serialize(&3u64).unwrap())
+            .unwrap();
+        assert!(matches!(
+            db.migrate(false),
+            Err(DatabaseError::IncompatibleSchema(3))
+        ));
+        assert_eq!(db.get_all_blocks().len(), 1);
+        drop(db);
+        dump_database(node_id);
+    }
+
+    #[test]
+    fn merkle_proof_for_every_transaction() {
+        // An odd number of transactions, so the last node of a level is paired with itself
+        let transactions: Vec<Transaction> = (0..5)
+            .map(|nonce| {
+                Transaction::new(
+                    "Alice".into(),
+                    "Bob".into(),
+                    Amount::from_coins(1),
+                    Amount::ZERO,
+                    nonce,
+                    1,
+                )
+            })
+            .collect();
+        let block = Block::new(
+            1,
+            1,
+            transactions.clone(),
+            create_genesis_block().hash,
+            INITIAL_BITS,
+        );
+
+        for tx in &transactions {
+            let proof = block.merkle_proof(&tx.hash()).unwrap();
+            assert_eq!(proof.block_hash, block.hash);
+            assert!(proof.verify());
+        }
+        assert!(block.merkle_proof("unknown").is_none());
+    }
+
+    #[test]
+    fn merkle_proof_invalid_branch() {
+        let transactions: Vec<Transaction> = (0..4)
+            .map(|nonce| {
+                Transaction::new(
+                    "Alice".into(),
+                    "Bob".into(),
+                    Amount::from_coins(1),
+                    Amount::ZERO,
+                    nonce,
+                    1,
+                )
+            })
+            .collect();
+        let block = Block::new(
+            1,
+            1,
+            transactions.clone(),
+            create_genesis_block().hash,
+            INITIAL_BITS,
+        );
+
+        let mut proof = block.merkle_proof(&transactions[2].hash()).unwrap();
+        proof.index = 3;
+        assert!(!proof.verify());
+
+        let mut proof = block.merkle_proof(&transactions[2].hash()).unwrap();
+        proof.branch[0] = transactions[0].hash();
+        assert!(!proof.verify());
+    }
+
+    #[test]
+    fn duplicated_transactions_dont_mutate_a_block() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let alice = Wallet::new();
+        fund(&mut blockchain, &alice.address());
+        let fork = blockchain.chain.clone();
+        fund(&mut blockchain, "Bob");
+
+        // A competing block with an odd number of transactions
+        let payments: Vec<Transaction> = (0..2)
+            .map(|nonce| {
+                let mut transaction = Transaction::new(
+                    alice.address(),
+                    "Carol".into(),
+                    Amount::from_coins(1),
+                    Amount::from_coins(1),
+                    nonce,
+                    1,
+                );
+                transaction.sign(&alice);
+                transaction
+            })
+            .collect();
+        let mut transactions = with_coinbase(&blockchain, payments);
+        transactions.rotate_right(1);
+        let block = mine_block(&blockchain, &fork, transactions, 1);
+
+        // Repeating the last transaction keeps the merkle root, so the hash and the PoW too
+        let mut mutated = block.clone();
+        mutated.transactions.push(block.transactions[2].clone());
+        assert_eq!(mutated.calculate_merkle_root(), block.merkle_root);
+        assert_eq!(mutated.calculate_hash(), block.hash);
+        assert!(!mutated.has_unique_transactions());
+        assert!(block.has_unique_transactions());
+
+        // The mutated block isn't kept, so the valid one with the same hash isn't ignored
+        assert!(matches!(
+            blockchain.process_block(mutated),
+            BlockOutcome::Invalid
+        ));
+        assert!(!blockchain.knows_block(&block.hash));
+        assert!(matches!(
+            blockchain.process_block(block),
+            BlockOutcome::SideBranch
+        ));
+    }
+
+    #[test]
+    fn block_hash_commits_to_merkle_root() {
+        let transaction = Transaction::new(
+            "Alice".into(),
+            "Bob".into(),
+            Amount::from_coins(1),
+            Amount::ZERO,
+            0,
+            1,
+        );
+        let mut block = Block::new(
+            1,
+            1,
+            vec![transaction],
+            create_genesis_block().hash,
+            INITIAL_BITS,
+        );
+        let original_hash = block.hash.clone();
+
+        let coinbase = Transaction::new(
+            "COINBASE".into(),
+            "Miner".into(),
+            Amount::from_coins(5),
+            Amount::ZERO,
+            1,
+            1,
+        );
+        block.add_transaction(coinbase);
+        assert_ne!(block.hash, original_hash);
+        assert_eq!(block.merkle_root, block.calculate_merkle_root());
+
+        // Changing the transactions without updating the merkle root is detected
+        block.transactions.pop();
+        assert_ne!(block.merkle_root, block.calculate_merkle_root());
+    }
+
+    /// Builds a chain with a block every `block_time` seconds, retargeting every 5 blocks
+    /// with a target of 10 seconds
+    fn blockchain_with_block_time(
+        initial_bits: u32,
+        blocks: u64,
+        block_time: u64,
+    ) -> blockchain::Blockchain {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = initial_bits;
+        blockchain.set_difficulty_adjustment(10, 5);
+        for index in 1..=blocks {
+            let previous_hash = blockchain.get_last_block().hash.clone();
+            let bits = blockchain.next_block_bits();
+            let block = Block::new(
+                index,
+                1_000 + index * block_time,
+                vec![],
+                previous_hash,
+                bits,
+            );
+            blockchain.chain.push(block);
+        }
+        blockchain
+    }
+
+    #[test]
+    fn difficulty_retargeting() {
+        // Blocks on target keep the target
+        let blockchain = blockchain_with_block_time(INITIAL_BITS, 9, 10);
+        assert_eq!(blockchain.next_block_bits(), INITIAL_BITS);
+
+        // Blocks twice as fast as the target halve it, but only at the end of the window
+        let blockchain = blockchain_with_block_time(INITIAL_BITS, 9, 5);
+        assert_eq!(blockchain.chain[4].bits, INITIAL_BITS);
+        assert_eq!(blockchain.chain[5].bits, 0x1d7fff80);
+        assert_eq!(blockchain.chain[9].bits, 0x1d7fff80);
+
+        // Blocks twice as slow as the target double it
+        let blockchain = blockchain_with_block_time(INITIAL_BITS, 5, 20);
+        assert_eq!(blockchain.chain[5].bits, 0x1e01fffe);
+
+        // The adjustment is limited to a factor of 4
+        let blockchain = blockchain_with_block_time(INITIAL_BITS, 5, 1);
+        assert_eq!(blockchain.chain[5].bits, 0x1d3fffc0);
+
+        // The target can't get easier than the limit
+        let blockchain = blockchain_with_block_time(POW_LIMIT_BITS, 5, 100);
+        assert_eq!(blockchain.chain[5].bits, POW_LIMIT_BITS);
+    }
+
+    #[test]
+    fn new_block_must_meet_retargeted_bits() {
+        let blockchain = blockchain_with_block_time(POW_LIMIT_BITS, 4, 5);
+        assert_eq!(blockchain.next_block_bits(), 0x203fffff);
+
+        // The miner is given the retargeted target
+        let (mut block, _) =
+            blockchain.prepare_block_for_mining(with_coinbase(&blockchain, vec![]));
+        assert_eq!(block.bits, 0x203fffff);
+
+        // A block claiming the previous target is rejected, even if its hash meets it
+        let mut easier = block.clone();
+        easier.bits = POW_LIMIT_BITS;
+        easier.mine();
+        assert!(!blockchain.is_valid_new_block(&easier));
+
+        block.mine();
+        assert!(blockchain.is_valid_new_block(&block));
+    }
+
+    #[test]
+    fn block_timestamp_must_follow_median_time_past() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let chain = mine_branch(&blockchain, &[create_genesis_block()], 3, 10);
+        for block in chain[1..].iter() {
+            assert!(blockchain.is_valid_new_block(block));
+            blockchain.add_block(block.clone());
+        }
+        // Median of 0, 1010, 1020 and 1030
+        assert_eq!(blockchain::Blockchain::median_time_past(&chain), 1020);
+
+        let bits = blockchain.next_block_bits();
+        let block_at = |timestamp: u64| {
+            let mut block = Block::new(
+                4,
+                timestamp,
+                vec![coinbase("Miner", 4)],
+                chain[3].hash.clone(),
+                bits,
+            );
+            block.mine();
+            block
+        };
+        let at_median = block_at(1020);
+        assert!(!blockchain.is_valid_new_block(&at_median));
+        let mut invalid_chain = chain.clone();
+        invalid_chain.push(at_median);
+        assert!(!blockchain.is_valid_chain(&invalid_chain));
+        assert!(blockchain
+            .validate_headers(&[invalid_chain[4].header()])
+            .is_none());
+
+        assert!(matches!(
+            blockchain.process_block(invalid_chain[4].clone()),
+            BlockOutcome::Invalid
+        ));
+
+        // A block too far ahead of our clock isn't valid yet, but it isn't treated as invalid,
+        // the peer that sent it, or its headers, isn't penalized
+        let in_future =
+            block_at(chrono::Utc::now().timestamp() as u64 + MAX_FUTURE_BLOCK_TIME + 60);
+        assert!(!blockchain.is_valid_new_block(&in_future));
+        assert!(blockchain::Blockchain::is_too_new(in_future.timestamp));
+        assert!(!blockchain::Blockchain::is_too_new(1021));
+        assert!(blockchain.validate_headers(&[in_future.header()]).is_none());
+        assert!(matches!(
+            blockchain.process_block(in_future.clone()),
+            BlockOutcome::TooNew
+        ));
+        assert!(!blockchain.knows_block(&in_future.hash));
+        invalid_chain[4] = in_future;
+        assert!(!blockchain.is_valid_chain(&invalid_chain));
+
+        assert!(blockchain.is_valid_new_block(&block_at(1021)));
+    }
+
+    #[test]
+    fn compact_target_encoding() {
+        // Bitcoin's genesis target
+        let target = compact_to_target(0x1d00ffff).unwrap();
+        assert_eq!(
+            hex::encode(target),
+            format!("00000000ffff{}", "0".repeat(52))
+        );
+        assert_eq!(target_to_compact(&target), 0x1d00ffff);
+
+        // Targets smaller than the mantissa
+        let target = compact_to_target(0x03123456).unwrap();
+        assert_eq!(hex::encode(&target[29..]), "123456");
+        assert_eq!(target_to_compact(&target), 0x03123456);
+        assert_eq!(
+            target_to_compact(&compact_to_target(0x01120000).unwrap()),
+            0x01120000
+        );
+
+        // The mantissa sign bit is never set when encoding
+        let target = compact_to_target(0x1e00ffff).unwrap();
+        assert_eq!(
+            target_to_compact(&scale_target(&target, 0x81, 1, &[0xff; 32])),
+            0x1f0080ff
+        );
+
+        // Negative and overflowing targets are invalid
+        assert!(compact_to_target(0x1d812345).is_none());
+        assert!(compact_to_target(0x23123456).is_none());
+    }
+
+    #[test]
+    fn hash_is_compared_numerically_with_target() {
+        let target = compact_to_target(0x1d00ffff).unwrap();
+        assert!(hash_meets_target(
+            &format!("00000000ffff{}", "0".repeat(52)),
+            &target
+        ));
+        assert!(hash_meets_target(
+            &format!("000000000001{}", "f".repeat(52)),
+            &target
+        ));
+        assert!(!hash_meets_target(
+            &format!("00000000ffff{}1", "0".repeat(51)),
+            &target
+        ));
+        assert!(!hash_meets_target("not a hash", &target));
+    }
+
+    /// Mines a block with these transactions on top of `parent`, `block_time` seconds after it,
+    /// with the target given by the retargeting rules of `blockchain`
+    fn mine_block(
+        blockchain: &blockchain::Blockchain,
+        parent: &[Block],
+        transactions: Vec<Transaction>,
+        block_time: u64,
+    ) -> Block {
+        let last = parent.last().unwrap();
+        let mut block = Block::new(
+            last.index + 1,
+            1_000 + (last.index + 1) * block_time,
+            transactions,
+            last.hash.clone(),
+            blockchain.next_bits(parent),
+        );
+        block.mine();
+        block
+    }
+
+    /// Mines `blocks` blocks with only a coinbase on top of `parent`, one every `block_time` seconds
+    fn mine_branch(
+        blockchain: &blockchain::Blockchain,
+        parent: &[Block],
+        blocks: u64,
+        block_time: u64,
+    ) -> Vec<Block> {
+        let mut chain = parent.to_vec();
+        for _ in 0..blocks {
+            let index = chain.len() as u64;
+            let block = mine_block(
+                blockchain,
+                &chain,
+                vec![coinbase("Miner", index)],
+                block_time,
+            );
+            chain.push(block);
+        }
+        chain
+    }
+
+    #[test]
+    fn block_work_from_target() {
+        // Same value as Bitcoin's genesis block
+        assert_eq!(block_work(0x1d00ffff), 0x100010001);
+        // Halving the target doubles the work
+        assert_eq!(block_work(0x1c7fff80), 2 * block_work(0x1d00ffff));
+        assert_eq!(block_work(0x04000000), 0);
+    }
+
+    #[test]
+    fn fork_choice_prefers_heaviest_chain() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        blockchain.set_difficulty_adjustment(10, 2);
+        let genesis = vec![create_genesis_block()];
+
+        // A long branch of slow blocks stays at the easiest target,
+        // while a short branch of fast blocks gets a harder one
+        let light = mine_branch(&blockchain, &genesis, 7, 100);
+        let heavy = mine_branch(&blockchain, &genesis, 5, 1);
+        assert!(heavy.len() < light.len());
+        assert!(
+            blockchain::Blockchain::chain_work(&heavy) > blockchain::Blockchain::chain_work(&light)
+        );
+
+        assert!(blockchain.is_heavier_valid_chain(&light));
+        blockchain.reorganize(light.clone());
+        assert!(blockchain.is_heavier_valid_chain(&heavy));
+        blockchain.reorganize(heavy.clone());
+
+        // Once on the heaviest chain, the longer one can't replace it, and ties keep ours
+        assert!(!blockchain.is_heavier_valid_chain(&light));
+        assert!(!blockchain.is_heavier_valid_chain(&heavy));
+        let fork = mine_branch(&blockchain, &heavy[..heavy.len() - 1], 1, 2);
+        assert_ne!(fork.last(), heavy.last());
+        assert_eq!(
+            blockchain::Blockchain::chain_work(&fork),
+            blockchain.total_work()
+        );
+        assert!(!blockchain.is_heavier_valid_chain(&fork));
+
+        // Extending the competing fork makes it the heaviest
+        let fork = mine_branch(&blockchain, &fork, 1, 1);
+        assert!(blockchain.is_heavier_valid_chain(&fork));
+    }
+
+    #[test]
+    fn fork_choice_rejects_unearned_work() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let genesis = vec![create_genesis_block()];
+        let chain = mine_branch(&blockchain, &genesis, 2, 1);
+        blockchain.reorganize(chain.clone());
+
+        // Claiming a harder target than the retargeting gives, without mining it
+        let mut forged = mine_branch(&blockchain, &genesis, 3, 1);
+        forged[3].bits = 0x1d00ffff;
+        forged[3].hash = forged[3].calculate_hash();
+        assert!(blockchain::Blockchain::chain_work(&forged) > blockchain.total_work());
+        assert!(!blockchain.is_heavier_valid_chain(&forged));
+
+        // Chains that don't start from our genesis block are rejected
+        let mut other_genesis = create_genesis_block();
+        other_genesis.timestamp = 1;
+        let other = mine_branch(&blockchain, &[other_genesis], 3, 1);
+        assert!(!blockchain.is_heavier_valid_chain(&other));
+    }
+
+    #[test]
+    fn fork_choice_validates_from_the_fork_point() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let alice = Wallet::new();
+        fund(&mut blockchain, &alice.address());
+        let fork = blockchain.chain.clone();
+        let payment = |recipient: &str, nonce: u64| {
+            let mut transaction = Transaction::new(
+                alice.address(),
+                recipient.into(),
+                Amount::from_coins(1),
+                Amount::from_coins(1),
+                nonce,
+                10,
+            );
+            transaction.sign(&alice);
+            transaction
+        };
+        let paid_coinbase = |index: u64| {
+            let mut transaction = coinbase("Miner", index);
+            transaction.amount = Amount::from_coins(REWARD + 1);
+            transaction
+        };
+
+        // Our chain spends the first nonce of Alice after the fork point
+        let block = mine_block(
+            &blockchain,
+            &fork,
+            vec![payment("Bob", 0), paid_coinbase(2)],
+            1,
+        );
+        assert!(blockchain.is_valid_new_block(&block));
+        blockchain.add_block(block);
+        assert_eq!(blockchain.get_next_nonce(&alice.address()), 1);
+
+        // Their branch is checked against the state at the fork point, not our tip,
+        // so the next nonce of our chain isn't valid there
+        let mut stale = fork.clone();
+        let block = mine_block(
+            &blockchain,
+            &stale,
+            vec![payment("Carol", 1), paid_coinbase(2)],
+            2,
+        );
+        stale.push(block);
+        let stale = mine_branch(&blockchain, &stale, 1, 2);
+        assert!(blockchain::Blockchain::chain_work(&stale) > blockchain.total_work());
+        assert!(!blockchain.is_heavier_valid_chain(&stale));
+
+        let mut theirs = fork.clone();
+        let block = mine_block(
+            &blockchain,
+            &theirs,
+            vec![payment("Carol", 0), paid_coinbase(2)],
+            2,
+        );
+        theirs.push(block);
+        let theirs = mine_branch(&blockchain, &theirs, 1, 2);
+        assert!(blockchain.is_heavier_valid_chain(&theirs));
+
+        // Switching rolls back our block and applies theirs, leaving the state of their chain
+        let reorganization = blockchain.reorganize(theirs.clone());
+        assert_eq!(reorganization.disconnected.len(), 1);
+        assert_eq!(reorganization.connected.len(), 2);
+        assert_eq!(blockchain.get_balance("Bob"), Amount::ZERO);
+        assert_eq!(blockchain.get_balance("Carol"), Amount::from_coins(1));
+        assert_eq!(
+            blockchain.get_balance(&alice.address()),
+            Amount::from_coins(REWARD - 2)
+        );
+        assert_eq!(
+            blockchain.get_balance("Miner"),
+            Amount::from_coins(2 * REWARD + 1)
+        );
+        assert_eq!(blockchain.get_next_nonce(&alice.address()), 1);
+        assert_eq!(blockchain.total_supply, Amount::from_coins(3 * REWARD));
+    }
+
+    #[test]
+    fn block_locator_finds_fork_point() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let genesis = vec![create_genesis_block()];
+        let chain = mine_branch(&blockchain, &genesis, 30, 1);
+        blockchain.reorganize(chain.clone());
+
+        // The latest blocks one by one, then exponentially further apart, down to genesis
+        let locator = blockchain.block_locator();
+        let indexes: Vec<u64> = locator
+            .iter()
+            .map(|hash| blockchain.get_block(hash).unwrap().index)
+            .collect();
+        assert_eq!(
+            indexes,
+            vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+        );
+
+        // A peer one block behind only gets the tip
+        let behind = vec![chain[29].hash.clone(), chain[0].hash.clone()];
+        assert_eq!(
+            blockchain.headers_after(&behind, 2000),
+            vec![chain[30].header()]
+        );
+        assert!(blockchain.headers_after(&locator, 2000).is_empty());
+
+        // Unknown hashes are skipped, and without any known one the headers start after genesis
+        let forked = vec!["unknown".to_string(), chain[10].hash.clone()];
+        assert_eq!(
+            blockchain.headers_after(&forked, 3),
+            chain[11..14].iter().map(Block::header).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            blockchain.headers_after(&["unknown".to_string()], 1),
+            vec![chain[1].header()]
+        );
+    }
+
+    #[test]
+    fn headers_validated_before_blocks() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        blockchain.set_difficulty_adjustment(10, 2);
+        let genesis = vec![create_genesis_block()];
+        let ours = mine_branch(&blockchain, &genesis, 2, 1);
+        blockchain.reorganize(ours.clone());
+
+        // A competing branch forking from our first block
+        let theirs = mine_branch(&blockchain, &ours[..2], 3, 2);
+        let headers: Vec<_> = theirs[2..].iter().map(Block::header).collect();
+        assert_eq!(
+            blockchain.validate_headers(&headers),
+            Some(blockchain::Blockchain::chain_work(&theirs))
+        );
+        assert!(blockchain.validate_headers(&[]).is_none());
+
+        // Headers must link to each other, and to a block of our chain
+        assert!(blockchain.validate_headers(&headers[1..]).is_none());
+        let mut gap = headers.clone();
+        gap.remove(1);
+        assert!(blockchain.validate_headers(&gap).is_none());
+
+        // The hash must match the header, meet its target, and the target the retargeting gives
+        let mut tampered = headers.clone();
+        tampered[1].timestamp += 1;
+        assert!(blockchain.validate_headers(&tampered).is_none());
+        let mut forged = headers.clone();
+        forged[2].bits = 0x1d00ffff;
+        forged[2].hash = forged[2].calculate_hash();
+        assert!(blockchain.validate_headers(&forged).is_none());
+
+        // Downloaded batches are checked as they arrive, against the previous batch
+        assert!(is_linked_batch(None, &headers[..2]));
+        assert!(is_linked_batch(Some(&headers[1]), &headers[2..]));
+        assert!(!is_linked_batch(Some(&headers[0]), &headers[2..]));
+        assert!(!is_linked_batch(None, &gap));
+        assert!(!is_linked_batch(None, &tampered));
+    }
+
+    fn coinbase(miner: &str, index: u64) -> Transaction {
+        Transaction::new(
+            "COINBASE".into(),
+            miner.into(),
+            Amount::from_coins(REWARD),
+            Amount::ZERO,
+            index,
+            1,
+        )
+    }
+
+    #[test]
+    fn reorganization_reverts_orphaned_blocks() {
+        let db = initialize_database("test-reorg");
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let alice = Wallet::new();
+        fund(&mut blockchain, &alice.address());
+        let funded = blockchain.chain.clone();
+
+        let mut to_bob = Transaction::new(
+            alice.address(),
+            "Bob".into(),
+            Amount::from_coins(1),
+            Amount::ZERO,
+            0,
+            1,
+        );
+        to_bob.sign(&alice);
+        let mut to_carol = Transaction::new(
+            alice.address(),
+            "Carol".into(),
+            Amount::from_coins(1),
+            Amount::ZERO,
+            1,
+            2,
+        );
+        to_carol.sign(&alice);
+        let erin = Wallet::new();
+        let mut to_dave = Transaction::new(
+            erin.address(),
+            "Dave".into(),
+            Amount::ZERO,
+            Amount::ZERO,
+            0,
+            3,
+        );
+        to_dave.sign(&erin);
+
+        // Our branch, both transactions of Alice are mined
+        let mut ours = funded.clone();
+        let block = mine_block(
+            &blockchain,
+            &ours,
+            vec![coinbase("MinerA", 2), to_bob.clone()],
+            1,
+        );
+        ours.push(block);
+        let block = mine_block(
+            &blockchain,
+            &ours,
+            vec![coinbase("MinerA", 3), to_carol.clone()],
+            1,
+        );
+        ours.push(block);
+        blockchain.reorganize(ours.clone());
+        db.store_blocks_and_transactions(ours.clone()).unwrap();
+        assert_eq!(db.get_wallet_nonce(&alice.address()).unwrap(), 2);
+
+        // The heavier branch only has the first transaction of Alice
+        let mut theirs = funded.clone();
+        let block = mine_block(
+            &blockchain,
+            &theirs,
+            vec![coinbase("MinerB", 2), to_bob.clone()],
+            2,
+        );
+        theirs.push(block);
+        let block = mine_block(
+            &blockchain,
+            &theirs,
+            vec![coinbase("MinerB", 3), to_dave.clone()],
+            2,
+        );
+        theirs.push(block);
+        theirs = mine_branch(&blockchain, &theirs, 1, 2);
+        assert!(blockchain.is_heavier_valid_chain(&theirs));
+
+        let reorganization = blockchain.reorganize(theirs.clone());
+        assert_eq!(reorganization.disconnected, ours[2..].to_vec());
+        assert_eq!(reorganization.connected, theirs[2..].to_vec());
+        assert_eq!(
+            reorganization.orphaned_transactions(),
+            vec![to_carol.clone()]
+        );
+        assert_eq!(blockchain.get_next_nonce(&alice.address()), 1);
+
+        db.apply_reorganization(&reorganization).unwrap();
+        assert!(db.get_block(&ours[3].hash).is_none());
+        assert!(db.get_block(&theirs[4].hash).is_some());
+        assert!(db.get_transaction(&to_carol.hash()).unwrap().is_none());
+        assert!(db.get_transaction(&to_bob.hash()).unwrap().is_some());
+        assert!(db.get_transactions_by_wallet("Carol").unwrap().is_empty());
+        assert_eq!(db.get_wallet_nonce(&alice.address()).unwrap(), 1);
+        assert_eq!(db.get_wallet_balance("MinerA").unwrap(), Amount::ZERO);
+        assert_eq!(
+            db.get_wallet_balance("MinerB").unwrap(),
+            Amount::from_coins(2 * REWARD)
+        );
+        // The balance table follows the ledger of the new chain
+        for address in [alice.address(), "Bob".into(), "Carol".into()] {
+            assert_eq!(
+                db.get_wallet_balance(&address).unwrap(),
+                blockchain.get_balance(&address)
+            );
+        }
+        dump_database("test-reorg");
+
+        // The orphaned transaction goes back to the pool, the ones of the new branch leave it
+        let mut pool = TransactionPool::new();
+        // The transactions of this test pay no fee
+        pool.set_limits(
+            DEFAULT_MAX_POOL_TRANSACTIONS,
+            DEFAULT_MAX_POOL_BYTES,
+            Amount::ZERO,
+        );
+        assert!(pool
+            .add_transaction(to_dave.clone(), 0, Amount::from_coins(100))
+            .is_ok());
+        let returned = pool.process_reorganization(
+            reorganization.orphaned_transactions(),
+            &reorganization.confirmed_transactions(),
+            |sender| blockchain.get_next_nonce(sender),
+            |sender| blockchain.get_balance(sender),
+            blockchain.next_height(),
+        );
+        assert_eq!(returned, 1);
+        assert!(pool.tx_map.contains_key(&to_carol.hash()));
+        assert!(!pool.tx_map.contains_key(&to_dave.hash()));
+    }
+
+    #[test]
+    fn side_branch_becomes_tip_when_heavier() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let genesis = vec![create_genesis_block()];
+
+        let ours = mine_branch(&blockchain, &genesis, 2, 1);
+        for block in ours[1..].iter() {
+            assert!(matches!(
+                blockchain.process_block(block.clone()),
+                BlockOutcome::NewTip(_)
+            ));
+        }
+
+        // A competing branch is kept aside, until it has more work than ours
+        let theirs = mine_branch(&blockchain, &genesis, 3, 2);
+        for block in theirs[1..3].iter() {
+            assert!(matches!(
+                blockchain.process_block(block.clone()),
+                BlockOutcome::SideBranch
+            ));
+        }
+        assert_eq!(blockchain.chain, ours);
+
+        match blockchain.process_block(theirs[3].clone()) {
+            BlockOutcome::NewTip(reorganization) => {
+                assert_eq!(reorganization.disconnected, ours[1..].to_vec());
+                assert_eq!(reorganization.connected, theirs[1..].to_vec());
+            }
+            _ => panic!("the heavier branch must become the main chain"),
+        }
+        assert_eq!(blockchain.chain, theirs);
+
+        // Our previous branch is kept, in case it becomes the heaviest again
+        assert_eq!(blockchain.block_tree.side_block_count(), 2);
+        assert!(blockchain.knows_block(&ours[2].hash));
+        assert!(matches!(
+            blockchain.process_block(ours[2].clone()),
+            BlockOutcome::Duplicate
+        ));
+    }
+
+    #[test]
+    fn side_blocks_are_checked_against_their_branch() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let genesis = vec![create_genesis_block()];
+        let ours = mine_branch(&blockchain, &genesis, 2, 1);
+        blockchain.reorganize(ours.clone());
+
+        // The target of a side block must be the one its own branch gives
+        let mut forged = Block::new(
+            1,
+            1_002,
+            vec![coinbase("Miner", 1)],
+            genesis[0].hash.clone(),
+            0x2000ffff,
+        );
+        forged.mine();
+        assert!(matches!(
+            blockchain.process_block(forged),
+            BlockOutcome::Invalid
+        ));
+        assert_eq!(blockchain.block_tree.side_block_count(), 0);
 
-        pool.add_transaction(tx1);
-        pool.add_transaction(tx2);
-        // Make sure the transaction with the higher fee will be popped first
-        assert_eq!(
-            pool.get_next_transaction().unwrap().fee,
-            OrderedFloat::from(2.0)
-        )
+        // A full tree evicts the branch tip with the least work, never the parent of the block
+        let mut tree = BlockTree::default();
+        let blocks: Vec<Block> = (0..MAX_SIDE_BLOCKS as u64)
+            .map(|i| Block::new(1, i, vec![], genesis[0].hash.clone(), POW_LIMIT_BITS))
+            .collect();
+        for (work, block) in blocks.iter().enumerate() {
+            assert!(tree.insert_side_block(block.clone(), work as u128 + 1));
+        }
+        let lighter = Block::new(
+            1,
+            1_000_000,
+            vec![],
+            genesis[0].hash.clone(),
+            POW_LIMIT_BITS,
+        );
+        assert!(!tree.insert_side_block(lighter.clone(), 1));
+        assert!(!tree.contains(&lighter.hash));
+
+        let child = Block::new(2, 1, vec![], blocks[0].hash.clone(), POW_LIMIT_BITS);
+        assert!(tree.insert_side_block(child.clone(), 10_000));
+        assert_eq!(tree.side_block_count(), MAX_SIDE_BLOCKS);
+        assert!(tree.contains(&child.hash));
+        assert!(tree.contains(&blocks[0].hash));
+        assert!(!tree.contains(&blocks[1].hash));
     }
 
     #[test]
-    fn transaction_pool_get_next_transaction_same_fee_order_correct() {
-        // When comparing transactions with the same fee, the oldest one will be prioritized
-        let mut pool = TransactionPool::new();
+    fn orphan_blocks_connect_when_parent_arrives() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let chain = mine_branch(&blockchain, &[create_genesis_block()], 3, 1);
 
-        let tx1 = Transaction::new("Alice".into(), "Bob".into(), 10.0, 1.0, 100);
-        let tx2 = Transaction::new("Charlie".into(), "Dave".into(), 5.0, 1.0, 101);
-        pool.add_transaction(tx1);
-        pool.add_transaction(tx2);
+        // Blocks arriving out of order wait for their parent
+        match blockchain.process_block(chain[3].clone()) {
+            BlockOutcome::Orphan(parent_hash) => assert_eq!(parent_hash, chain[2].hash),
+            _ => panic!("the block must be kept as an orphan"),
+        }
+        match blockchain.process_block(chain[2].clone()) {
+            BlockOutcome::Orphan(parent_hash) => assert_eq!(parent_hash, chain[1].hash),
+            _ => panic!("the block must be kept as an orphan"),
+        }
+        assert_eq!(blockchain.block_tree.orphan_count(), 2);
 
-        let tx = pool.get_next_transaction().unwrap();
-        assert_eq!(tx.amount, OrderedFloat::from(10.0));
-        assert_eq!(tx.timestamp, 100);
+        match blockchain.process_block(chain[1].clone()) {
+            BlockOutcome::NewTip(reorganization) => {
+                assert!(reorganization.disconnected.is_empty());
+                assert_eq!(reorganization.connected, chain[1..].to_vec());
+            }
+            _ => panic!("the orphans must be connected to the chain"),
+        }
+        assert_eq!(blockchain.chain, chain);
+        assert_eq!(blockchain.block_tree.orphan_count(), 0);
+
+        // Blocks failing the PoW aren't kept
+        let mut invalid = mine_block(&blockchain, &chain, vec![], 1);
+        invalid.previous_hash = "unknown".into();
+        assert!(matches!(
+            blockchain.process_block(invalid),
+            BlockOutcome::Invalid
+        ));
+        assert_eq!(blockchain.block_tree.orphan_count(), 0);
+    }
+
+    #[test]
+    fn balance_table_follows_stored_blocks() {
+        let node_id = "test-balances";
+        let db = initialize_database(node_id);
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let alice = Wallet::new();
+        fund(&mut blockchain, &alice.address());
+
+        let mut to_bob = Transaction::new(
+            alice.address(),
+            "Bob".into(),
+            Amount::from_coins(3),
+            Amount::from_coins(1),
+            0,
+            1,
+        );
+        to_bob.sign(&alice);
+        let txs = with_coinbase(&blockchain, vec![to_bob]);
+        let block = mine_block(&blockchain, &blockchain.chain, txs, 1);
+        assert!(blockchain.add_block(block.clone()));
+
+        db.store_blocks_and_transactions(blockchain.chain.clone())
+            .unwrap();
+        // Storing a block twice doesn't move the balances again
+        db.store_blocks_and_transactions(vec![block.clone()])
+            .unwrap();
+        let addresses = [alice.address(), "Bob".into(), "Miner".into()];
+        for address in &addresses {
+            assert_eq!(
+                db.get_wallet_balance(address).unwrap(),
+                blockchain.get_balance(address)
+            );
+        }
+        assert_eq!(
+            db.get_wallet_balance(&alice.address()).unwrap(),
+            Amount::from_coins(REWARD - 4)
+        );
+
+        // A block of a replaced chain left in the database isn't counted once the balances
+        // are rebuilt from the restored chain, and it's removed
+        let mut to_carol = Transaction::new(
+            alice.address(),
+            "Carol".into(),
+            Amount::from_coins(2),
+            Amount::from_coins(1),
+            0,
+            1,
+        );
+        to_carol.sign(&alice);
+        let replaced = mine_block(
+            &blockchain,
+            &blockchain.chain[..2],
+            vec![to_carol, coinbase("Miner", 2)],
+            2,
+        );
+        db.store_blocks_and_transactions(vec![replaced.clone()])
+            .unwrap();
+        assert_eq!(
+            db.get_wallet_balance("Carol").unwrap(),
+            Amount::from_coins(2)
+        );
+        db.rebuild_from_chain(&blockchain.chain).unwrap();
+        for address in &addresses {
+            assert_eq!(
+                db.get_wallet_balance(address).unwrap(),
+                blockchain.get_balance(address)
+            );
+        }
+        assert_eq!(db.get_wallet_balance("Carol").unwrap(), Amount::ZERO);
+        assert_eq!(
+            db.get_wallet_nonce(&alice.address()).unwrap(),
+            blockchain.get_next_nonce(&alice.address())
+        );
+        assert!(db.get_block(&replaced.hash).is_none());
+        assert!(db.get_block(&block.hash).is_some());
+
+        db.revert_block(&block).unwrap();
+        assert_eq!(
+            db.get_wallet_balance(&alice.address()).unwrap(),
+            Amount::from_coins(REWARD)
+        );
+        assert_eq!(db.get_wallet_balance("Bob").unwrap(), Amount::ZERO);
+        dump_database(node_id);
     }
 
-    fn initialize_database() -> crate::db::Database {
-        Database::new(String::from("test"))
+    fn initialize_database(node_id: &str) -> crate::db::Database {
+        Database::new(String::from(node_id), false)
     }
 
-    fn dump_database() {
+    fn dump_database(node_id: &str) {
         use std::fs;
 
-        let test_database_path = format!("./database/blockchain-db-{}", String::from("test"));
+        let test_database_path = format!("./database/blockchain-db-{}", node_id);
 
         // Remove the old database directory if it exists
         if fs::metadata(test_database_path.clone()).is_ok() {
@@ -142,12 +1879,116 @@ mod tests {
 
     #[test]
     fn test_save_get_block() {
-        let db = initialize_database();
+        let db = initialize_database("test");
         let block = create_genesis_block();
         db.store_block(&block).unwrap();
         let block_from_db = db.get_block(block.hash.as_str()).unwrap();
         assert_eq!(block, block_from_db);
-        dump_database();
+        dump_database("test");
+    }
+
+    #[test]
+    fn test_restore_blockchain_from_database() {
+        let db = initialize_database("test-restore");
+        let genesis = create_genesis_block();
+        let miner_tx = Transaction::new(
+            "COINBASE".into(),
+            "Miner".into(),
+            Amount::from_coins(5),
+            Amount::ZERO,
+            1,
+            1,
+        );
+        let mut first = Block::new(1, 1, vec![miner_tx], genesis.hash.clone(), POW_LIMIT_BITS);
+        first.mine();
+        let second_miner_tx = Transaction::new(
+            "COINBASE".into(),
+            "Miner".into(),
+            Amount::from_coins(5),
+            Amount::ZERO,
+            2,
+            2,
+        );
+        let mut second = Block::new(
+            2,
+            2,
+            vec![second_miner_tx],
+            first.hash.clone(),
+            POW_LIMIT_BITS,
+        );
+        second.mine();
+        // A block from a chain that was replaced during sync, it must not be restored
+        let mut stale = Block::new(1, 3, vec![], genesis.hash.clone(), POW_LIMIT_BITS);
+        stale.mine();
+
+        db.store_blocks_and_transactions(vec![genesis, first, stale, second.clone()])
+            .unwrap();
+
+        let mut restored = blockchain::Blockchain::new();
+        restored.initial_bits = POW_LIMIT_BITS;
+        assert!(restored.restore_from_blocks(db.get_all_blocks()));
+        assert_eq!(restored.chain.len(), 3);
+        assert_eq!(restored.get_last_block(), &second);
+        assert_eq!(restored.total_supply, Amount::from_coins(2 * REWARD));
+        dump_database("test-restore");
+    }
+
+    #[test]
+    fn test_restore_blockchain_skips_invalid_blocks() {
+        let genesis = create_genesis_block();
+        let mut tampered = Block::new(1, 1, vec![], genesis.hash.clone(), POW_LIMIT_BITS);
+        tampered.mine();
+        tampered.nonce += 1;
+
+        // The tampered block is ignored, only the valid part of the chain is restored
+        let mut restored = blockchain::Blockchain::new();
+        restored.initial_bits = POW_LIMIT_BITS;
+        assert!(restored.restore_from_blocks(vec![genesis, tampered]));
+        assert_eq!(restored.chain.len(), 1);
+        assert!(!restored.restore_from_blocks(vec![]));
+
+        // Blocks that don't meet the target are ignored too
+        let mut unmined = Block::new(1, 1, vec![], create_genesis_block().hash, INITIAL_BITS);
+        while unmined.is_valid() {
+            unmined.mine_step();
+        }
+        let mut restored = blockchain::Blockchain::new();
+        assert!(restored.restore_from_blocks(vec![create_genesis_block(), unmined]));
+        assert_eq!(restored.chain.len(), 1);
+    }
+
+    #[test]
+    fn test_restore_blockchain_picks_heaviest_chain() {
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        blockchain.set_difficulty_adjustment(10, 2);
+        let genesis = vec![create_genesis_block()];
+
+        // The longer stored branch has less work than the shorter one
+        let light = mine_branch(&blockchain, &genesis, 7, 100);
+        let heavy = mine_branch(&blockchain, &genesis, 5, 1);
+        let mut blocks = light.clone();
+        blocks.extend_from_slice(&heavy[1..]);
+        // A corrupted block extending the heavy branch is skipped, its parent stays the tip
+        let mut tampered = mine_block(&blockchain, &heavy, vec![coinbase("Miner", 6)], 1);
+        tampered.nonce += 1;
+        blocks.push(tampered);
+
+        let mut restored = blockchain::Blockchain::new();
+        restored.initial_bits = POW_LIMIT_BITS;
+        restored.set_difficulty_adjustment(10, 2);
+        assert!(restored.restore_from_blocks(blocks.clone()));
+        assert_eq!(restored.chain, heavy);
+        blockchain.reorganize(heavy.clone());
+        assert_eq!(
+            restored.get_balance("Miner"),
+            blockchain.get_balance("Miner")
+        );
+        assert_eq!(restored.total_supply, blockchain.total_supply);
+
+        // Blocks from another network can't be restored without our genesis
+        assert!(!restored.restore_from_blocks(blocks[1..].to_vec()));
+        assert_eq!(restored.chain, heavy);
     }
 
     #[test]
@@ -172,6 +2013,24 @@ mod tests {
         assert_eq!(config.http_address, "127.0.0.1:3000");
         assert_eq!(config.bootstrap_address, Some("127.0.0.1:4000".to_string()));
         assert_eq!(config.node_id, "node-123");
+        // Missing retargeting fields fall back to the defaults
+        assert_eq!(config.target_block_time, TARGET_BLOCK_TIME);
+        assert_eq!(
+            config.difficulty_adjustment_window,
+            DIFFICULTY_ADJUSTMENT_WINDOW
+        );
+        assert_eq!(config.halving_interval, HALVING_INTERVAL);
+        assert_eq!(config.network_id, DEFAULT_NETWORK_ID);
+        assert_eq!(config.max_inbound_peers, DEFAULT_MAX_INBOUND_PEERS);
+        assert_eq!(config.max_outbound_peers, DEFAULT_MAX_OUTBOUND_PEERS);
+        assert!(!config.local_discovery);
+        assert!(!config.drop_incompatible_database);
+        assert_eq!(config.max_pool_transactions, DEFAULT_MAX_POOL_TRANSACTIONS);
+        assert_eq!(config.max_pool_bytes, DEFAULT_MAX_POOL_BYTES);
+        assert_eq!(
+            config.min_relay_fee,
+            Amount::from_base_units(DEFAULT_MIN_RELAY_FEE)
+        );
 
         // Cleanup test file
         std::fs::remove_file(file_path).expect("Failed to remove test file.");
@@ -198,4 +2057,1296 @@ mod tests {
         // Cleanup test file
         std::fs::remove_file(file_path).expect("Failed to remove test file.");
     }
+
+    #[tokio::test]
+    async fn codec_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let request = Request {
+            command: GET_BLOCK.to_string(),
+            data: "a".repeat(2048),
+        };
+        write_message(&mut client, MessageType::Request, 7, &request)
+            .await
+            .unwrap();
+        write_frame(&mut client, &Frame::end_of_stream(7))
+            .await
+            .unwrap();
+        drop(client);
+
+        // Messages larger than a single read are received whole
+        let frame = read_frame(&mut server).await.unwrap();
+        assert_eq!(frame.message_type, MessageType::Request);
+        assert_eq!(frame.request_id, 7);
+        let received: Request = frame.decode().unwrap();
+        assert_eq!(received.data, request.data);
+
+        let frame = read_frame(&mut server).await.unwrap();
+        assert_eq!(frame.message_type, MessageType::EndOfStream);
+        assert_eq!(frame.request_id, 7);
+        assert!(frame.payload.is_empty());
+        assert!(matches!(
+            read_frame(&mut server).await,
+            Err(CodecError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn codec_rejects_invalid_frames() {
+        let oversized = Frame {
+            message_type: MessageType::Response,
+            request_id: 1,
+            payload: vec![0u8; MAX_MESSAGE_SIZE],
+        };
+        assert!(matches!(
+            encode_frame(&oversized),
+            Err(CodecError::MessageTooLarge(_))
+        ));
+
+        // The length is checked before the payload is read
+        let length = (MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes();
+        assert!(matches!(
+            read_frame(&mut &length[..]).await,
+            Err(CodecError::MessageTooLarge(_))
+        ));
+
+        let response = Frame {
+            message_type: MessageType::Response,
+            request_id: 1,
+            payload: b"{}".to_vec(),
+        };
+        let mut unknown = encode_frame(&response).unwrap();
+        unknown[4] = 0xff;
+        assert!(matches!(
+            read_frame(&mut &unknown[..]).await,
+            Err(CodecError::UnknownMessageType(0xff))
+        ));
+
+        // A frame must at least hold the message type and the request id
+        let headerless = [0, 0, 0, 1, 2];
+        assert!(matches!(
+            read_frame(&mut &headerless[..]).await,
+            Err(CodecError::IncompleteHeader)
+        ));
+
+        let frame = encode_frame(&response).unwrap();
+        assert!(matches!(
+            read_frame(&mut &frame[..frame.len() - 1]).await,
+            Err(CodecError::Truncated)
+        ));
+        assert!(matches!(
+            read_frame(&mut &frame[..2]).await,
+            Err(CodecError::Truncated)
+        ));
+
+        let frame = read_frame(&mut &frame[..]).await.unwrap();
+        assert!(frame.decode::<Request>().is_err());
+    }
+
+    fn handshake(network_id: &str) -> Handshake {
+        Handshake::new(
+            Identity::generate(),
+            "127.0.0.1:5000".into(),
+            network_id.into(),
+            Arc::new(RwLock::new(blockchain::Blockchain::new())),
+        )
+    }
+
+    #[tokio::test]
+    async fn handshake_exchanges_versions() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let initiator = handshake(DEFAULT_NETWORK_ID);
+        let responder = handshake(DEFAULT_NETWORK_ID);
+
+        let (initiated, accepted) =
+            tokio::join!(initiator.initiate(client), responder.accept(server));
+        let (mut client, remote) = initiated.unwrap();
+        assert_eq!(remote.peer.id, responder.node_id());
+        assert_eq!(client.remote_id(), responder.node_id());
+        assert_eq!(remote.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(remote.genesis_hash, create_genesis_block().hash);
+        assert_eq!(remote.best_height, 0);
+        let (mut server, remote) = accepted.unwrap();
+        assert_eq!(remote.peer.id, initiator.node_id());
+
+        // Requests sent after the handshake are read as usual
+        let request = Request {
+            command: GET_BLOCK.to_string(),
+            data: "hash".into(),
+        };
+        write_message(&mut client, MessageType::Request, 1, &request)
+            .await
+            .unwrap();
+        let received: Request = read_frame(&mut server).await.unwrap().decode().unwrap();
+        assert_eq!(received.command, GET_BLOCK);
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_other_networks() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let initiator = handshake("other-network");
+        let responder = handshake(DEFAULT_NETWORK_ID);
+
+        let (initiated, accepted) =
+            tokio::join!(initiator.initiate(client), responder.accept(server));
+        assert!(matches!(
+            accepted,
+            Err(HandshakeError::NetworkMismatch(network)) if network == "other-network"
+        ));
+        assert!(matches!(initiated, Err(HandshakeError::Rejected(_))));
+
+        let local = handshake(DEFAULT_NETWORK_ID).local_version().await;
+        let mut remote = handshake(DEFAULT_NETWORK_ID).local_version().await;
+        assert!(Handshake::check_version(&local, &remote).is_ok());
+
+        remote.genesis_hash = "1".repeat(64);
+        assert!(matches!(
+            Handshake::check_version(&local, &remote),
+            Err(HandshakeError::GenesisMismatch(_))
+        ));
+        remote.genesis_hash = local.genesis_hash.clone();
+        remote.protocol_version = MIN_PROTOCOL_VERSION - 1;
+        assert!(matches!(
+            Handshake::check_version(&local, &remote),
+            Err(HandshakeError::UnsupportedProtocolVersion(_))
+        ));
+        remote.protocol_version = PROTOCOL_VERSION;
+        remote.services = 0;
+        assert!(matches!(
+            Handshake::check_version(&local, &remote),
+            Err(HandshakeError::MissingServices(0))
+        ));
+        assert!(matches!(
+            Handshake::check_version(&local, &local),
+            Err(HandshakeError::SelfConnection)
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_impersonation() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let responder = handshake(DEFAULT_NETWORK_ID);
+        let victim = handshake(DEFAULT_NETWORK_ID);
+        let impostor = Identity::generate();
+
+        // The impostor announces the id of another node, without holding its key
+        let version = victim.local_version().await;
+        let impersonate = async {
+            let mut stream = transport::initiate(client, &impostor).await.unwrap();
+            let request = Request {
+                command: VERSION.to_string(),
+                data: serde_json::to_string(&version).unwrap(),
+            };
+            write_message(&mut stream, MessageType::Request, 0, &request)
+                .await
+                .unwrap();
+            read_frame(&mut stream).await
+        };
+        let (_, accepted) = tokio::join!(impersonate, responder.accept(server));
+        assert!(matches!(
+            accepted,
+            Err(HandshakeError::IdentityMismatch(id)) if id == victim.node_id()
+        ));
+    }
+
+    #[tokio::test]
+    async fn secure_stream_encrypts_frames() {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let (initiator, responder) = (Identity::generate(), Identity::generate());
+        let (initiated, responded) = tokio::join!(
+            transport::initiate(client, &initiator),
+            transport::respond(&mut server, &responder)
+        );
+        let (mut client, mut secure_server) = (initiated.unwrap(), responded.unwrap());
+        assert_eq!(client.remote_id(), responder.id());
+        assert_eq!(secure_server.remote_id(), initiator.id());
+
+        // Frames larger than a noise message are split, and put back together
+        let request = Request {
+            command: GET_BLOCK.to_string(),
+            data: "a".repeat(200 * 1024),
+        };
+        let (written, read) = tokio::join!(
+            write_message(&mut client, MessageType::Request, 1, &request),
+            read_frame(&mut secure_server)
+        );
+        written.unwrap();
+        let received: Request = read.unwrap().decode().unwrap();
+        assert_eq!(received.data, request.data);
+
+        // Nothing readable goes over the connection
+        drop(secure_server);
+        let secret = Request {
+            command: GET_BLOCK.to_string(),
+            data: "secret-block-hash".into(),
+        };
+        write_message(&mut client, MessageType::Request, 2, &secret)
+            .await
+            .unwrap();
+        let mut raw = vec![0u8; 1024];
+        let size = tokio::io::AsyncReadExt::read(&mut server, &mut raw)
+            .await
+            .unwrap();
+        assert!(size > secret.data.len());
+        assert!(!raw[..size]
+            .windows(secret.data.len())
+            .any(|window| window == secret.data.as_bytes()));
+    }
+
+    #[test]
+    fn identity_is_kept_across_restarts() {
+        let path = std::path::Path::new("./database/test-identity.key");
+        let _ = std::fs::remove_file(path);
+
+        let identity = Identity::load_or_create(path).unwrap();
+        assert_eq!(identity.id().len(), 64);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(Identity::load_or_create(path).unwrap().id(), identity.id());
+        assert_ne!(Identity::generate().id(), identity.id());
+
+        write(path, r#"{"public_key":"00","private_key":"00"}"#).unwrap();
+        assert!(matches!(
+            Identity::load_or_create(path),
+            Err(IdentityError::InvalidKey(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn known_inventory_forgets_oldest() {
+        let mut known = KnownInventory::new(2);
+        assert!(known.insert("a"));
+        assert!(!known.insert("a"));
+        assert!(known.insert("b"));
+        assert!(known.insert("c"));
+
+        // "a" was forgotten to make room for "c"
+        assert!(!known.insert("c"));
+        assert!(known.insert("a"));
+    }
+
+    #[test]
+    fn peer_scores_ban_and_rate_limit() {
+        let mut scores = PeerScores::default();
+        for _ in 0..9 {
+            assert!(!scores.penalize("peer", Misbehavior::InvalidTransaction));
+        }
+        assert_eq!(scores.score("peer"), 90);
+        assert_eq!(scores.score("other"), 0);
+        // The score starts over once the peer is banned
+        assert!(scores.penalize("peer", Misbehavior::InvalidTransaction));
+        assert_eq!(scores.score("peer"), 0);
+        assert!(scores.penalize("peer", Misbehavior::InvalidBlock));
+
+        // A burst is allowed, then the bucket refills over time
+        let start = std::time::Instant::now();
+        for _ in 0..RATE_LIMIT_BURST {
+            assert!(scores.allow_message("peer", start));
+        }
+        assert!(!scores.allow_message("peer", start));
+        assert!(scores.allow_message("other", start));
+        let later = start + std::time::Duration::from_secs(1);
+        for _ in 0..RATE_LIMIT_PER_SEC {
+            assert!(scores.allow_message("peer", later));
+        }
+        assert!(!scores.allow_message("peer", later));
+    }
+
+    #[test]
+    fn database_stores_bans() {
+        let db = initialize_database("test-bans");
+        db.ban_peer("127.0.0.1:5000", 100).unwrap();
+        assert!(db.is_banned("127.0.0.1:5000", 99).unwrap());
+        assert!(!db.is_banned("127.0.0.1:5001", 99).unwrap());
+
+        // Expired bans are removed
+        assert!(!db.is_banned("127.0.0.1:5000", 100).unwrap());
+        db.ban_peer("127.0.0.1:5000", 200).unwrap();
+        assert!(db.is_banned("127.0.0.1:5000", 150).unwrap());
+        drop(db);
+        dump_database("test-bans");
+    }
+
+    #[test]
+    fn address_book_ranks_peers() {
+        let now = 1_000_000;
+        let record = |address: &str, last_success: Option<i64>, failures: u32| PeerRecord {
+            last_success,
+            failures,
+            ..PeerRecord::new(address, now)
+        };
+        let mut records = vec![
+            record("failing", Some(now), 3),
+            record("never-connected", None, 0),
+            record("connected-long-ago", Some(now - 1000), 0),
+            record("connected-lately", Some(now - 10), 0),
+        ];
+        rank_peers(&mut records);
+        let ranked: Vec<&str> = records
+            .iter()
+            .map(|record| record.address.as_str())
+            .collect();
+        assert_eq!(
+            ranked,
+            vec![
+                "connected-lately",
+                "connected-long-ago",
+                "never-connected",
+                "failing"
+            ]
+        );
+
+        let stale = PeerRecord::new("stale", now - ADDRESS_BOOK_EXPIRY_SECS - 1);
+        assert!(stale.is_expired(now));
+        assert!(!records[0].is_expired(now));
+    }
+
+    #[tokio::test]
+    async fn address_book_persists_peers() {
+        dump_database("test-address-book");
+        let database = Arc::new(Mutex::new(initialize_database("test-address-book")));
+        let address_book = AddressBook::new(database.clone(), "127.0.0.1:5000".into());
+        // Our own address is never stored
+        address_book.learned("127.0.0.1:5000", "node-1").await;
+        address_book.connected("127.0.0.1:5001", "node-1").await;
+        address_book.learned("127.0.0.1:5002", "node-1").await;
+        address_book.failed("127.0.0.1:5001").await;
+        address_book.connected("127.0.0.1:5002", "node-2").await;
+        address_book.learned("127.0.0.1:5003", "node-1").await;
+        address_book.forget("127.0.0.1:5003").await;
+
+        let stored = database
+            .lock()
+            .await
+            .get_peer("127.0.0.1:5001")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.node_id, Some("node-1".to_string()));
+        assert_eq!(stored.failures, 1);
+        assert_eq!(database.lock().await.get_peers().unwrap().len(), 2);
+
+        // Peers that failed come after the ones we connected to
+        let none = HashSet::new();
+        assert_eq!(
+            address_book.select_outbound(8, &none).await,
+            vec!["127.0.0.1:5002".to_string(), "127.0.0.1:5001".to_string()]
+        );
+        assert_eq!(address_book.select_outbound(1, &none).await.len(), 1);
+        let connected = HashSet::from(["127.0.0.1:5002".to_string()]);
+        assert_eq!(
+            address_book.select_outbound(8, &connected).await,
+            vec!["127.0.0.1:5001".to_string()]
+        );
+
+        // Only addresses seen recently are shared, the book is read again from the database
+        let now = chrono::Utc::now().timestamp();
+        let old = PeerRecord::new("127.0.0.1:5005", now - ADDR_RECENT_SECS - 1);
+        database.lock().await.store_peer(&old).unwrap();
+        let address_book = AddressBook::new(database.clone(), "127.0.0.1:5000".into());
+        let mut shared = address_book.sample_recent(8).await;
+        shared.sort();
+        assert_eq!(shared, vec!["127.0.0.1:5001", "127.0.0.1:5002"]);
+        assert_eq!(address_book.sample_recent(1).await.len(), 1);
+
+        // Expired addresses are removed when selecting
+        let mut expired = PeerRecord::new("127.0.0.1:5004", 0);
+        expired.last_success = Some(0);
+        database.lock().await.store_peer(&expired).unwrap();
+        let address_book = AddressBook::new(database.clone(), "127.0.0.1:5000".into());
+        assert_eq!(address_book.select_outbound(8, &none).await.len(), 3);
+        assert!(database
+            .lock()
+            .await
+            .get_peer("127.0.0.1:5004")
+            .unwrap()
+            .is_none());
+        drop(address_book);
+        drop(database);
+        dump_database("test-address-book");
+    }
+
+    #[tokio::test]
+    async fn address_book_limits_unverified_addresses() {
+        dump_database("test-address-book-limits");
+        let database = Arc::new(Mutex::new(initialize_database("test-address-book-limits")));
+        let now = chrono::Utc::now().timestamp();
+        let known = PeerRecord::new("127.0.0.1:5001", now - ADDR_RECENT_SECS - 1);
+        database.lock().await.store_peer(&known).unwrap();
+        let address_book = AddressBook::new(database.clone(), "127.0.0.1:5000".into());
+
+        // An addr or a registration doesn't refresh the addresses we know, a connection does
+        address_book.learned("127.0.0.1:5001", "node-1").await;
+        assert!(address_book.sample_recent(8).await.is_empty());
+        address_book.connected("127.0.0.1:5001", "node-2").await;
+        assert_eq!(address_book.sample_recent(8).await, vec!["127.0.0.1:5001"]);
+
+        // A peer adds a limited number of addresses, the verified ones don't count
+        let address = |i: usize| format!("10.{}.{}.1:5000", i / 256, i % 256);
+        for i in 0..MAX_ADDRESSES_PER_SOURCE + 10 {
+            address_book.learned(&address(i), "node-1").await;
+        }
+        assert_eq!(address_book.len().await, MAX_ADDRESSES_PER_SOURCE + 1);
+        address_book.connected(&address(0), "node-3").await;
+        address_book.learned(&address(10_000), "node-1").await;
+        assert_eq!(address_book.len().await, MAX_ADDRESSES_PER_SOURCE + 2);
+
+        // Once the book is full, new addresses are ignored,
+        // and verified ones make room by evicting the worst ranked
+        let mut i = 0;
+        while address_book.len().await < MAX_ADDRESS_BOOK_SIZE {
+            i += 1;
+            address_book
+                .learned(
+                    &address(20_000 + i),
+                    &format!("source-{}", i / MAX_ADDRESSES_PER_SOURCE),
+                )
+                .await;
+        }
+        address_book.learned("192.168.0.1:5000", "node-4").await;
+        address_book.failed(&address(1)).await;
+        address_book.connected("192.168.0.2:5000", "node-5").await;
+        assert_eq!(address_book.len().await, MAX_ADDRESS_BOOK_SIZE);
+        let database_records = database.lock().await.get_peers().unwrap();
+        assert_eq!(database_records.len(), MAX_ADDRESS_BOOK_SIZE);
+        assert!(database_records
+            .iter()
+            .any(|record| record.address == "192.168.0.2:5000"));
+        assert!(!database_records
+            .iter()
+            .any(|record| record.address == "192.168.0.1:5000" || record.address == address(1)));
+        drop(address_book);
+        drop(database);
+        dump_database("test-address-book-limits");
+    }
+
+    #[test]
+    fn address_book_prefers_diverse_peers() {
+        assert_eq!(network_group("10.1.2.3:5000"), "10.1");
+        assert_eq!(network_group("[2001:db8::1]:5000"), "2001:db8");
+        assert_eq!(network_group("seed.example.com:5000"), "seed.example.com");
+
+        let records: Vec<PeerRecord> = ["10.1.0.1:5000", "10.1.0.2:5000", "10.2.0.1:5000"]
+            .iter()
+            .map(|address| PeerRecord::new(address, 0))
+            .collect();
+        // One peer per network first, then the best ranked of the rest
+        assert_eq!(
+            select_diverse(&records, 2, &HashSet::new()),
+            vec!["10.1.0.1:5000", "10.2.0.1:5000"]
+        );
+        assert_eq!(
+            select_diverse(&records, 3, &HashSet::new()),
+            vec!["10.1.0.1:5000", "10.2.0.1:5000", "10.1.0.2:5000"]
+        );
+        // Networks we are already connected to come last
+        let connected = HashSet::from(["10.1.9.9:5000".to_string()]);
+        assert_eq!(
+            select_diverse(&records, 1, &connected),
+            vec!["10.2.0.1:5000"]
+        );
+    }
+
+    #[test]
+    fn local_announcements_are_filtered() {
+        let this_peer = Peer {
+            id: "this-node".into(),
+            address: "192.168.1.10:5000".into(),
+        };
+        let sender: std::net::SocketAddr = "192.168.1.20:7777".parse().unwrap();
+        let announce = |network_id: &str, id: &str, address: &str| {
+            serde_json::to_vec(&LocalAnnouncement {
+                network_id: network_id.into(),
+                peer: Peer {
+                    id: id.into(),
+                    address: address.into(),
+                },
+            })
+            .unwrap()
+        };
+
+        let data = announce(DEFAULT_NETWORK_ID, "other-node", "192.168.1.20:5001");
+        assert_eq!(
+            announced_address(&data, sender, DEFAULT_NETWORK_ID, &this_peer),
+            Some("192.168.1.20:5001".into())
+        );
+        // Nodes listening on every interface are reached on the one they announced from
+        let data = announce(DEFAULT_NETWORK_ID, "other-node", "0.0.0.0:5001");
+        assert_eq!(
+            announced_address(&data, sender, DEFAULT_NETWORK_ID, &this_peer),
+            Some("192.168.1.20:5001".into())
+        );
+
+        // Our own announcements, other networks and invalid data are ignored
+        for data in [
+            announce(DEFAULT_NETWORK_ID, "this-node", "192.168.1.20:5001"),
+            announce(DEFAULT_NETWORK_ID, "other-node", "192.168.1.10:5000"),
+            announce("other-network", "other-node", "192.168.1.20:5001"),
+            announce(DEFAULT_NETWORK_ID, "other-node", "not an address"),
+            b"{\"network_id\"".to_vec(),
+        ] {
+            assert_eq!(
+                announced_address(&data, sender, DEFAULT_NETWORK_ID, &this_peer),
+                None
+            );
+        }
+    }
+
+    /// Starts a peer manager for a node listening on a free local port
+    async fn peer_manager(
+        name: &str,
+        peers: Vec<String>,
+    ) -> (
+        PeerManager,
+        String,
+        String,
+        tokio::sync::mpsc::Receiver<IncomingRequest>,
+    ) {
+        peer_manager_with_limits(
+            name,
+            peers,
+            DEFAULT_MAX_INBOUND_PEERS,
+            DEFAULT_MAX_OUTBOUND_PEERS,
+        )
+        .await
+    }
+
+    async fn peer_manager_with_limits(
+        name: &str,
+        peers: Vec<String>,
+        max_inbound: usize,
+        max_outbound: usize,
+    ) -> (
+        PeerManager,
+        String,
+        String,
+        tokio::sync::mpsc::Receiver<IncomingRequest>,
+    ) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let identity = Identity::generate();
+        let node_id = identity.id();
+        let handshake = Handshake::new(
+            identity,
+            address.clone(),
+            DEFAULT_NETWORK_ID.into(),
+            Arc::new(RwLock::new(blockchain::Blockchain::new())),
+        );
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel(PEER_QUEUE_SIZE);
+        let peers = Arc::new(Mutex::new(peers.into_iter().collect()));
+        // Bans of previous runs would keep the nodes from connecting
+        let database_name = format!("test-peers-{}", name);
+        dump_database(&database_name);
+        let database = Arc::new(Mutex::new(initialize_database(&database_name)));
+        let mut manager =
+            PeerManager::new(handshake, address.clone(), peers, incoming_tx, database);
+        manager.set_connection_limits(max_inbound, max_outbound);
+
+        let accepting = manager.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let accepting = accepting.clone();
+                tokio::spawn(async move { accepting.accept(stream).await });
+            }
+        });
+        (manager, address, node_id, incoming_rx)
+    }
+
+    #[tokio::test]
+    async fn peer_manager_multiplexes_one_connection() {
+        let (node_1, address_1, id_1, mut incoming_1) = peer_manager("node-1", vec![]).await;
+        let (node_2, _address_2, id_2, mut incoming_2) =
+            peer_manager("node-2", vec![address_1]).await;
+        let connecting = node_2.clone();
+        tokio::spawn(async move { connecting.connect_to_peers().await });
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_1.is_connected(&id_2).await || !node_2.is_connected(&id_1).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nodes didn't connect");
+
+        // Node 1 answers with two frames, node 2 with none
+        let requester = id_2.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming_1.recv().await {
+                assert_eq!(incoming.peer_id, requester);
+                incoming.responder.respond(&1u64).await.unwrap();
+                incoming.responder.respond(&2u64).await.unwrap();
+                incoming.responder.end().await.unwrap();
+            }
+        });
+        let request = Request {
+            command: GET_BLOCK.to_string(),
+            data: "hash".into(),
+        };
+
+        // Concurrent requests over the same connection get their own responses
+        let (mut first, mut second) = (
+            node_2.request(&id_1, &request).await.unwrap(),
+            node_2.request(&id_1, &request).await.unwrap(),
+        );
+        for response in [&mut first, &mut second] {
+            let mut values = vec![];
+            while let Some(frame) = response.next().await.unwrap() {
+                values.push(frame.decode::<u64>().unwrap());
+            }
+            assert_eq!(values, vec![1, 2]);
+        }
+
+        // The connection opened by node 2 carries the requests of node 1 as well
+        node_1.announce(&Inventory::block("hash")).await;
+        let incoming = incoming_2.recv().await.unwrap();
+        assert_eq!(incoming.request.command, INV);
+        let announced: Vec<Inventory> = serde_json::from_str(&incoming.request.data).unwrap();
+        assert_eq!(announced, vec![Inventory::block("hash")]);
+        assert_eq!(incoming.peer_id, id_1);
+
+        // Items are announced once per peer, and never to a peer that already has them
+        node_1.announce(&Inventory::block("hash")).await;
+        node_1
+            .mark_known(&id_2, &[Inventory::transaction("tx")])
+            .await;
+        node_1.announce(&Inventory::transaction("tx")).await;
+        node_1.announce(&Inventory::transaction("other")).await;
+        let incoming = incoming_2.recv().await.unwrap();
+        let announced: Vec<Inventory> = serde_json::from_str(&incoming.request.data).unwrap();
+        assert_eq!(announced, vec![Inventory::transaction("other")]);
+
+        assert!(matches!(
+            node_2.request("unknown", &request).await,
+            Err(PeerError::NotConnected(_))
+        ));
+        dump_database("test-peers-node-1");
+        dump_database("test-peers-node-2");
+    }
+
+    #[tokio::test]
+    async fn peer_manager_drops_unread_responses() {
+        let (node_9, address_9, id_9, mut incoming_9) = peer_manager("node-9", vec![]).await;
+        let (node_10, _address_10, id_10, _incoming_10) =
+            peer_manager("node-10", vec![address_9]).await;
+        let connecting = node_10.clone();
+        tokio::spawn(async move { connecting.connect_to_peers().await });
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_9.is_connected(&id_10).await || !node_10.is_connected(&id_9).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nodes didn't connect");
+
+        // Node 9 answers GET_BLOCK with more frames than a response can buffer, anything else with one
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming_9.recv().await {
+                tokio::spawn(async move {
+                    let frames = match incoming.request.command.as_str() {
+                        GET_BLOCK => PEER_QUEUE_SIZE as u64 * 2,
+                        _ => 1,
+                    };
+                    for value in 0..frames {
+                        incoming.responder.respond(&value).await.unwrap();
+                    }
+                    incoming.responder.end().await.unwrap();
+                });
+            }
+        });
+        let request = |command: &str| Request {
+            command: command.to_string(),
+            data: "hash".into(),
+        };
+
+        // The unread response doesn't hold back the next one on the same connection
+        let mut unread = node_10.request(&id_9, &request(GET_BLOCK)).await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        let answered = tokio::time::timeout(
+            tokio::time::Duration::from_secs(2),
+            node_10.request_one(&id_9, &request(GET_DATA)),
+        )
+        .await
+        .expect("response was held back");
+        assert_eq!(answered.unwrap().unwrap().decode::<u64>().unwrap(), 0);
+
+        // The frames buffered before it was dropped are still read, then the request fails
+        let mut read = 0;
+        let failed = loop {
+            match unread.next().await {
+                Ok(Some(_)) => read += 1,
+                Ok(None) => panic!("response wasn't dropped"),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(read, PEER_QUEUE_SIZE);
+        assert!(matches!(failed, PeerError::Disconnected));
+        assert!(node_10.is_connected(&id_9).await);
+        dump_database("test-peers-node-9");
+        dump_database("test-peers-node-10");
+    }
+
+    #[tokio::test]
+    async fn headers_download_stops_when_the_peer_reorganizes() {
+        let (node_11, address_11, id_11, mut incoming_11) = peer_manager("node-11", vec![]).await;
+        let (node_12, _address_12, id_12, _incoming_12) =
+            peer_manager("node-12", vec![address_11.clone()]).await;
+        let connecting = node_12.clone();
+        tokio::spawn(async move { connecting.connect_to_peers().await });
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_11.is_connected(&id_12).await || !node_12.is_connected(&id_11).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nodes didn't connect");
+
+        let branch = |length: usize, timestamp: u64| {
+            let mut headers: Vec<BlockHeader> = vec![create_genesis_block().header()];
+            for index in 1..=length as u64 {
+                let parent = headers.last().unwrap();
+                let mut block = Block::new(
+                    index,
+                    timestamp,
+                    vec![],
+                    parent.hash.clone(),
+                    POW_LIMIT_BITS,
+                );
+                block.mine();
+                headers.push(block.header());
+            }
+            headers.split_off(1)
+        };
+        // A full batch, then the peer switches to another branch, its answer to the next
+        // locator starts over from block 1
+        let first = branch(MAX_HEADERS_PER_REQUEST, 1);
+        let other = branch(5, 2);
+        let mut tampered = branch(5, 3);
+        tampered[2].timestamp += 1;
+        let batches = vec![first.clone(), other, tampered];
+        tokio::spawn(async move {
+            for batch in batches {
+                let incoming = incoming_11.recv().await.unwrap();
+                assert_eq!(incoming.request.command, GET_HEADERS);
+                incoming.responder.respond(&batch).await.unwrap();
+                incoming.responder.end().await.unwrap();
+            }
+        });
+
+        let locator = vec![create_genesis_block().hash];
+        let headers = crate::sync::Sync::download_headers(&node_12, &id_11, locator.clone()).await;
+        assert_eq!(headers, first);
+        assert!(!node_12.is_banned(&id_11).await);
+
+        // Headers that don't form a chain within a batch are still misbehavior
+        let headers = crate::sync::Sync::download_headers(&node_12, &id_11, locator).await;
+        assert!(headers.is_empty());
+        assert!(node_12.is_banned(&id_11).await);
+        dump_database("test-peers-node-11");
+        dump_database("test-peers-node-12");
+    }
+
+    #[tokio::test]
+    async fn blocks_download_is_bounded_and_connected_in_order() {
+        let (node_13, address_13, id_13, mut incoming_13) = peer_manager("node-13", vec![]).await;
+        let (node_14, _address_14, id_14, _incoming_14) =
+            peer_manager("node-14", vec![address_13.clone()]).await;
+        let connecting = node_14.clone();
+        tokio::spawn(async move { connecting.connect_to_peers().await });
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_13.is_connected(&id_14).await || !node_14.is_connected(&id_13).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nodes didn't connect");
+
+        // Both chains stay at the easiest target, their blocks have the same work
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        blockchain.set_difficulty_adjustment(1, DIFFICULTY_ADJUSTMENT_WINDOW);
+        let genesis = vec![create_genesis_block()];
+        let ours = mine_branch(&blockchain, &genesis, MAX_BLOCKS_PER_REQUEST as u64 + 10, 2);
+        let theirs = mine_branch(&blockchain, &genesis, 5 * MAX_BLOCKS_PER_REQUEST as u64, 1);
+        let mut served = blockchain.clone();
+        served.reorganize(theirs.clone());
+        blockchain.reorganize(ours);
+        let handler = server_handler(&node_13, served, "test-sync-13");
+
+        // The requests are answered once no other one arrives, so a batch more than the limit
+        // would be asked for in the meantime
+        let server = tokio::spawn(async move {
+            let mut rounds = vec![];
+            while let Ok(Some(incoming)) =
+                tokio::time::timeout(tokio::time::Duration::from_secs(1), incoming_13.recv()).await
+            {
+                assert_eq!(incoming.request.command, GET_BLOCKS);
+                let mut requests = vec![incoming];
+                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+                while let Ok(incoming) = incoming_13.try_recv() {
+                    requests.push(incoming);
+                }
+                rounds.push(requests.len());
+                for incoming in requests {
+                    handler.clone().handle_request(incoming).await;
+                }
+            }
+            rounds
+        });
+
+        dump_database("test-sync-14");
+        let (block_tx, mut block_rx) = tokio::sync::mpsc::channel(20);
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let sync = crate::sync::Sync::new(
+            blockchain.clone(),
+            Arc::new(Mutex::new(block_tx)),
+            Arc::new(Mutex::new(initialize_database("test-sync-14"))),
+            Arc::new(Mutex::new(TransactionPool::new())),
+            Arc::new(tokio::sync::Notify::new()),
+            node_14.clone(),
+        );
+        let headers: Vec<BlockHeader> = theirs[1..].iter().map(Block::header).collect();
+        sync.download_blocks(std::slice::from_ref(&id_13), &headers)
+            .await;
+        assert!(blockchain.read().await.chain == theirs);
+
+        // The first batch doesn't have more work than our chain yet, the second one
+        // reorganizes to their branch, and the next ones extend it
+        let mut notified = 0;
+        while block_rx.try_recv().is_ok() {
+            notified += 1;
+        }
+        assert_eq!(notified, 4);
+        let rounds = server.await.unwrap();
+        assert_eq!(rounds.iter().sum::<usize>(), 5);
+        assert_eq!(rounds[0], BLOCK_BATCHES_PER_PEER);
+        assert!(rounds.iter().all(|round| *round <= BLOCK_BATCHES_PER_PEER));
+        dump_database("test-peers-node-13");
+        dump_database("test-peers-node-14");
+        dump_database("test-sync-13");
+        dump_database("test-sync-14");
+    }
+
+    /// Server handler of the node, serving the requests its peer manager receives
+    fn server_handler(
+        peer_manager: &PeerManager,
+        blockchain: blockchain::Blockchain,
+        database_name: &str,
+    ) -> Arc<ServerHandler> {
+        dump_database(database_name);
+        let (block_tx, _block_rx) = tokio::sync::mpsc::channel(20);
+        Arc::new(ServerHandler::new(
+            Arc::new(RwLock::new(blockchain)),
+            Arc::new(Mutex::new(block_tx)),
+            Arc::new(Broadcaster::new(peer_manager.clone())),
+            Arc::new(Mutex::new(TransactionPool::new())),
+            Arc::new(Mutex::new(initialize_database(database_name))),
+            Arc::new(tokio::sync::Notify::new()),
+            peer_manager.clone(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn announced_transactions_are_fetched_once() {
+        let (node_a, address_a, id_a, mut incoming_a) = peer_manager("relay-a", vec![]).await;
+        let (node_b, _address_b, id_b, mut incoming_b) =
+            peer_manager("relay-b", vec![address_a.clone()]).await;
+        let connecting = node_b.clone();
+        tokio::spawn(async move { connecting.connect_to_peers().await });
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_a.is_connected(&id_b).await || !node_b.is_connected(&id_a).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nodes didn't connect");
+
+        let alice = Wallet::new();
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        fund(&mut blockchain, &alice.address());
+        let handler_a = server_handler(&node_a, blockchain.clone(), "test-relay-a");
+        let handler_b = server_handler(&node_b, blockchain, "test-relay-b");
+
+        // Node B serves every request, node A records them,
+        // answering `get_data` with a transaction it wasn't asked for while there is one
+        let handler = handler_b.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming_b.recv().await {
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.handle_request(incoming).await });
+            }
+        });
+        let received = Arc::new(Mutex::new(Vec::<String>::new()));
+        let unrequested = Arc::new(Mutex::new(None::<Transaction>));
+        let (handler, receiving, sending) =
+            (handler_a.clone(), received.clone(), unrequested.clone());
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming_a.recv().await {
+                let command = incoming.request.command.clone();
+                receiving.lock().await.push(command.clone());
+                match sending.lock().await.clone() {
+                    Some(tx) if command == GET_DATA => {
+                        let data = InventoryData::Transaction(tx);
+                        incoming.responder.respond(&data).await.unwrap();
+                        incoming.responder.end().await.unwrap();
+                    }
+                    _ => handler.handle_request(incoming).await,
+                }
+            }
+        });
+
+        let transfer = |nonce| {
+            let mut transaction = Transaction::new(
+                alice.address(),
+                "Bob".into(),
+                Amount::from_coins(1),
+                Amount::from_coins(1),
+                nonce,
+                1,
+            );
+            transaction.sign(&alice);
+            transaction
+        };
+        let in_pool = |handler: &Arc<ServerHandler>, tx: &Transaction| {
+            let (handler, hash) = (handler.clone(), tx.hash());
+            async move {
+                handler
+                    .transaction_pool
+                    .lock()
+                    .await
+                    .get_transaction(&hash)
+                    .is_some()
+            }
+        };
+        let wait_for = |tx: Transaction| {
+            let handler = handler_b.clone();
+            async move {
+                tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+                    while !in_pool(&handler, &tx).await {
+                        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .expect("transaction wasn't relayed");
+            }
+        };
+
+        // The second announcement is skipped, node B already knows the transaction
+        let first = transfer(0);
+        handler_a
+            .transaction_pool
+            .lock()
+            .await
+            .add_transaction(first.clone(), 0, Amount::from_coins(100))
+            .unwrap();
+        node_a
+            .announce(&Inventory::transaction(&first.hash()))
+            .await;
+        node_a
+            .announce(&Inventory::transaction(&first.hash()))
+            .await;
+        wait_for(first.clone()).await;
+
+        // Announced again past the known inventory, node B doesn't ask for it since it has it
+        let inv = |tx: &Transaction| Request {
+            command: INV.to_string(),
+            data: serde_json::to_string(&[Inventory::transaction(&tx.hash())]).unwrap(),
+        };
+        node_a.send(&id_b, &inv(&first)).await.unwrap();
+        let second = transfer(1);
+        handler_a
+            .transaction_pool
+            .lock()
+            .await
+            .add_transaction(second.clone(), 1, Amount::from_coins(100))
+            .unwrap();
+        node_a
+            .announce(&Inventory::transaction(&second.hash()))
+            .await;
+        wait_for(second).await;
+
+        // Node B relays both transactions to its peers, except node A that sent them
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert_eq!(*received.lock().await, vec![GET_DATA, GET_DATA]);
+
+        // Data that wasn't asked for is rejected, the peer is banned once it keeps sending it
+        let substitute = transfer(2);
+        *unrequested.lock().await = Some(substitute.clone());
+        let decoys: Vec<Transaction> = (3..)
+            .take((BAN_SCORE / Misbehavior::UnrequestedData.penalty()) as usize)
+            .map(transfer)
+            .collect();
+        for decoy in &decoys {
+            node_a
+                .announce(&Inventory::transaction(&decoy.hash()))
+                .await;
+        }
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_b.is_banned(&id_a).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peer wasn't banned");
+        // Node B connected to node A, so the address of node A is banned with its key
+        assert!(node_b.is_banned(&address_a).await);
+        for tx in decoys.iter().chain([&substitute]) {
+            assert!(!in_pool(&handler_b, tx).await);
+        }
+
+        dump_database("test-peers-relay-a");
+        dump_database("test-peers-relay-b");
+        dump_database("test-relay-a");
+        dump_database("test-relay-b");
+    }
+
+    #[actix_web::test]
+    async fn wallet_nonce_follows_pending_transactions() {
+        let (node, _address, _id, _incoming) = peer_manager("http-nonce", vec![]).await;
+        let alice = Wallet::new();
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        fund(&mut blockchain, &alice.address());
+        let handler = server_handler(&node, blockchain, "test-http-nonce");
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(actix_web::web::Data::new(handler.clone()))
+                .service(get_wallet_nonce),
+        )
+        .await;
+        let nonce = |address: String| {
+            actix_web::test::TestRequest::get()
+                .uri(&format!("/wallet/nonce/{}", address))
+                .to_request()
+        };
+
+        let next: u64 =
+            actix_web::test::call_and_read_body_json(&app, nonce(alice.address())).await;
+        assert_eq!(next, 0);
+
+        // A wallet sending several transactions before they are mined needs the nonces
+        // of its pending transactions to be counted
+        for nonce in 0..2 {
+            let mut transaction = Transaction::new(
+                alice.address(),
+                "Bob".into(),
+                Amount::from_coins(1),
+                Amount::from_coins(1),
+                nonce,
+                1,
+            );
+            transaction.sign(&alice);
+            handler
+                .transaction_pool
+                .lock()
+                .await
+                .add_transaction(transaction, 0, Amount::from_coins(REWARD))
+                .unwrap();
+        }
+        let next: u64 =
+            actix_web::test::call_and_read_body_json(&app, nonce(alice.address())).await;
+        assert_eq!(next, 2);
+        let next: u64 = actix_web::test::call_and_read_body_json(&app, nonce("Bob".into())).await;
+        assert_eq!(next, 0);
+
+        dump_database("test-peers-http-nonce");
+        dump_database("test-http-nonce");
+    }
+
+    #[actix_web::test]
+    async fn submitted_coinbase_transactions_are_rejected() {
+        let (node, _address, _id, _incoming) = peer_manager("http-coinbase", vec![]).await;
+        // Anyone can send coins to the coinbase sender, so a coinbase could afford its fee
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        fund(&mut blockchain, "COINBASE");
+        let handler = server_handler(&node, blockchain, "test-http-coinbase");
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(actix_web::web::Data::new(handler.clone()))
+                .service(submit_transaction),
+        )
+        .await;
+
+        // A coinbase passes the signature check, it's only valid inside a block
+        let mut minted = coinbase("Mallory", 0);
+        minted.amount = Amount::from_coins(1);
+        minted.fee = Amount::from_coins(1);
+        let request = actix_web::test::TestRequest::post()
+            .uri("/transaction/submit")
+            .set_json(&minted)
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            actix_web::test::read_body(response).await,
+            "Coinbase transactions can't be submitted"
+        );
+        assert_eq!(handler.transaction_pool.lock().await.transaction_count(), 0);
+
+        dump_database("test-peers-http-coinbase");
+        dump_database("test-http-coinbase");
+    }
+
+    #[tokio::test]
+    async fn relayed_coinbase_transactions_are_rejected() {
+        let (node_c, address_c, id_c, mut incoming_c) = peer_manager("relay-c", vec![]).await;
+        let (node_d, _address_d, id_d, mut incoming_d) =
+            peer_manager("relay-d", vec![address_c.clone()]).await;
+        let connecting = node_d.clone();
+        tokio::spawn(async move { connecting.connect_to_peers().await });
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_c.is_connected(&id_d).await || !node_d.is_connected(&id_c).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nodes didn't connect");
+
+        let mut blockchain = blockchain::Blockchain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let handler_d = server_handler(&node_d, blockchain, "test-relay-d");
+        let handler = handler_d.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming_d.recv().await {
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.handle_request(incoming).await });
+            }
+        });
+
+        // Node C announces coinbase transactions, and sends them when node D asks for them
+        let minted: Vec<Transaction> = (1..)
+            .take((BAN_SCORE / Misbehavior::InvalidTransaction.penalty()) as usize)
+            .map(|index| {
+                let mut transaction = coinbase("Mallory", index);
+                transaction.fee = Amount::from_coins(1);
+                transaction
+            })
+            .collect();
+        let sending = minted.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming_c.recv().await {
+                if incoming.request.command != GET_DATA {
+                    continue;
+                }
+                let requested: Vec<Inventory> =
+                    serde_json::from_str(&incoming.request.data).unwrap();
+                for item in requested {
+                    if let Some(tx) = sending.iter().find(|tx| tx.hash() == item.hash) {
+                        let data = InventoryData::Transaction(tx.clone());
+                        incoming.responder.respond(&data).await.unwrap();
+                    }
+                }
+                incoming.responder.end().await.unwrap();
+            }
+        });
+        for tx in &minted {
+            node_c.announce(&Inventory::transaction(&tx.hash())).await;
+        }
+
+        // Every coinbase is penalized, until the peer is banned
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_d.is_banned(&id_c).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peer wasn't banned");
+        assert_eq!(
+            handler_d.transaction_pool.lock().await.transaction_count(),
+            0
+        );
+
+        dump_database("test-peers-relay-c");
+        dump_database("test-peers-relay-d");
+        dump_database("test-relay-d");
+    }
+
+    #[tokio::test]
+    async fn peer_manager_bans_misbehaving_peers() {
+        let (node_3, address_3, _id_3, _incoming_3) = peer_manager("node-3", vec![]).await;
+        let (node_4, address_4, id_4, _incoming_4) =
+            peer_manager("node-4", vec![address_3.clone()]).await;
+        let connecting = node_4.clone();
+        tokio::spawn(async move { connecting.connect_to_peers().await });
+
+        let connected = tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while !node_3.is_connected(&id_4).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        });
+        connected.await.expect("nodes didn't connect");
+
+        // Small penalties only add up, an invalid block bans the peer right away
+        node_3
+            .misbehaving(&id_4, Misbehavior::MalformedMessage)
+            .await;
+        assert!(!node_3.is_banned(&id_4).await);
+        node_3.misbehaving(&id_4, Misbehavior::InvalidBlock).await;
+        // Node 4 opened the connection, the address it reported is never banned,
+        // it could belong to another node
+        assert!(node_3.is_banned(&id_4).await);
+        assert!(!node_3.is_banned(&address_4).await);
+
+        // The connection is closed, and the peer can't connect again
+        let disconnected = tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while node_3.is_connected(&id_4).await {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        });
+        disconnected.await.expect("banned peer wasn't disconnected");
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        assert!(!node_3.is_connected(&id_4).await);
+        dump_database("test-peers-node-3");
+        dump_database("test-peers-node-4");
+    }
+
+    #[tokio::test]
+    async fn peer_manager_limits_connections() {
+        // Node 5 accepts a single connection, from node 6 or node 7
+        let (node_5, address_5, _id_5, _incoming_5) =
+            peer_manager_with_limits("node-5", vec![], 1, DEFAULT_MAX_OUTBOUND_PEERS).await;
+        let (node_6, address_6, _id_6, _incoming_6) =
+            peer_manager("node-6", vec![address_5.clone()]).await;
+        let (node_7, address_7, _id_7, _incoming_7) =
+            peer_manager("node-7", vec![address_5.clone()]).await;
+        // Node 8 opens a single connection, to node 6 or node 7
+        let (node_8, _address_8, _id_8, _incoming_8) = peer_manager_with_limits(
+            "node-8",
+            vec![address_6, address_7],
+            DEFAULT_MAX_INBOUND_PEERS,
+            1,
+        )
+        .await;
+        assert_eq!(node_8.missing_outbound().await, 0);
+        for node in [node_6, node_7, node_8.clone()] {
+            tokio::spawn(async move { node.connect_to_peers().await });
+        }
+
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+            while node_5.connected_peers().await.is_empty()
+                || node_8.connected_peers().await.is_empty()
+            {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nodes didn't connect");
+        // The other peer keeps trying in the meantime
+        tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+        assert_eq!(node_5.connected_peers().await.len(), 1);
+        assert_eq!(node_8.connected_peers().await.len(), 1);
+
+        for node_id in ["node-5", "node-6", "node-7", "node-8"] {
+            dump_database(&format!("test-peers-{}", node_id));
+        }
+    }
 }
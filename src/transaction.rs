@@ -1,5 +1,6 @@
+use crate::amount::Amount;
+use crate::pool::DropReason;
 use crate::wallet::Wallet;
-use ordered_float::OrderedFloat;
 use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
 use secp256k1::{Message, Secp256k1};
 
@@ -20,45 +21,39 @@ pub struct SignTransactionRequest {
     pub private_key_hex: String,
 }
 
+/// Where a transaction is, a dropped one has left the pool without being mined
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum TransactionStatus {
+    Confirmed,
+    Pending,
+    Dropped { reason: DropReason },
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Transaction {
     pub sender: String,
     pub recipient: String,
-    #[serde(with = "ordered_float_serde")]
-    pub amount: OrderedFloat<f64>,
-    #[serde(with = "ordered_float_serde")]
-    pub fee: OrderedFloat<f64>, // NEW: Transaction fee
+    pub amount: Amount,
+    pub fee: Amount, // NEW: Transaction fee
+    /// Sequence number of the sender's transactions, it must match the next nonce
+    /// of the sender's account, so a signed transaction can only be applied once
+    pub nonce: u64,
     pub timestamp: i64,
+    /// Height of the last block the transaction can be included in, blocks with a higher
+    /// index reject it, so a transaction that can't be mined in time isn't kept forever
+    #[serde(default)]
+    pub valid_until: Option<u64>,
     pub signature: Option<String>, // Signature is optional until it's signed
 }
 
-mod ordered_float_serde {
-    use ordered_float::OrderedFloat;
-    use serde::{Deserialize, Deserializer, Serializer};
-
-    pub fn serialize<S>(value: &OrderedFloat<f64>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_f64(value.into_inner())
-    }
-
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<OrderedFloat<f64>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let float_value = f64::deserialize(deserializer)?;
-        Ok(OrderedFloat(float_value))
-    }
-}
-
 impl Eq for Transaction {}
 
 impl Ord for Transaction {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.fee //Transactions with the same fee, will be prioritized
-            .cmp(&other.fee) // OrderedFloat reverses the order internally, so we need to use self before other
+        self.fee // Transactions with higher fees will be prioritized
+            .cmp(&other.fee)
             .then_with(|| other.timestamp.cmp(&self.timestamp)) // If the fee is the same, the older transaction will be selected as the priority
     }
 }
@@ -78,26 +73,80 @@ impl PartialOrd for Transaction {
 impl Transaction {
     /// Create a new transaction (unsigned)
     #[allow(dead_code)]
-    pub fn new(sender: String, recipient: String, amount: f64, fee: f64, timestamp: i64) -> Self {
+    pub fn new(
+        sender: String,
+        recipient: String,
+        amount: Amount,
+        fee: Amount,
+        nonce: u64,
+        timestamp: i64,
+    ) -> Self {
         Transaction {
             sender,
             recipient,
-            amount: OrderedFloat(amount),
-            fee: OrderedFloat(fee),
+            amount,
+            fee,
+            nonce,
             timestamp,
+            valid_until: None,
             signature: None,
         }
     }
 
+    /// Sets the height of the last block the transaction can be included in
+    #[allow(dead_code)]
+    pub fn with_valid_until(mut self, valid_until: u64) -> Self {
+        self.valid_until = Some(valid_until);
+        self
+    }
+
+    /// Transaction data (excluding signature) used for signing and hashing,
+    /// amounts are written as integer base units, so the result is always the same.
+    /// The valid-until height is only appended when it's set, so transactions without it
+    /// keep the hash they had before it existed
+    fn message_data(&self) -> String {
+        let data = format!(
+            "{}:{}:{}:{}:{}:{}",
+            self.sender,
+            self.recipient,
+            self.amount.base_units(),
+            self.fee.base_units(),
+            self.nonce,
+            self.timestamp
+        );
+        match self.valid_until {
+            Some(valid_until) => format!("{}:{}", data, valid_until),
+            None => data,
+        }
+    }
+
+    /// Coinbase transactions mint the block reward, they can only be created by miners
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == "COINBASE"
+    }
+
+    /// Whether the transaction can no longer be included in the block at this height
+    pub fn is_expired(&self, height: u64) -> bool {
+        self.valid_until
+            .is_some_and(|valid_until| height > valid_until)
+    }
+
+    /// Size of the transaction in bytes, as it's sent to peers
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map_or(0, |data| data.len())
+    }
+
+    /// Total amount debited from the sender, None if it overflows
+    pub fn total_cost(&self) -> Option<Amount> {
+        self.amount.checked_add(self.fee)
+    }
+
     /// Sign the transaction using the sender's wallet private key
     pub fn sign(&mut self, wallet: &Wallet) {
         let secp = Secp256k1::new();
 
-        // Serialize transaction data as bytes (including fee in the hash)
-        let message_data = format!(
-            "{}:{}:{}:{}:{}",
-            self.sender, self.recipient, self.amount, self.fee, self.timestamp
-        );
+        // Serialize transaction data as bytes (including fee and nonce in the hash)
+        let message_data = self.message_data();
         let message_hash = Sha256::digest(message_data.as_bytes());
 
         // Create a message for signing
@@ -122,8 +171,8 @@ impl Transaction {
 
     /// Verify the transaction's signature
     pub fn verify(&self) -> bool {
-        // TODO - Add this to handlers, to make sure no COINBASE transaction is sent through RPC nodes
-        if self.sender == "COINBASE" {
+        // Coinbase transactions are only valid inside a block, the handlers reject them
+        if self.is_coinbase() {
             return true; // No signature needed for coinbase
         }
 
@@ -151,10 +200,7 @@ impl Transaction {
                 };
 
             // Serialize transaction data (excluding signature) as bytes
-            let message_data = format!(
-                "{}:{}:{}:{}:{}",
-                self.sender, self.recipient, self.amount, self.fee, self.timestamp
-            );
+            let message_data = self.message_data();
             let message_hash = Sha256::digest(message_data.as_bytes());
 
             // Create a message for signature verification
@@ -177,10 +223,7 @@ impl Transaction {
     }
 
     pub fn hash(&self) -> String {
-        let tx_data = format!(
-            "{}:{}:{}:{}:{}",
-            self.sender, self.recipient, self.amount, self.fee, self.timestamp
-        );
+        let tx_data = self.message_data();
 
         let tx_hash = Sha256::digest(tx_data.as_bytes());
         hex::encode(tx_hash)
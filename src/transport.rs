@@ -0,0 +1,235 @@
+use crate::constants::HANDSHAKE_TIMEOUT_SECS;
+use crate::error::HandshakeError;
+use crate::identity::Identity;
+use bytes::{Buf, BytesMut};
+use snow::{HandshakeState, TransportState};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Mutual authentication with the static keys of both nodes, x25519 key exchange,
+/// ChaCha20-Poly1305 encryption
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Size of the length prefix of every noise message
+const NOISE_LENGTH_SIZE: usize = 2;
+const MAX_NOISE_MESSAGE: usize = 65535;
+/// Size of the authentication tag added to every encrypted message
+const TAG_SIZE: usize = 16;
+/// Bytes encrypted in a single noise message, larger frames are split
+const MAX_CHUNK_SIZE: usize = MAX_NOISE_MESSAGE - TAG_SIZE;
+
+/// Runs the noise handshake on a connection opened by this node
+pub async fn initiate<S>(stream: S, identity: &Identity) -> Result<SecureStream<S>, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let noise = builder(identity)?.build_initiator()?;
+    run_handshake(stream, noise).await
+}
+
+/// Runs the noise handshake on a connection opened by the remote node
+pub async fn respond<S>(stream: S, identity: &Identity) -> Result<SecureStream<S>, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let noise = builder(identity)?.build_responder()?;
+    run_handshake(stream, noise).await
+}
+
+fn builder(identity: &Identity) -> Result<snow::Builder<'_>, HandshakeError> {
+    let params = NOISE_PARAMS.parse()?;
+    Ok(snow::Builder::new(params).local_private_key(identity.private_key()))
+}
+
+/// Exchanges the three messages of the XX pattern: both nodes send an ephemeral key,
+/// then their static key encrypted, so a passive observer learns neither of them
+async fn run_handshake<S>(
+    mut stream: S,
+    mut noise: HandshakeState,
+) -> Result<SecureStream<S>, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; MAX_NOISE_MESSAGE];
+    while !noise.is_handshake_finished() {
+        if noise.is_my_turn() {
+            let length = noise.write_message(&[], &mut buffer)?;
+            stream.write_all(&(length as u16).to_be_bytes()).await?;
+            stream.write_all(&buffer[..length]).await?;
+            stream.flush().await?;
+        } else {
+            let message = tokio::time::timeout(
+                tokio::time::Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+                read_noise_message(&mut stream),
+            )
+            .await
+            .map_err(|_| HandshakeError::Timeout)??;
+            noise.read_message(&message, &mut buffer)?;
+        }
+    }
+
+    let remote_key = noise
+        .get_remote_static()
+        .map(|key| key.to_vec())
+        .ok_or(snow::Error::Input)?;
+    Ok(SecureStream::new(
+        stream,
+        noise.into_transport_mode()?,
+        remote_key,
+    ))
+}
+
+async fn read_noise_message<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut length = [0u8; NOISE_LENGTH_SIZE];
+    stream.read_exact(&mut length).await?;
+    let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+fn invalid_data(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Connection encrypted with the keys agreed in the noise handshake.
+/// The bytes written are split in noise messages of at most 64KB, each one prefixed
+/// with its length, and decrypted back into a byte stream on the other side,
+/// so the frames of the P2P protocol are read and written as on a plain connection.
+/// A message that fails to decrypt is a read error.
+pub struct SecureStream<S> {
+    inner: S,
+    transport: TransportState,
+    remote_key: Vec<u8>,
+    /// Encrypted bytes read, not forming a whole message yet
+    encrypted: BytesMut,
+    /// Decrypted bytes not read yet
+    decrypted: BytesMut,
+    /// Encrypted messages waiting to be written
+    pending: BytesMut,
+}
+
+impl<S> SecureStream<S> {
+    fn new(inner: S, transport: TransportState, remote_key: Vec<u8>) -> Self {
+        Self {
+            inner,
+            transport,
+            remote_key,
+            encrypted: BytesMut::new(),
+            decrypted: BytesMut::new(),
+            pending: BytesMut::new(),
+        }
+    }
+
+    /// Id of the remote node, its public key authenticated in the handshake
+    pub fn remote_id(&self) -> String {
+        hex::encode(&self.remote_key)
+    }
+
+    /// Decrypts the next message if it was entirely read
+    fn decrypt_message(&mut self) -> io::Result<bool> {
+        if self.encrypted.len() < NOISE_LENGTH_SIZE {
+            return Ok(false);
+        }
+        let length = u16::from_be_bytes([self.encrypted[0], self.encrypted[1]]) as usize;
+        if self.encrypted.len() < NOISE_LENGTH_SIZE + length {
+            return Ok(false);
+        }
+        self.encrypted.advance(NOISE_LENGTH_SIZE);
+        let message = self.encrypted.split_to(length);
+        let mut payload = vec![0u8; length];
+        let size = self
+            .transport
+            .read_message(&message, &mut payload)
+            .map_err(invalid_data)?;
+        self.decrypted.extend_from_slice(&payload[..size]);
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SecureStream<S> {
+    /// Writes the encrypted messages waiting to be written
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SecureStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.decrypted.is_empty() {
+                let size = this.decrypted.len().min(buf.remaining());
+                buf.put_slice(&this.decrypted.split_to(size));
+                return Poll::Ready(Ok(()));
+            }
+            if this.decrypt_message()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // The connection was closed, in the middle of a message or not
+                return match this.encrypted.is_empty() {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                };
+            }
+            this.encrypted.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SecureStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+
+        let size = buf.len().min(MAX_CHUNK_SIZE);
+        let mut message = vec![0u8; size + TAG_SIZE];
+        let length = this
+            .transport
+            .write_message(&buf[..size], &mut message)
+            .map_err(invalid_data)?;
+        this.pending
+            .extend_from_slice(&(length as u16).to_be_bytes());
+        this.pending.extend_from_slice(&message[..length]);
+        // The message is sent by the next write or flush if the connection isn't ready
+        if let Poll::Ready(Err(err)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}